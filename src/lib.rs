@@ -2,5 +2,12 @@ pub mod order_book;
 
 pub use order_book::orderbook::{OrderBook, HalfBook};
 pub use order_book::matching_engine::MatchingEngine;
-pub use order_book::types::{EngineNewOrder, EngineModifyOrder, EngineCancelOrder};
-pub use order_book::tracing::Tracing;
\ No newline at end of file
+pub use order_book::types::{EngineNewOrder, EngineModifyOrder, EngineCancelOrder, Fill, Instrument, LevelAction, LevelUpdate, MarketDataEvent, MassCancelFilter, MatchingAlgorithm, OrderAction, OrderUpdate, BboUpdate, PriceLevelDepth, OrderState, OrderStatus, QueuePosition, QueuePositionUpdate, TradingStateUpdate};
+pub use order_book::tracing::Tracing;
+pub use order_book::error::EngineError;
+pub use order_book::journal::{Journal, JournalRecord};
+pub use order_book::clock::{Clock, SystemClock, SimulatedClock};
+pub use order_book::position::{Exposure, Position, TradedQuantity};
+pub use order_book::auction::{AuctionKind, AuctionPrice, CallAuction, UncrossOutcome};
+pub use order_book::risk::{RiskCheck, RiskLimits, ParticipantLimits, RiskOrder, MarketContext, RiskRejection};
+pub use order_book::trading_state::{StateTransition, TradingState};
\ No newline at end of file
@@ -0,0 +1,251 @@
+use std::collections::BTreeSet;
+use crate::order_book::error::EngineError;
+use crate::order_book::orderbook::{HalfBook, OrderBook};
+use crate::order_book::types::{EngineNewOrder, Fill, OrderAction, OrderUpdate, PriceLevel};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AuctionKind{
+    Opening,
+    Closing
+}
+
+// a security in a call auction: orders are collected without matching until the uncross.
+// market orders (market on open / market on close) have no price to rest at and wait here.
+#[derive(Debug, Clone)]
+pub struct CallAuction{
+    pub kind : AuctionKind,
+    pub market_orders : Vec<EngineNewOrder> // both sides, in arrival order
+}
+
+impl CallAuction {
+    pub fn new(kind : AuctionKind) -> Self{
+        Self { kind, market_orders : Vec::new() }
+    }
+
+    pub fn get(&self, order_id : u64, is_buy_side : bool) -> Option<&EngineNewOrder>{
+        self.market_orders.iter().find(|order| order.engine_order_id == order_id && order.is_buy_side == is_buy_side)
+    }
+
+    pub fn remove(&mut self, order_id : u64, is_buy_side : bool) -> Option<EngineNewOrder>{
+        let position = self.market_orders.iter().position(|order| order.engine_order_id == order_id && order.is_buy_side == is_buy_side)?;
+        Some(self.market_orders.remove(position))
+    }
+
+    fn market_quantity(&self, is_buy_side : bool) -> u64{
+        self.market_orders.iter().filter(|order| order.is_buy_side == is_buy_side).map(|order| order.current_quantity as u64).sum()
+    }
+}
+
+// the single price an auction uncrosses at and the volumes behind it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AuctionPrice{
+    pub price : u32,
+    pub matched_quantity : u64, // executable at `price`, the smaller of the two sides
+    pub buy_quantity : u64, // market buys and bids at or above `price`, iceberg reserves included
+    pub sell_quantity : u64 // market sells and asks at or below `price`
+}
+
+impl AuctionPrice {
+    // surplus left after the uncross, positive on the buy side
+    pub fn imbalance(&self) -> i64{
+        self.buy_quantity as i64 - self.sell_quantity as i64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UncrossOutcome{
+    pub auction_price : Option<AuctionPrice>, // None when nothing crossed, the book is left as it was
+    pub fills : Vec<Fill>, // the auction's at the auction price, then those of released stops
+    pub cancelled_orders : Vec<u64>, // market orders the auction left (partly) unfilled
+    pub released_stops : Vec<u64>,
+    pub self_trade_cancels : Vec<u64>, // by released stops, the auction itself doesn't apply self-trade prevention
+    pub sequence : u64,
+    pub timestamp : u64
+}
+
+// the price maximising the executable quantity. ties go to the smallest surplus, then to
+// market pressure (the highest price when every remaining candidate has a buy surplus, the
+// lowest for a sell surplus), then to the price closest to the reference (the last trade
+// price), the lower one if that still ties. candidates are the limit prices on both sides
+// and the reference price. None when nothing would trade.
+pub fn equilibrium_price(orderbook : &OrderBook, auction : &CallAuction) -> Option<AuctionPrice>{
+    let bids = level_quantities(&orderbook.bid);
+    let asks = level_quantities(&orderbook.ask);
+    let reference_price = orderbook.last_trade_price;
+    let mut prices : BTreeSet<u32> = bids.iter().chain(&asks).map(|(price, _)| *price).collect();
+    prices.extend(reference_price);
+
+    // walking up the prices, bids below the candidate drop out and asks at it join in
+    let mut buy_quantity = auction.market_quantity(true) + bids.iter().map(|(_, quantity)| quantity).sum::<u64>();
+    let mut sell_quantity = auction.market_quantity(false);
+    let (mut next_bid, mut next_ask) = (0, 0);
+    let mut candidates = Vec::new();
+    for price in prices {
+        while let Some((bid_price, quantity)) = bids.get(next_bid) && *bid_price < price {
+            buy_quantity -= quantity;
+            next_bid += 1;
+        }
+        while let Some((ask_price, quantity)) = asks.get(next_ask) && *ask_price <= price {
+            sell_quantity += quantity;
+            next_ask += 1;
+        }
+        let matched_quantity = buy_quantity.min(sell_quantity);
+        if matched_quantity > 0 {
+            candidates.push(AuctionPrice { price, matched_quantity, buy_quantity, sell_quantity });
+        }
+    }
+
+    let most_matched = candidates.iter().map(|candidate| candidate.matched_quantity).max()?;
+    candidates.retain(|candidate| candidate.matched_quantity == most_matched);
+    let least_surplus = candidates.iter().map(|candidate| candidate.imbalance().unsigned_abs()).min()?;
+    candidates.retain(|candidate| candidate.imbalance().unsigned_abs() == least_surplus);
+    if candidates.iter().all(|candidate| candidate.imbalance() > 0) {
+        return candidates.last().copied();
+    }
+    if candidates.iter().all(|candidate| candidate.imbalance() < 0) {
+        return candidates.first().copied();
+    }
+    candidates.into_iter().min_by_key(|candidate| (reference_price.map_or(0, |reference| candidate.price.abs_diff(reference)), candidate.price))
+}
+
+// trades `auction_price.matched_quantity` at the auction price. each side is allocated in
+// priority order, market orders first, then the best priced levels, each in queue order (also
+// for pro-rata instruments). an uncross has no aggressor, fills name the buy order as such.
+// resting orders are taken out of the book as they fill, market orders are left to the caller.
+pub fn uncross(
+    orderbook : &mut OrderBook,
+    auction : &CallAuction,
+    auction_price : &AuctionPrice,
+    security_id : u32,
+    trade_id : &mut u64,
+    timestamp : u64
+) -> Result<Vec<Fill>, EngineError>{
+    let mut buys = allocation(&orderbook.bid, true, auction, auction_price);
+    let mut sells = allocation(&orderbook.ask, false, auction, auction_price);
+    let mut fills = Vec::new();
+    let (mut next_buy, mut next_sell) = (0, 0);
+    while next_buy < buys.len() && next_sell < sells.len() {
+        let (buy_order_id, buy_quantity, buy_rests) = buys[next_buy];
+        let (sell_order_id, sell_quantity, sell_rests) = sells[next_sell];
+        let quantity = buy_quantity.min(sell_quantity);
+        *trade_id += 1;
+        fills.push(Fill {
+            sequence : 0, // numbered by the caller
+            timestamp,
+            trade_id : *trade_id,
+            security_id,
+            aggressor_order_id : buy_order_id,
+            passive_order_id : sell_order_id,
+            price : auction_price.price,
+            quantity,
+            aggressor_is_buy_side : true
+        });
+        if buy_rests {
+            execute_resting(orderbook, true, buy_order_id, quantity, *trade_id, timestamp)?;
+        }
+        if sell_rests {
+            execute_resting(orderbook, false, sell_order_id, quantity, *trade_id, timestamp)?;
+        }
+        buys[next_buy].1 -= quantity;
+        sells[next_sell].1 -= quantity;
+        if buys[next_buy].1 == 0 {
+            next_buy += 1;
+        }
+        if sells[next_sell].1 == 0 {
+            next_sell += 1;
+        }
+    }
+    Ok(fills)
+}
+
+// (price, quantity) per level, lowest price first, iceberg reserves included
+fn level_quantities(half : &HalfBook) -> Vec<(u32, u64)>{
+    half.price_map.iter().map(|(price, price_level)| {
+        let mut quantity = 0;
+        let mut cursor = price_level.head;
+        while let Some(order_node) = cursor.and_then(|idx| half.order_pool.get(idx)).and_then(|node| node.as_ref()) {
+            quantity += (order_node.current_quantity + order_node.hidden_quantity) as u64;
+            cursor = order_node.next;
+        }
+        (*price, quantity)
+    }).collect()
+}
+
+// (order id, quantity, resting) for every order of one side taking part in the uncross
+fn allocation(half : &HalfBook, is_buy_side : bool, auction : &CallAuction, auction_price : &AuctionPrice) -> Vec<(u64, u32, bool)>{
+    let mut remaining = auction_price.matched_quantity;
+    let mut allocations = Vec::new();
+    for order in auction.market_orders.iter().filter(|order| order.is_buy_side == is_buy_side) {
+        if remaining == 0 {
+            return allocations;
+        }
+        let quantity = remaining.min(order.current_quantity as u64) as u32;
+        allocations.push((order.engine_order_id, quantity, false));
+        remaining -= quantity as u64;
+    }
+    let levels : Box<dyn Iterator<Item = (&u32, &PriceLevel)>> = if is_buy_side {
+        Box::new(half.price_map.range(auction_price.price..).rev())
+    } else {
+        Box::new(half.price_map.range(..=auction_price.price))
+    };
+    for (_, price_level) in levels {
+        let mut cursor = price_level.head;
+        while let Some(order_node) = cursor.and_then(|idx| half.order_pool.get(idx)).and_then(|node| node.as_ref()) {
+            if remaining == 0 {
+                return allocations;
+            }
+            let quantity = remaining.min((order_node.current_quantity + order_node.hidden_quantity) as u64) as u32;
+            allocations.push((order_node.order_id, quantity, true));
+            remaining -= quantity as u64;
+            cursor = order_node.next;
+        }
+    }
+    allocations
+}
+
+// takes `quantity` off a resting order, shown quantity first. an iceberg whose peak runs out
+// refills from its reserve at the back of the level, like in continuous trading.
+fn execute_resting(orderbook : &mut OrderBook, is_buy_side : bool, order_id : u64, quantity : u32, trade_id : u64, timestamp : u64) -> Result<(), EngineError>{
+    let OrderBook { ask, bid, order_events, .. } = orderbook;
+    let HalfBook { price_map, order_registry, order_pool, free_list, touched_levels, exposure } = if is_buy_side { bid } else { ask };
+    let idx = *order_registry.get(&order_id).ok_or(EngineError::UnknownOrder(order_id))?;
+    let (price, owner_id) = match order_pool[idx].as_ref() {
+        Some(order_node) => (order_node.market_limit, order_node.owner_id),
+        None => {
+            return Err(EngineError::InvariantViolation("auction allocation points at an empty pool slot"));
+        }
+    };
+    HalfBook::touch_level(touched_levels, price_map, price);
+    HalfBook::remove_exposure(exposure, owner_id, price, quantity);
+    let price_level = price_map.get_mut(&price).ok_or(EngineError::InvariantViolation("resting order without its price level"))?;
+    let mut remaining = quantity;
+    while remaining > 0 {
+        let Some(order_node) = order_pool[idx].as_mut() else {
+            return Err(EngineError::InvariantViolation("failed to get the auction order from the order pool"));
+        };
+        let from_shown = remaining.min(order_node.current_quantity);
+        order_node.current_quantity -= from_shown;
+        price_level.total_quantity -= from_shown;
+        remaining -= from_shown;
+        order_events.push(OrderUpdate::new(order_id, is_buy_side, price, from_shown, OrderAction::Executed { trade_id }));
+        if order_node.current_quantity > 0 {
+            break;
+        }
+        if order_node.hidden_quantity == 0 {
+            HalfBook::unlink(order_pool, free_list, order_registry, price_level, idx);
+            order_events.push(OrderUpdate::new(order_id, is_buy_side, price, 0, OrderAction::Delete));
+            break;
+        }
+        let refill_quantity = order_node.hidden_quantity.min(order_node.peak_quantity);
+        order_node.current_quantity = refill_quantity;
+        order_node.hidden_quantity -= refill_quantity;
+        order_node.entry_time = timestamp;
+        price_level.total_quantity += refill_quantity;
+        order_events.push(OrderUpdate::new(order_id, is_buy_side, price, refill_quantity, OrderAction::Replace));
+        HalfBook::move_to_tail(order_pool, price_level, idx)?;
+    }
+    if price_level.head.is_none() {
+        price_map.remove(&price);
+    }
+    Ok(())
+}
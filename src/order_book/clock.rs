@@ -0,0 +1,46 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// source of engine time, in nanoseconds since the unix epoch. the engine never lets its own
+// time go backwards, so a clock stepping back only stalls timestamps instead of reordering them.
+pub trait Clock : Debug + Send {
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64{
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    }
+}
+
+// time only moves when told to. clones share the same time, so keep one handle to drive the
+// clock after handing another to the engine (tests, backtests, replay).
+#[derive(Debug, Default, Clone)]
+pub struct SimulatedClock{
+    now : Arc<AtomicU64>
+}
+
+impl SimulatedClock {
+    pub fn new(start : u64) -> Self{
+        Self { now : Arc::new(AtomicU64::new(start)) }
+    }
+
+    pub fn set(&self, now : u64){
+        self.now.store(now, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by : u64){
+        self.now.fetch_add(by, Ordering::Relaxed);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> u64{
+        self.now.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::io::ErrorKind;
+use crate::order_book::risk::RiskRejection;
+use crate::order_book::trading_state::TradingState;
+
+// every failure the order book and the engine can report. callers are expected to match
+// on the variant (e.g. to pick a reject code) instead of reading the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError{
+    UnknownOrder(u64), // order id not resting in the book
+    UnknownSecurity(u32), // security id not registered with the engine
+    DuplicateSecurity(u32), // security id already registered
+    SecurityHalted(u32), // only cancels are accepted while halted
+    InvalidReferenceData(&'static str), // instrument rejected at registration
+    PriceNotOnTick { price : u32, tick_size : u32 },
+    PriceOutOfRange { price : u32, min_price : u32, max_price : u32 },
+    QuantityNotOnLot { quantity : u32, lot_size : u32 },
+    QuantityOutOfRange { quantity : u32, min_quantity : u32, max_quantity : u32 },
+    InvariantViolation(&'static str), // book internals disagree with each other, says where
+    InvalidPrice(&'static str),
+    InvalidQuantity(&'static str),
+    UnsupportedOrder(&'static str), // order type / flag combination the engine doesn't accept
+    PostOnlyWouldCross(u32), // best opposite price the post-only order would have taken
+    JournalIo(ErrorKind), // reading or writing the journal file failed
+    CorruptJournal(&'static str), // a complete journal record that can't be decoded
+    TornJournal(u64), // the journal ends in a partly written frame starting at this byte offset
+    DuplicateClientOrderId { owner_id : u64, client_order_id : String }, // already used by one of the owner's live orders
+    UnknownClientOrderId { owner_id : u64, client_order_id : String }, // no live order of the owner carries it
+    RiskRejected(RiskRejection), // stopped by the pre-trade risk check before reaching the book
+    AuctionInProgress(u32), // the security is already in a call auction
+    NoAuction(u32), // the security isn't in a call auction
+    NotAcceptingOrders { security_id : u32, state : TradingState }, // new orders and modifies wait for the security to open
+    InvalidStateTransition { security_id : u32, from : TradingState, to : TradingState },
+    SnapshotIo(ErrorKind), // reading or writing the snapshot file failed
+    CorruptSnapshot(&'static str) // snapshot can't be decoded or describes an inconsistent book
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::UnknownOrder(order_id) => write!(f, "unknown order {}", order_id),
+            EngineError::UnknownSecurity(security_id) => write!(f, "unknown security {}", security_id),
+            EngineError::DuplicateSecurity(security_id) => write!(f, "security {} is already registered", security_id),
+            EngineError::SecurityHalted(security_id) => write!(f, "security {} is halted", security_id),
+            EngineError::InvalidReferenceData(reason) => write!(f, "invalid reference data: {}", reason),
+            EngineError::PriceNotOnTick { price, tick_size } => write!(f, "price {} is not a multiple of the tick size {}", price, tick_size),
+            EngineError::PriceOutOfRange { price, min_price, max_price } => write!(f, "price {} outside of [{}, {}]", price, min_price, max_price),
+            EngineError::QuantityNotOnLot { quantity, lot_size } => write!(f, "quantity {} is not a multiple of the lot size {}", quantity, lot_size),
+            EngineError::QuantityOutOfRange { quantity, min_quantity, max_quantity } => write!(f, "quantity {} outside of [{}, {}]", quantity, min_quantity, max_quantity),
+            EngineError::InvariantViolation(reason) => write!(f, "order book invariant violated: {}", reason),
+            EngineError::InvalidPrice(reason) => write!(f, "invalid price: {}", reason),
+            EngineError::InvalidQuantity(reason) => write!(f, "invalid quantity: {}", reason),
+            EngineError::UnsupportedOrder(reason) => write!(f, "unsupported order: {}", reason),
+            EngineError::PostOnlyWouldCross(best_price) => write!(f, "post-only order would cross the best price {}", best_price),
+            EngineError::JournalIo(kind) => write!(f, "journal io failed: {}", kind),
+            EngineError::CorruptJournal(reason) => write!(f, "corrupt journal: {}", reason),
+            EngineError::TornJournal(offset) => write!(f, "journal ends in a torn frame at byte {}, repair it before appending", offset),
+            EngineError::DuplicateClientOrderId { owner_id, client_order_id } => {
+                write!(f, "client order id {} is already in use by owner {}", client_order_id, owner_id)
+            }
+            EngineError::UnknownClientOrderId { owner_id, client_order_id } => {
+                write!(f, "owner {} has no live order with client order id {}", owner_id, client_order_id)
+            }
+            EngineError::RiskRejected(rejection) => write!(f, "rejected by pre-trade risk: {}", rejection),
+            EngineError::AuctionInProgress(security_id) => write!(f, "security {} is already in an auction", security_id),
+            EngineError::NoAuction(security_id) => write!(f, "security {} is not in an auction", security_id),
+            EngineError::NotAcceptingOrders { security_id, state } => write!(f, "security {} does not accept orders while {}", security_id, state),
+            EngineError::InvalidStateTransition { security_id, from, to } => {
+                write!(f, "security {} can't go from {} to {}", security_id, from, to)
+            }
+            EngineError::SnapshotIo(kind) => write!(f, "snapshot io failed: {}", kind),
+            EngineError::CorruptSnapshot(reason) => write!(f, "corrupt snapshot: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
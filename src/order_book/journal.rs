@@ -0,0 +1,792 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use crate::order_book::auction::AuctionKind;
+use crate::order_book::error::EngineError;
+use crate::order_book::trading_state::TradingState;
+use crate::order_book::types::{
+    EngineCancelOrder, EngineModifyOrder, EngineNewOrder, Fill, Instrument, MassCancelFilter, MatchingAlgorithm, OrderType, PostOnly, SelfTradePrevention, TimeInForce
+};
+
+// every record on disk is framed as [len : u32][sequence : u64][timestamp : u64][tag : u8][payload],
+// little endian, where len covers everything after itself. sequence and timestamp are the engine's
+// stamps. a frame cut short by a crash at the tail is ignored on read, one whose length runs past
+// the end with complete frames after it is corruption.
+const FRAME_HEADER : usize = 4;
+
+// (sequence, timestamp, record)
+type Entry = (u64, u64, JournalRecord);
+
+// inbound commands are written before they are applied, events after. replay only needs
+// the commands, the events are there for audit and downstream consumers.
+#[derive(Debug, Clone)]
+pub enum JournalRecord{
+    RegisterSecurity(Instrument),
+    DelistSecurity(u32),
+    Halt(u32),
+    Resume(u32),
+    SetSelfTradePrevention(Option<SelfTradePrevention>),
+    NewOrder(EngineNewOrder),
+    ModifyOrder(EngineModifyOrder),
+    CancelOrder(EngineCancelOrder),
+    WatchQueuePosition { security_id : u32, order_id : u64, is_buy_side : bool },
+    UnwatchQueuePosition { security_id : u32, order_id : u64 },
+    MassCancel(MassCancelFilter),
+    EndSession(u64),
+    SetSessionKeepsGtc { session_id : u64, keep : bool },
+    ExpireDayOrders(u32),
+    StartAuction { security_id : u32, kind : AuctionKind },
+    Uncross(u32),
+    SetTradingState { security_id : u32, state : TradingState },
+    ScheduleTransition { security_id : u32, at : u64, state : TradingState },
+    ClearSchedule(u32),
+    RunSchedule,
+    Fill(Fill),
+    Rested { security_id : u32, order_id : u64 },
+    Cancelled { security_id : u32, order_id : u64 },
+    Rejected { reason : String }
+}
+
+impl JournalRecord {
+    pub fn is_command(&self) -> bool{
+        !matches!(self, JournalRecord::Fill(_) | JournalRecord::Rested { .. } | JournalRecord::Cancelled { .. } | JournalRecord::Rejected { .. })
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal{
+    writer : BufWriter<File>,
+    last_sequence : u64
+}
+
+impl Journal {
+    // opens (or creates) the journal for appending after whatever is already on disk.
+    // a journal ending in a torn frame is refused, appending after it would bury new records
+    // inside its length. `repair` cuts it off once the operator has had a look.
+    pub fn open(path : &Path) -> Result<Self, EngineError>{
+        let last_sequence = match Self::read_frames(path) {
+            Ok((_, intact_len, file_len)) if intact_len < file_len => return Err(EngineError::TornJournal(intact_len as u64)),
+            Ok((records, _, _)) => records.last().map_or(0, |(sequence, _, _)| *sequence),
+            Err(EngineError::JournalIo(ErrorKind::NotFound)) => 0,
+            Err(e) => return Err(e)
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| EngineError::JournalIo(e.kind()))?;
+        Ok(Self { writer : BufWriter::new(file), last_sequence })
+    }
+
+    // cuts a torn frame off the tail of the journal and returns how many bytes went. a journal
+    // that is corrupt anywhere else is left untouched and the error is returned.
+    pub fn repair(path : &Path) -> Result<u64, EngineError>{
+        let (_, intact_len, file_len) = Self::read_frames(path)?;
+        if intact_len < file_len {
+            let file = OpenOptions::new().write(true).open(path).map_err(|e| EngineError::JournalIo(e.kind()))?;
+            file.set_len(intact_len as u64).map_err(|e| EngineError::JournalIo(e.kind()))?;
+            file.sync_data().map_err(|e| EngineError::JournalIo(e.kind()))?;
+        }
+        Ok((file_len - intact_len) as u64)
+    }
+
+    // sequences come from the engine and have to keep increasing
+    pub fn append(&mut self, sequence : u64, timestamp : u64, record : &JournalRecord) -> Result<(), EngineError>{
+        if sequence <= self.last_sequence {
+            return Err(EngineError::InvariantViolation("journal sequence went backwards"));
+        }
+        let mut payload = Encoder::default();
+        payload.u64(sequence);
+        payload.u64(timestamp);
+        payload.record(record);
+        let mut frame = Vec::with_capacity(FRAME_HEADER + payload.buf.len());
+        frame.extend_from_slice(&(payload.buf.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload.buf);
+        self.writer.write_all(&frame).map_err(|e| EngineError::JournalIo(e.kind()))?;
+        self.last_sequence = sequence;
+        Ok(())
+    }
+
+    // sequence of the last record written, 0 for an empty journal
+    pub fn last_sequence(&self) -> u64{
+        self.last_sequence
+    }
+
+    pub fn flush(&mut self) -> Result<(), EngineError>{
+        self.writer.flush().map_err(|e| EngineError::JournalIo(e.kind()))
+    }
+
+    // flush plus fsync, the records appended so far survive a crash of the machine
+    pub fn sync(&mut self) -> Result<(), EngineError>{
+        self.flush()?;
+        self.writer.get_ref().sync_data().map_err(|e| EngineError::JournalIo(e.kind()))
+    }
+
+    // (sequence, timestamp, record) in the order they were written, a torn frame at the tail is left out
+    pub fn read_all(path : &Path) -> Result<Vec<Entry>, EngineError>{
+        Self::read_frames(path).map(|(records, _, _)| records)
+    }
+
+    // the records, the length of the file up to the end of the last complete frame and the
+    // length of the whole file
+    fn read_frames(path : &Path) -> Result<(Vec<Entry>, usize, usize), EngineError>{
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| EngineError::JournalIo(e.kind()))?;
+        let mut records : Vec<Entry> = Vec::new();
+        let mut offset = 0;
+        while offset + FRAME_HEADER <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[offset..offset + FRAME_HEADER].try_into().unwrap()) as usize;
+            let start = offset + FRAME_HEADER;
+            if start + len > bytes.len() {
+                // a crash half way through the last write, unless complete frames follow, in
+                // which case it's the length that got damaged
+                let last_sequence = records.last().map_or(0, |(sequence, _, _)| *sequence);
+                if Self::frame_follows(&bytes, start, last_sequence) {
+                    return Err(EngineError::CorruptJournal("frame length runs past the end of the journal"));
+                }
+                break;
+            }
+            let mut decoder = Decoder { buf : &bytes[start..start + len], pos : 0 };
+            let sequence = decoder.u64()?;
+            let timestamp = decoder.u64()?;
+            let mut record = decoder.record()?;
+            if let JournalRecord::Fill(fill) = &mut record {
+                // a fill's stamps are the frame's, they aren't repeated in the payload
+                fill.sequence = sequence;
+                fill.timestamp = timestamp;
+            }
+            records.push((sequence, timestamp, record));
+            offset = start + len;
+        }
+        Ok((records, offset, bytes.len()))
+    }
+
+    // whether a complete frame written after `last_sequence` starts anywhere from `from` on
+    fn frame_follows(bytes : &[u8], from : usize, last_sequence : u64) -> bool{
+        (from..=bytes.len().saturating_sub(FRAME_HEADER)).any(|offset| {
+            let len = u32::from_le_bytes(bytes[offset..offset + FRAME_HEADER].try_into().unwrap()) as usize;
+            let start = offset + FRAME_HEADER;
+            if start + len > bytes.len() {
+                return false;
+            }
+            let mut decoder = Decoder { buf : &bytes[start..start + len], pos : 0 };
+            decoder.u64().is_ok_and(|sequence| sequence > last_sequence) && decoder.u64().is_ok() && decoder.record().is_ok() && decoder.pos == len
+        })
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder{
+    pub(crate) buf : Vec<u8>
+}
+
+impl Encoder {
+    pub(crate) fn u8(&mut self, value : u8){
+        self.buf.push(value);
+    }
+    pub(crate) fn bool(&mut self, value : bool){
+        self.buf.push(value as u8);
+    }
+    pub(crate) fn u32(&mut self, value : u32){
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn u64(&mut self, value : u64){
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn opt_u32(&mut self, value : Option<u32>){
+        match value {
+            Some(value) => { self.u8(1); self.u32(value); }
+            None => self.u8(0)
+        }
+    }
+    pub(crate) fn opt_u64(&mut self, value : Option<u64>){
+        match value {
+            Some(value) => { self.u8(1); self.u64(value); }
+            None => self.u8(0)
+        }
+    }
+    pub(crate) fn opt_bool(&mut self, value : Option<bool>){
+        match value {
+            Some(value) => { self.u8(1); self.bool(value); }
+            None => self.u8(0)
+        }
+    }
+    pub(crate) fn str(&mut self, value : &str){
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+    pub(crate) fn opt_str(&mut self, value : Option<&str>){
+        match value {
+            Some(value) => { self.u8(1); self.str(value); }
+            None => self.u8(0)
+        }
+    }
+
+    pub(crate) fn instrument(&mut self, instrument : &Instrument){
+        self.u32(instrument.security_id);
+        self.str(&instrument.symbol);
+        self.u32(instrument.tick_size);
+        self.u32(instrument.lot_size);
+        self.u32(instrument.min_quantity);
+        self.u32(instrument.max_quantity);
+        self.u32(instrument.min_price);
+        self.u32(instrument.max_price);
+        match instrument.matching_algorithm {
+            MatchingAlgorithm::Fifo => self.u8(0),
+            MatchingAlgorithm::ProRata { top_order_priority, min_allocation } => {
+                self.u8(1);
+                self.bool(top_order_priority);
+                self.u32(min_allocation);
+            }
+        }
+    }
+
+    pub(crate) fn new_order(&mut self, order : &EngineNewOrder){
+        self.u64(order.engine_order_id);
+        self.opt_u32(order.price);
+        self.u32(order.initial_quantity);
+        self.u32(order.current_quantity);
+        self.bool(order.is_buy_side);
+        self.u32(order.security_id);
+        match order.order_type {
+            OrderType::Market(market_limit) => { self.u8(0); self.opt_u32(market_limit); }
+            OrderType::Limit => self.u8(1),
+            OrderType::Stop(stop_price) => { self.u8(2); self.u32(stop_price); }
+            OrderType::StopLimit(stop_price) => { self.u8(3); self.u32(stop_price); }
+            OrderType::Iceberg(peak_quantity) => { self.u8(4); self.u32(peak_quantity); }
+        }
+        self.time_in_force(order.time_in_force);
+        self.post_only(order.post_only);
+        self.u64(order.owner_id);
+        self.opt_str(order.client_order_id.as_deref());
+        self.opt_u64(order.session_id);
+    }
+
+    pub(crate) fn time_in_force(&mut self, time_in_force : TimeInForce){
+        self.u8(match time_in_force {
+            TimeInForce::GoodTillCancel => 0,
+            TimeInForce::ImmediateOrCancel => 1,
+            TimeInForce::FillOrKill => 2,
+            TimeInForce::Day => 3
+        });
+    }
+
+    pub(crate) fn post_only(&mut self, mode : Option<PostOnly>){
+        self.u8(match mode {
+            None => 0,
+            Some(PostOnly::Reject) => 1,
+            Some(PostOnly::Slide) => 2
+        });
+    }
+
+    pub(crate) fn auction_kind(&mut self, kind : AuctionKind){
+        self.u8(match kind {
+            AuctionKind::Opening => 0,
+            AuctionKind::Closing => 1
+        });
+    }
+
+    pub(crate) fn trading_state(&mut self, state : TradingState){
+        self.u8(match state {
+            TradingState::PreOpen => 0,
+            TradingState::OpeningAuction => 1,
+            TradingState::Continuous => 2,
+            TradingState::Halted => 3,
+            TradingState::ClosingAuction => 4,
+            TradingState::Closed => 5
+        });
+    }
+
+    pub(crate) fn self_trade_prevention(&mut self, mode : Option<SelfTradePrevention>){
+        self.u8(match mode {
+            None => 0,
+            Some(SelfTradePrevention::CancelNewest) => 1,
+            Some(SelfTradePrevention::CancelOldest) => 2,
+            Some(SelfTradePrevention::CancelBoth) => 3,
+            Some(SelfTradePrevention::DecrementAndCancel) => 4
+        });
+    }
+
+    fn record(&mut self, record : &JournalRecord){
+        match record {
+            JournalRecord::RegisterSecurity(instrument) => { self.u8(0); self.instrument(instrument); }
+            JournalRecord::DelistSecurity(security_id) => { self.u8(1); self.u32(*security_id); }
+            JournalRecord::Halt(security_id) => { self.u8(2); self.u32(*security_id); }
+            JournalRecord::Resume(security_id) => { self.u8(3); self.u32(*security_id); }
+            JournalRecord::SetSelfTradePrevention(mode) => { self.u8(4); self.self_trade_prevention(*mode); }
+            JournalRecord::NewOrder(order) => { self.u8(5); self.new_order(order); }
+            JournalRecord::ModifyOrder(order) => {
+                self.u8(6);
+                self.u64(order.order_id);
+                self.u32(order.security_id);
+                self.bool(order.is_buy_side);
+                self.opt_u32(order.new_price);
+                self.opt_u32(order.new_quantity);
+            }
+            JournalRecord::CancelOrder(order) => {
+                self.u8(7);
+                self.u64(order.order_id);
+                self.u32(order.security_id);
+                self.bool(order.is_buy_side);
+            }
+            JournalRecord::Fill(fill) => {
+                self.u8(8);
+                self.u64(fill.trade_id);
+                self.u32(fill.security_id);
+                self.u64(fill.aggressor_order_id);
+                self.u64(fill.passive_order_id);
+                self.u32(fill.price);
+                self.u32(fill.quantity);
+                self.bool(fill.aggressor_is_buy_side);
+            }
+            JournalRecord::Rested { security_id, order_id } => { self.u8(9); self.u32(*security_id); self.u64(*order_id); }
+            JournalRecord::Cancelled { security_id, order_id } => { self.u8(10); self.u32(*security_id); self.u64(*order_id); }
+            JournalRecord::Rejected { reason } => { self.u8(11); self.str(reason); }
+            JournalRecord::WatchQueuePosition { security_id, order_id, is_buy_side } => {
+                self.u8(12);
+                self.u32(*security_id);
+                self.u64(*order_id);
+                self.bool(*is_buy_side);
+            }
+            JournalRecord::UnwatchQueuePosition { security_id, order_id } => { self.u8(13); self.u32(*security_id); self.u64(*order_id); }
+            JournalRecord::MassCancel(filter) => {
+                self.u8(14);
+                self.opt_u32(filter.security_id);
+                self.opt_bool(filter.is_buy_side);
+                self.opt_u64(filter.owner_id);
+                self.opt_u32(filter.min_price);
+                self.opt_u32(filter.max_price);
+            }
+            JournalRecord::EndSession(session_id) => { self.u8(15); self.u64(*session_id); }
+            JournalRecord::SetSessionKeepsGtc { session_id, keep } => { self.u8(16); self.u64(*session_id); self.bool(*keep); }
+            JournalRecord::ExpireDayOrders(security_id) => { self.u8(17); self.u32(*security_id); }
+            JournalRecord::StartAuction { security_id, kind } => { self.u8(18); self.u32(*security_id); self.auction_kind(*kind); }
+            JournalRecord::Uncross(security_id) => { self.u8(19); self.u32(*security_id); }
+            JournalRecord::SetTradingState { security_id, state } => { self.u8(20); self.u32(*security_id); self.trading_state(*state); }
+            JournalRecord::ScheduleTransition { security_id, at, state } => {
+                self.u8(21);
+                self.u32(*security_id);
+                self.u64(*at);
+                self.trading_state(*state);
+            }
+            JournalRecord::ClearSchedule(security_id) => { self.u8(22); self.u32(*security_id); }
+            JournalRecord::RunSchedule => self.u8(23),
+        }
+    }
+}
+
+pub(crate) struct Decoder<'a>{
+    pub(crate) buf : &'a [u8],
+    pub(crate) pos : usize
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n : usize) -> Result<&[u8], EngineError>{
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or(EngineError::CorruptJournal("record shorter than its fields"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, EngineError>{
+        Ok(self.take(1)?[0])
+    }
+    pub(crate) fn bool(&mut self) -> Result<bool, EngineError>{
+        Ok(self.u8()? != 0)
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, EngineError>{
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, EngineError>{
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub(crate) fn opt_u32(&mut self) -> Result<Option<u32>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u32()?))
+        }
+    }
+    pub(crate) fn opt_u64(&mut self) -> Result<Option<u64>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u64()?))
+        }
+    }
+    pub(crate) fn opt_bool(&mut self) -> Result<Option<bool>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.bool()?))
+        }
+    }
+    pub(crate) fn string(&mut self) -> Result<String, EngineError>{
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| EngineError::CorruptJournal("string is not utf-8"))
+    }
+    pub(crate) fn opt_string(&mut self) -> Result<Option<String>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?))
+        }
+    }
+
+    pub(crate) fn instrument(&mut self) -> Result<Instrument, EngineError>{
+        Ok(Instrument {
+            security_id : self.u32()?,
+            symbol : self.string()?,
+            tick_size : self.u32()?,
+            lot_size : self.u32()?,
+            min_quantity : self.u32()?,
+            max_quantity : self.u32()?,
+            min_price : self.u32()?,
+            max_price : self.u32()?,
+            matching_algorithm : match self.u8()? {
+                0 => MatchingAlgorithm::Fifo,
+                1 => MatchingAlgorithm::ProRata { top_order_priority : self.bool()?, min_allocation : self.u32()? },
+                _ => return Err(EngineError::CorruptJournal("unknown matching algorithm"))
+            }
+        })
+    }
+
+    pub(crate) fn new_order(&mut self) -> Result<EngineNewOrder, EngineError>{
+        Ok(EngineNewOrder {
+            engine_order_id : self.u64()?,
+            price : self.opt_u32()?,
+            initial_quantity : self.u32()?,
+            current_quantity : self.u32()?,
+            is_buy_side : self.bool()?,
+            security_id : self.u32()?,
+            order_type : match self.u8()? {
+                0 => OrderType::Market(self.opt_u32()?),
+                1 => OrderType::Limit,
+                2 => OrderType::Stop(self.u32()?),
+                3 => OrderType::StopLimit(self.u32()?),
+                4 => OrderType::Iceberg(self.u32()?),
+                _ => return Err(EngineError::CorruptJournal("unknown order type"))
+            },
+            time_in_force : self.time_in_force()?,
+            post_only : self.post_only()?,
+            owner_id : self.u64()?,
+            client_order_id : self.opt_string()?,
+            session_id : self.opt_u64()?
+        })
+    }
+
+    pub(crate) fn time_in_force(&mut self) -> Result<TimeInForce, EngineError>{
+        match self.u8()? {
+            0 => Ok(TimeInForce::GoodTillCancel),
+            1 => Ok(TimeInForce::ImmediateOrCancel),
+            2 => Ok(TimeInForce::FillOrKill),
+            3 => Ok(TimeInForce::Day),
+            _ => Err(EngineError::CorruptJournal("unknown time in force"))
+        }
+    }
+
+    pub(crate) fn post_only(&mut self) -> Result<Option<PostOnly>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(PostOnly::Reject)),
+            2 => Ok(Some(PostOnly::Slide)),
+            _ => Err(EngineError::CorruptJournal("unknown post-only mode"))
+        }
+    }
+
+    pub(crate) fn auction_kind(&mut self) -> Result<AuctionKind, EngineError>{
+        match self.u8()? {
+            0 => Ok(AuctionKind::Opening),
+            1 => Ok(AuctionKind::Closing),
+            _ => Err(EngineError::CorruptJournal("unknown auction kind"))
+        }
+    }
+
+    pub(crate) fn trading_state(&mut self) -> Result<TradingState, EngineError>{
+        match self.u8()? {
+            0 => Ok(TradingState::PreOpen),
+            1 => Ok(TradingState::OpeningAuction),
+            2 => Ok(TradingState::Continuous),
+            3 => Ok(TradingState::Halted),
+            4 => Ok(TradingState::ClosingAuction),
+            5 => Ok(TradingState::Closed),
+            _ => Err(EngineError::CorruptJournal("unknown trading state"))
+        }
+    }
+
+    pub(crate) fn self_trade_prevention(&mut self) -> Result<Option<SelfTradePrevention>, EngineError>{
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(SelfTradePrevention::CancelNewest)),
+            2 => Ok(Some(SelfTradePrevention::CancelOldest)),
+            3 => Ok(Some(SelfTradePrevention::CancelBoth)),
+            4 => Ok(Some(SelfTradePrevention::DecrementAndCancel)),
+            _ => Err(EngineError::CorruptJournal("unknown self-trade prevention mode"))
+        }
+    }
+
+    fn record(&mut self) -> Result<JournalRecord, EngineError>{
+        let record = match self.u8()? {
+            0 => JournalRecord::RegisterSecurity(self.instrument()?),
+            1 => JournalRecord::DelistSecurity(self.u32()?),
+            2 => JournalRecord::Halt(self.u32()?),
+            3 => JournalRecord::Resume(self.u32()?),
+            4 => JournalRecord::SetSelfTradePrevention(self.self_trade_prevention()?),
+            5 => JournalRecord::NewOrder(self.new_order()?),
+            6 => JournalRecord::ModifyOrder(EngineModifyOrder {
+                order_id : self.u64()?,
+                security_id : self.u32()?,
+                is_buy_side : self.bool()?,
+                new_price : self.opt_u32()?,
+                new_quantity : self.opt_u32()?
+            }),
+            7 => JournalRecord::CancelOrder(EngineCancelOrder {
+                order_id : self.u64()?,
+                security_id : self.u32()?,
+                is_buy_side : self.bool()?
+            }),
+            8 => JournalRecord::Fill(Fill {
+                sequence : 0,
+                timestamp : 0,
+                trade_id : self.u64()?,
+                security_id : self.u32()?,
+                aggressor_order_id : self.u64()?,
+                passive_order_id : self.u64()?,
+                price : self.u32()?,
+                quantity : self.u32()?,
+                aggressor_is_buy_side : self.bool()?
+            }),
+            9 => JournalRecord::Rested { security_id : self.u32()?, order_id : self.u64()? },
+            10 => JournalRecord::Cancelled { security_id : self.u32()?, order_id : self.u64()? },
+            11 => JournalRecord::Rejected { reason : self.string()? },
+            12 => JournalRecord::WatchQueuePosition { security_id : self.u32()?, order_id : self.u64()?, is_buy_side : self.bool()? },
+            13 => JournalRecord::UnwatchQueuePosition { security_id : self.u32()?, order_id : self.u64()? },
+            14 => JournalRecord::MassCancel(MassCancelFilter {
+                security_id : self.opt_u32()?,
+                is_buy_side : self.opt_bool()?,
+                owner_id : self.opt_u64()?,
+                min_price : self.opt_u32()?,
+                max_price : self.opt_u32()?
+            }),
+            15 => JournalRecord::EndSession(self.u64()?),
+            16 => JournalRecord::SetSessionKeepsGtc { session_id : self.u64()?, keep : self.bool()? },
+            17 => JournalRecord::ExpireDayOrders(self.u32()?),
+            18 => JournalRecord::StartAuction { security_id : self.u32()?, kind : self.auction_kind()? },
+            19 => JournalRecord::Uncross(self.u32()?),
+            20 => JournalRecord::SetTradingState { security_id : self.u32()?, state : self.trading_state()? },
+            21 => JournalRecord::ScheduleTransition { security_id : self.u32()?, at : self.u64()?, state : self.trading_state()? },
+            22 => JournalRecord::ClearSchedule(self.u32()?),
+            23 => JournalRecord::RunSchedule,
+            _ => return Err(EngineError::CorruptJournal("unknown record tag"))
+        };
+        Ok(record)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use tracing::Span;
+    use super::{Journal, JournalRecord};
+    use crate::order_book::auction::AuctionKind;
+    use crate::order_book::clock::SimulatedClock;
+    use crate::order_book::error::EngineError;
+    use crate::order_book::matching_engine::MatchingEngine;
+    use crate::order_book::risk::{ParticipantLimits, RiskLimits};
+    use crate::order_book::test_support::{assert_same_state, journaled_engine, limit_order, TempFile};
+    use crate::order_book::trading_state::TradingState;
+    use crate::order_book::types::{EngineNewOrder, Instrument, MassCancelFilter, OrderType, PostOnly, TimeInForce};
+
+    #[test]
+    fn replay_rebuilds_the_engine(){
+        let journal = TempFile::new("replay");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 101, 10), &span).unwrap();
+        live.match_order(limit_order(2, false, 102, 10), &span).unwrap();
+        live.match_order(limit_order(3, true, 99, 5), &span).unwrap();
+        live.match_order(limit_order(4, true, 102, 15), &span).unwrap();
+        live.modify(2, 1, Some(103), None, false, &span).unwrap();
+        live.cancel(3, 1, &span, true).unwrap();
+        // rejected commands are replayed (and rejected) too
+        assert!(live.cancel(3, 1, &span, true).is_err());
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+    }
+
+    #[test]
+    fn a_mass_cancel_is_replayed(){
+        let journal = TempFile::new("mass-cancel");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, true, 99, 5), &span).unwrap();
+        live.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        live.match_order(EngineNewOrder { order_type : OrderType::Stop(90), price : None, ..limit_order(3, false, 0, 5) }, &span).unwrap();
+        live.match_order(limit_order(4, false, 102, 5), &span).unwrap();
+        let filter = MassCancelFilter { max_price : Some(101), ..Default::default() };
+        assert_eq!(live.mass_cancel(filter, &span).unwrap().len(), 3);
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+    }
+
+    #[test]
+    fn session_ends_and_day_order_expiry_are_replayed(){
+        let journal = TempFile::new("sessions");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.set_session_keeps_gtc(7, true).unwrap();
+        live.match_order(EngineNewOrder { session_id : Some(7), ..limit_order(1, true, 99, 5) }, &span).unwrap();
+        live.match_order(EngineNewOrder { session_id : Some(7), time_in_force : TimeInForce::Day, ..limit_order(2, true, 98, 5) }, &span).unwrap();
+        live.match_order(EngineNewOrder { session_id : Some(8), ..limit_order(3, false, 101, 5) }, &span).unwrap();
+        live.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(4, false, 102, 5) }, &span).unwrap();
+        assert_eq!(live.end_session(7, &span).unwrap().len(), 1);
+        assert_eq!(live.end_session(8, &span).unwrap().len(), 1);
+        assert_eq!(live.expire_day_orders(1, &span).unwrap().len(), 1);
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+    }
+
+    #[test]
+    fn a_risk_rejection_is_journaled_without_its_command(){
+        let journal = TempFile::new("risk");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.set_risk_check(Some(Box::new(ParticipantLimits::new(RiskLimits { max_order_quantity : Some(10), ..Default::default() }))));
+        live.match_order(limit_order(1, true, 99, 5), &span).unwrap();
+        live.match_order(limit_order(2, true, 99, 50), &span).unwrap_err();
+
+        let last = Journal::read_all(&journal.0).unwrap().pop().unwrap();
+        assert!(matches!(last, (_, _, JournalRecord::Rejected { reason }) if reason == "rejected by pre-trade risk: quantity 50 above the limit of 10"));
+        // replayed without the check, the trailing rejection still counts
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+    }
+
+    #[test]
+    fn commands_come_before_their_events(){
+        let journal = TempFile::new("events");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        live.match_order(limit_order(2, true, 100, 8), &span).unwrap();
+        assert!(live.cancel(9, 1, &span, true).is_err());
+
+        let records : Vec<String> = Journal::read_all(&journal.0).unwrap().into_iter()
+            .map(|(_, _, record)| match record {
+                JournalRecord::RegisterSecurity(_) => "register".to_string(),
+                JournalRecord::NewOrder(order) => format!("new {}", order.engine_order_id),
+                JournalRecord::CancelOrder(order) => format!("cancel {}", order.order_id),
+                JournalRecord::Fill(fill) => format!("fill {} {}", fill.passive_order_id, fill.quantity),
+                JournalRecord::Rested { order_id, .. } => format!("rested {}", order_id),
+                JournalRecord::Rejected { .. } => "rejected".to_string(),
+                other => format!("{:?}", other)
+            })
+            .collect();
+        assert_eq!(records, ["register", "new 1", "rested 1", "new 2", "fill 1 5", "rested 2", "cancel 9", "rejected"]);
+        // numbered by the engine, one after the other
+        let sequences : Vec<u64> = Journal::read_all(&journal.0).unwrap().into_iter().map(|(sequence, _, _)| sequence).collect();
+        assert_eq!(sequences, (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn orders_leaving_the_book_on_a_failure_are_journaled_as_cancelled(){
+        let journal = TempFile::new("cancelled");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        live.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        // released as a post-only bid at 102 with 101 still offered
+        let stop = EngineNewOrder { order_type : OrderType::StopLimit(101), post_only : Some(PostOnly::Reject), ..limit_order(10, true, 102, 5) };
+        live.match_order(stop, &span).unwrap();
+        live.match_order(limit_order(3, true, 101, 6), &span).unwrap();
+        // a post-only bid repriced through the ask can't come back
+        live.match_order(EngineNewOrder { post_only : Some(PostOnly::Reject), ..limit_order(4, true, 99, 5) }, &span).unwrap();
+        assert_eq!(live.modify(4, 1, Some(101), None, true, &span).unwrap_err(), EngineError::PostOnlyWouldCross(101));
+
+        let cancelled : Vec<u64> = Journal::read_all(&journal.0).unwrap().into_iter()
+            .filter_map(|(_, _, record)| match record {
+                JournalRecord::Cancelled { order_id, .. } => Some(order_id),
+                _ => None
+            })
+            .collect();
+        assert_eq!(cancelled, [10, 4]);
+    }
+
+    #[test]
+    fn a_torn_tail_is_ignored_on_replay_and_only_cut_off_on_request(){
+        let journal = TempFile::new("torn");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 100, 20), &span).unwrap();
+        drop(live);
+        let intact_len = std::fs::metadata(&journal.0).unwrap().len();
+        // a crash half way through writing a frame
+        std::fs::OpenOptions::new().append(true).open(&journal.0).unwrap().write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+
+        let mut restarted = MatchingEngine::replay(&journal.0).unwrap();
+        assert_eq!(restarted.attach_journal(&journal.0).unwrap_err(), EngineError::TornJournal(intact_len));
+        assert_eq!(std::fs::metadata(&journal.0).unwrap().len(), intact_len + 7);
+
+        assert_eq!(Journal::repair(&journal.0).unwrap(), 7);
+        assert_eq!(Journal::repair(&journal.0).unwrap(), 0);
+        restarted.attach_journal(&journal.0).unwrap();
+        restarted.match_order(limit_order(2, true, 100, 5), &span).unwrap();
+        restarted.match_order(limit_order(3, true, 99, 5), &span).unwrap();
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &restarted);
+    }
+
+    #[test]
+    fn a_damaged_length_before_the_tail_is_corruption(){
+        let journal = TempFile::new("corrupt");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 100, 20), &span).unwrap();
+        live.match_order(limit_order(2, false, 101, 20), &span).unwrap();
+        drop(live);
+        let mut bytes = std::fs::read(&journal.0).unwrap();
+        // the first frame (the registration) claims to run to well past the end of the file
+        bytes[0..4].copy_from_slice(&100_000u32.to_le_bytes());
+        std::fs::write(&journal.0, &bytes).unwrap();
+
+        assert!(matches!(Journal::read_all(&journal.0), Err(EngineError::CorruptJournal(_))));
+        assert!(matches!(MatchingEngine::replay(&journal.0), Err(EngineError::CorruptJournal(_))));
+        assert!(matches!(Journal::repair(&journal.0), Err(EngineError::CorruptJournal(_))));
+        assert_eq!(std::fs::read(&journal.0).unwrap(), bytes);
+    }
+
+    #[test]
+    fn an_auction_and_its_uncross_are_replayed(){
+        let journal = TempFile::new("auction");
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.start_auction(1, AuctionKind::Opening).unwrap();
+        live.match_order(EngineNewOrder { order_type : OrderType::Market(None), price : None, ..limit_order(1, true, 0, 20) }, &span).unwrap();
+        live.match_order(limit_order(2, true, 101, 5), &span).unwrap();
+        live.match_order(limit_order(3, false, 100, 10), &span).unwrap();
+        live.match_order(limit_order(4, false, 102, 5), &span).unwrap();
+        assert_eq!(live.uncross(1, &span).unwrap().cancelled_orders, [1]);
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+    }
+
+    #[test]
+    fn scheduled_transitions_are_made_at_the_same_point_on_replay(){
+        let journal = TempFile::new("schedule");
+        let span = Span::none();
+        let clock = SimulatedClock::new(1_000);
+        let mut live = MatchingEngine::new();
+        live.set_clock(Box::new(clock.clone()));
+        live.attach_journal(&journal.0).unwrap();
+        live.register_security(Instrument::new(1, "TEST")).unwrap();
+        live.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(1, true, 99, 5) }, &span).unwrap();
+        live.schedule_transition(1, 2_000, TradingState::ClosingAuction).unwrap();
+        live.schedule_transition(1, 3_000, TradingState::Closed).unwrap();
+        clock.set(2_000);
+        live.match_order(limit_order(2, false, 99, 3), &span).unwrap();
+        clock.set(3_000);
+        assert_eq!(live.run_schedule().unwrap().len(), 2);
+
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &live);
+        assert_eq!(replayed.trading_state(1).unwrap(), TradingState::Closed);
+    }
+}
@@ -1,34 +1,716 @@
 use crate::order_book::{
-    orderbook::OrderBook, types::{
-        BookDepth, CancelOutcome, EngineCancelOrder, EngineModifyOrder, EngineNewOrder, MatchOutcome, ModifyOutcome, OrderNode, OrderType
+    auction::{self, AuctionKind, AuctionPrice, CallAuction, UncrossOutcome}, clock::{Clock, SimulatedClock, SystemClock}, error::EngineError, journal::{Decoder, Encoder, Journal, JournalRecord}, order_status::OrderTracker, orderbook::{HalfBook, OrderBook}, position::{Position, PositionTracker}, risk::{MarketContext, RiskCheck, RiskOrder}, snapshot, trading_state::{StateTransition, TradingState}, types::{
+        BboUpdate, BookDepth, EngineCancelOrder, EngineModifyOrder, EngineNewOrder, Fill, MatchOutcome, Instrument, LevelUpdate, MarketDataEvent, MassCancelFilter, MatchingAlgorithm, ModifyOutcome, OrderAction, OrderNode, OrderState, OrderStatus, OrderUpdate, OrderType, PostOnly, PriceLevelDepth, QueuePosition, QueuePositionUpdate, SelfTradePrevention, TimeInForce, TradingStateUpdate
     }
 };
-use anyhow::{Context, anyhow};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::Path;
 use tracing::{Span};
 
 #[derive(Debug)]
 pub struct MatchingEngine {
-    _book: HashMap<u32, OrderBook>
+    _book: HashMap<u32, OrderBook>,
+    _instruments: HashMap<u32, Instrument>, // registered securities, always in step with `_book`
+    _trade_id: u64, // last trade id handed out, shared across all securities
+    _sequence: u64, // last sequence number handed out, one per command and one per event
+    _timestamp: u64, // engine time of the command being processed, never goes backwards
+    _clock: Box<dyn Clock>,
+    _self_trade_prevention: Option<SelfTradePrevention>, // None lets orders of the same owner trade
+    _journal: Option<Journal>, // when attached, every command and its events are appended here
+    _market_data: Vec<MarketDataEvent>, // published but not yet drained
+    _market_data_sequence: u64, // last sequence handed to a market data event
+    _orders: OrderTracker, // fills and final state per order, for `order_status`
+    _sessions_keeping_gtc: BTreeSet<u64>, // sessions whose good till cancel orders outlive `end_session`
+    _risk_check: Option<Box<dyn RiskCheck>>, // pre-trade checks, None lets every order through
+    _positions: PositionTracker, // traded quantities per owner and security
+    _fired_transitions: Vec<StateTransition> // made by the schedule but not yet handed out by `run_schedule`
+}
+
+impl Default for MatchingEngine {
+    fn default() -> Self{
+        Self::new()
+    }
 }
 
 impl MatchingEngine {
 
     pub fn new() -> Self{
-        Self { _book: HashMap::new()}
+        Self {
+            _book: HashMap::new(),
+            _instruments: HashMap::new(),
+            _trade_id: 0,
+            _sequence: 0,
+            _timestamp: 0,
+            _clock: Box::new(SystemClock),
+            _self_trade_prevention: None,
+            _journal: None,
+            _market_data: Vec::new(),
+            _market_data_sequence: 0,
+            _orders: OrderTracker::new(),
+            _sessions_keeping_gtc: BTreeSet::new(),
+            _risk_check: None,
+            _positions: PositionTracker::new(),
+            _fired_transitions: Vec::new()
+        }
+    }
+
+    // swaps the time source, e.g. for a `SimulatedClock` in tests and backtests
+    pub fn set_clock(&mut self, clock : Box<dyn Clock>){
+        self._clock = clock;
+    }
+
+    // e.g. `ParticipantLimits`. orders it rejects are journaled as a rejection only, never as a
+    // command, so replay and recovery don't need the same checks installed.
+    pub fn set_risk_check(&mut self, risk_check : Option<Box<dyn RiskCheck>>){
+        self._risk_check = risk_check;
+    }
+
+    // sequence number of the last command or event, consumers can use it to detect gaps
+    pub fn last_sequence(&self) -> u64{
+        self._sequence
+    }
+
+    // hands over everything published since the last drain. consumers seed a local book from
+    // `depth` together with `market_data_sequence` and apply the updates numbered after it.
+    pub fn drain_market_data(&mut self) -> Vec<MarketDataEvent>{
+        std::mem::take(&mut self._market_data)
+    }
+
+    pub fn market_data_sequence(&self) -> u64{
+        self._market_data_sequence
+    }
+
+    fn publish_market_data(&mut self, security_id : u32){
+        if let Some(orderbook) = self._book.get_mut(&security_id) {
+            Self::push_market_data(&mut self._market_data, &mut self._market_data_sequence, self._timestamp, security_id, orderbook);
+        }
+    }
+
+    // the order updates of the last command as they happened, then one update per price
+    // level it left in a different state (bids first), then the bbo if it moved and the
+    // watched orders whose place in the queue changed
+    fn push_market_data(market_data : &mut Vec<MarketDataEvent>, sequence : &mut u64, timestamp : u64, security_id : u32, orderbook : &mut OrderBook){
+        // only an order on a touched level can have moved, the rest keep their last position
+        let mut moved = Vec::new();
+        orderbook.queue_watches.retain(|order_id, (is_buy_side, position)| {
+            let half = if *is_buy_side { &orderbook.bid } else { &orderbook.ask };
+            let Some(order_node) = half.get_order(*order_id) else {
+                return false;
+            };
+            if half.touched_levels.contains_key(&order_node.market_limit)
+                && let Some(current) = half.queue_position(*order_id)
+                && current != *position {
+                *position = current;
+                moved.push((*order_id, *is_buy_side, current));
+            }
+            true
+        });
+        for mut update in orderbook.order_events.drain(..) {
+            *sequence += 1;
+            update.sequence = *sequence;
+            update.timestamp = timestamp;
+            update.security_id = security_id;
+            market_data.push(MarketDataEvent::Order(update));
+        }
+        for (is_buy_side, half) in [(true, &mut orderbook.bid), (false, &mut orderbook.ask)] {
+            for (price, action, quantity, order_count) in half.drain_level_changes() {
+                *sequence += 1;
+                market_data.push(MarketDataEvent::Level(LevelUpdate {
+                    sequence : *sequence,
+                    timestamp,
+                    security_id,
+                    is_buy_side,
+                    price,
+                    quantity,
+                    order_count,
+                    action
+                }));
+            }
+        }
+        let bbo = (orderbook.best_bid(), orderbook.best_ask());
+        if bbo != orderbook.published_bbo {
+            orderbook.published_bbo = bbo;
+            *sequence += 1;
+            market_data.push(MarketDataEvent::Bbo(BboUpdate {
+                sequence : *sequence,
+                timestamp,
+                security_id,
+                best_bid : bbo.0,
+                best_ask : bbo.1
+            }));
+        }
+        for (order_id, is_buy_side, position) in moved {
+            *sequence += 1;
+            market_data.push(MarketDataEvent::QueuePosition(QueuePositionUpdate {
+                sequence : *sequence,
+                timestamp,
+                security_id,
+                order_id,
+                is_buy_side,
+                position
+            }));
+        }
+    }
+
+    // starts journaling into `path`, appending to whatever is already there. fails with
+    // `TornJournal` when the file ends in a torn frame, see `Journal::repair`.
+    pub fn attach_journal(&mut self, path : &Path) -> Result<(), EngineError>{
+        let journal = Journal::open(path)?;
+        self._sequence = self._sequence.max(journal.last_sequence());
+        self._journal = Some(journal);
+        Ok(())
+    }
+
+    // rebuilds an engine by re-applying every journaled command in order
+    pub fn replay(path : &Path) -> Result<Self, EngineError>{
+        let mut engine = Self::new();
+        engine.apply_journal(path, 0)?;
+        Ok(engine)
+    }
+
+    // re-applies the journaled commands after `after_sequence` under their original sequence
+    // numbers and timestamps. commands that were rejected originally are rejected again, so
+    // their errors are not surfaced here.
+    fn apply_journal(&mut self, path : &Path, after_sequence : u64) -> Result<(), EngineError>{
+        let span = Span::none();
+        let replay_clock = SimulatedClock::default();
+        let clock = std::mem::replace(&mut self._clock, Box::new(replay_clock.clone()));
+        let records = Journal::read_all(path)?;
+        // a trailing risk rejection numbers an event without any command to replay
+        let last_sequence = records.last().map_or(0, |(sequence, _, _)| *sequence);
+        for (sequence, timestamp, record) in records {
+            if sequence <= after_sequence || !record.is_command() {
+                continue;
+            }
+            self._sequence = sequence - 1;
+            replay_clock.set(timestamp);
+            let _ = match record {
+                JournalRecord::RegisterSecurity(instrument) => self.register_security(instrument),
+                JournalRecord::DelistSecurity(security_id) => self.delist_security(security_id).map(|_| ()),
+                JournalRecord::Halt(security_id) => self.halt(security_id),
+                JournalRecord::Resume(security_id) => self.resume(security_id),
+                JournalRecord::SetSelfTradePrevention(mode) => self.set_self_trade_prevention(mode),
+                JournalRecord::NewOrder(order) => self.match_order(order, &span).map(|_| ()),
+                JournalRecord::ModifyOrder(order) => self
+                    .modify(order.order_id, order.security_id, order.new_price, order.new_quantity, order.is_buy_side, &span)
+                    .map(|_| ()),
+                JournalRecord::CancelOrder(order) => self.cancel(order.order_id, order.security_id, &span, order.is_buy_side).map(|_| ()),
+                JournalRecord::WatchQueuePosition { security_id, order_id, is_buy_side } => {
+                    self.watch_queue_position(security_id, order_id, is_buy_side).map(|_| ())
+                }
+                JournalRecord::UnwatchQueuePosition { security_id, order_id } => self.unwatch_queue_position(security_id, order_id),
+                JournalRecord::MassCancel(filter) => self.mass_cancel(filter, &span).map(|_| ()),
+                JournalRecord::EndSession(session_id) => self.end_session(session_id, &span).map(|_| ()),
+                JournalRecord::SetSessionKeepsGtc { session_id, keep } => self.set_session_keeps_gtc(session_id, keep),
+                JournalRecord::StartAuction { security_id, kind } => self.start_auction(security_id, kind),
+                JournalRecord::Uncross(security_id) => self.uncross(security_id, &span).map(|_| ()),
+                JournalRecord::ExpireDayOrders(security_id) => self.expire_day_orders(security_id, &span).map(|_| ()),
+                JournalRecord::SetTradingState { security_id, state } => self.set_trading_state(security_id, state, &span).map(|_| ()),
+                JournalRecord::ScheduleTransition { security_id, at, state } => self.schedule_transition(security_id, at, state),
+                JournalRecord::ClearSchedule(security_id) => self.clear_schedule(security_id),
+                JournalRecord::RunSchedule => self.run_schedule().map(|_| ()),
+                _ => Ok(())
+            };
+        }
+        // whoever ran the engine originally was handed these already
+        self._fired_transitions.clear();
+        self._clock = clock;
+        self._sequence = self._sequence.max(last_sequence);
+        Ok(())
+    }
+
+    // writes the full engine state to `path` and returns the last journal sequence it covers
+    // (0 without a journal). securities are written in id order.
+    pub fn save_snapshot(&self, path : &Path) -> Result<u64, EngineError>{
+        let journal_sequence = self._journal.as_ref().map_or(0, |journal| journal.last_sequence());
+        let mut body = Encoder::default();
+        body.u64(journal_sequence);
+        body.u64(self._sequence);
+        body.u64(self._timestamp);
+        body.u64(self._trade_id);
+        body.u64(self._market_data_sequence);
+        body.self_trade_prevention(self._self_trade_prevention);
+        let instruments = self.securities();
+        body.u32(instruments.len() as u32);
+        for instrument in instruments {
+            body.instrument(instrument);
+            body.order_book(&self._book[&instrument.security_id]);
+        }
+        body.order_tracker(&self._orders);
+        body.u32(self._sessions_keeping_gtc.len() as u32);
+        for session_id in &self._sessions_keeping_gtc {
+            body.u64(*session_id);
+        }
+        body.position_tracker(&self._positions);
+        snapshot::write(path, body)?;
+        Ok(journal_sequence)
+    }
+
+    // loads a snapshot written by `save_snapshot`. the restored engine has no journal attached.
+    pub fn restore_snapshot(path : &Path) -> Result<Self, EngineError>{
+        Self::read_snapshot(path).map(|(engine, _)| engine)
+    }
+
+    fn read_snapshot(path : &Path) -> Result<(Self, u64), EngineError>{
+        let body = snapshot::read(path)?;
+        let mut decoder = Decoder { buf : &body, pos : 0 };
+        let mut engine = Self::new();
+        let journal_sequence = decoder.u64().map_err(snapshot::corrupt)?;
+        engine._sequence = decoder.u64().map_err(snapshot::corrupt)?;
+        engine._timestamp = decoder.u64().map_err(snapshot::corrupt)?;
+        engine._trade_id = decoder.u64().map_err(snapshot::corrupt)?;
+        engine._market_data_sequence = decoder.u64().map_err(snapshot::corrupt)?;
+        engine._self_trade_prevention = decoder.self_trade_prevention().map_err(snapshot::corrupt)?;
+        for _ in 0..decoder.u32().map_err(snapshot::corrupt)? {
+            let instrument = decoder.instrument().map_err(snapshot::corrupt)?;
+            let mut orderbook = decoder.order_book().map_err(snapshot::corrupt)?;
+            orderbook.matching_algorithm = instrument.matching_algorithm;
+            orderbook.lot_size = instrument.lot_size;
+            engine._book.insert(instrument.security_id, orderbook);
+            engine._instruments.insert(instrument.security_id, instrument);
+        }
+        engine._orders = decoder.order_tracker().map_err(snapshot::corrupt)?;
+        for _ in 0..decoder.u32().map_err(snapshot::corrupt)? {
+            engine._sessions_keeping_gtc.insert(decoder.u64().map_err(snapshot::corrupt)?);
+        }
+        engine._positions = decoder.position_tracker().map_err(snapshot::corrupt)?;
+        if decoder.pos != body.len() {
+            return Err(EngineError::CorruptSnapshot("trailing bytes after the last book"));
+        }
+        Ok((engine, journal_sequence))
+    }
+
+    // restart path: restore the snapshot, re-apply whatever the journal recorded after it and
+    // keep journaling into the same file
+    pub fn recover(snapshot_path : &Path, journal_path : &Path) -> Result<Self, EngineError>{
+        let (mut engine, journal_sequence) = Self::read_snapshot(snapshot_path)?;
+        engine.apply_journal(journal_path, journal_sequence)?;
+        engine.attach_journal(journal_path)?;
+        Ok(engine)
+    }
+
+    // write-ahead: the command is on disk (synced, not just buffered) before the engine acts on it.
+    // also stamps the command: it gets the next sequence number and fixes the engine time
+    // every event it produces is reported at. scheduled transitions that fell due by then are
+    // made before the command is processed.
+    fn journal_command(&mut self, record : JournalRecord) -> Result<u64, EngineError>{
+        self._sequence += 1;
+        self._timestamp = self._timestamp.max(self._clock.now());
+        if let Some(journal) = self._journal.as_mut() {
+            journal.append(self._sequence, self._timestamp, &record)?;
+            journal.sync()?;
+        }
+        let sequence = self._sequence;
+        self.fire_due_transitions(sequence)?;
+        Ok(sequence)
+    }
+
+    // earliest first, securities due at the same time in id order. they are derived from the
+    // schedule and the command timestamps alone, so replay makes them at the same point.
+    fn fire_due_transitions(&mut self, sequence : u64) -> Result<(), EngineError>{
+        let span = Span::none();
+        loop {
+            let due = self._book.iter()
+                .filter_map(|(security_id, orderbook)| orderbook.schedule.first().map(|(at, _)| (*at, *security_id)))
+                .filter(|(at, _)| *at <= self._timestamp)
+                .min();
+            let Some((_, security_id)) = due else {
+                return Ok(());
+            };
+            let Some(orderbook) = self._book.get_mut(&security_id) else {
+                return Ok(());
+            };
+            let (_, state) = orderbook.schedule.remove(0);
+            let result = self.process_transition(security_id, state, sequence, &span);
+            self.journal_events(&result, Self::transition_events)?;
+            if let Ok(transition) = result {
+                self._fired_transitions.push(transition);
+            }
+        }
+    }
+
+    // events are numbered whether or not a journal is attached, so sequence numbers don't
+    // depend on it. fills already got theirs while matching.
+    fn journal_events<T>(&mut self, result : &Result<T, EngineError>, events : impl FnOnce(&T) -> Vec<JournalRecord>) -> Result<(), EngineError>{
+        let records = match result {
+            Ok(value) => events(value),
+            Err(e) => vec![JournalRecord::Rejected { reason : e.to_string() }]
+        };
+        for record in &records {
+            let sequence = match record {
+                JournalRecord::Fill(fill) => fill.sequence,
+                _ => { self._sequence += 1; self._sequence }
+            };
+            if let Some(journal) = self._journal.as_mut() {
+                journal.append(sequence, self._timestamp, record)?;
+            }
+        }
+        match self._journal.as_mut() {
+            Some(journal) => journal.flush(),
+            None => Ok(())
+        }
+    }
+
+    fn match_events(security_id : u32, order_id : u64, outcome : &MatchOutcome) -> Vec<JournalRecord>{
+        let mut records : Vec<JournalRecord> = outcome.fills.iter().map(|fill| JournalRecord::Fill(*fill)).collect();
+        records.extend(outcome.self_trade_cancels.iter().map(|cancelled_id| JournalRecord::Cancelled { security_id, order_id : *cancelled_id }));
+        records.extend(outcome.cancelled_stops.iter().map(|cancelled_id| JournalRecord::Cancelled { security_id, order_id : *cancelled_id }));
+        if outcome.order_index.is_some() {
+            records.push(JournalRecord::Rested { security_id, order_id });
+        }
+        records
+    }
+
+    pub fn set_self_trade_prevention(&mut self, mode : Option<SelfTradePrevention>) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::SetSelfTradePrevention(mode))?;
+        self._self_trade_prevention = mode;
+        self.journal_events(&Ok(()), |_| Vec::new())
+    }
+
+    pub fn register_security(&mut self, instrument : Instrument) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::RegisterSecurity(instrument.clone()))?;
+        let result = self.process_register_security(instrument);
+        self.journal_events(&result, |_| Vec::new())?;
+        result
+    }
+
+    fn process_register_security(&mut self, instrument : Instrument) -> Result<(), EngineError>{
+        if self._instruments.contains_key(&instrument.security_id) {
+            return Err(EngineError::DuplicateSecurity(instrument.security_id));
+        }
+        instrument.validate_reference_data()?;
+        let mut orderbook = OrderBook::new();
+        orderbook.matching_algorithm = instrument.matching_algorithm;
+        orderbook.lot_size = instrument.lot_size;
+        self._book.insert(instrument.security_id, orderbook);
+        self._instruments.insert(instrument.security_id, instrument);
+        Ok(())
+    }
+
+    pub fn instrument(&self, security_id : u32) -> Option<&Instrument>{
+        self._instruments.get(&security_id)
+    }
+
+    // registered securities ordered by security id
+    pub fn securities(&self) -> Vec<&Instrument>{
+        let mut instruments : Vec<&Instrument> = self._instruments.values().collect();
+        instruments.sort_by_key(|instrument| instrument.security_id);
+        instruments
+    }
+
+    // drops the security together with its book. every resting and untriggered stop order
+    // is cancelled, their ids are handed back in ascending order.
+    pub fn delist_security(&mut self, security_id : u32) -> Result<Vec<u64>, EngineError>{
+        self.journal_command(JournalRecord::DelistSecurity(security_id))?;
+        let result = self.process_delist_security(security_id);
+        self.journal_events(&result, |cancelled| {
+            cancelled.iter().map(|order_id| JournalRecord::Cancelled { security_id, order_id : *order_id }).collect()
+        })?;
+        result
+    }
+
+    fn process_delist_security(&mut self, security_id : u32) -> Result<Vec<u64>, EngineError>{
+        let mut orderbook = self._book.remove(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        self._instruments.remove(&security_id);
+        // the book goes away with the security, every order and level it had is deleted for consumers
+        for (is_buy_side, half) in [(true, &mut orderbook.bid), (false, &mut orderbook.ask)] {
+            for (price, price_level) in &half.price_map {
+                let mut cursor = price_level.head;
+                while let Some(order_node) = cursor.and_then(|idx| half.order_pool.get(idx)).and_then(|node| node.as_ref()) {
+                    orderbook.order_events.push(OrderUpdate::new(order_node.order_id, is_buy_side, *price, order_node.current_quantity, OrderAction::Delete));
+                    cursor = order_node.next;
+                }
+            }
+            let prices : Vec<u32> = half.price_map.keys().copied().collect();
+            for price in prices {
+                half.touch(price);
+            }
+            half.price_map.clear();
+        }
+        Self::push_market_data(&mut self._market_data, &mut self._market_data_sequence, self._timestamp, security_id, &mut orderbook);
+        let mut cancelled : Vec<u64> = orderbook.bid.order_registry.keys()
+            .chain(orderbook.ask.order_registry.keys())
+            .copied()
+            .chain(orderbook.triggers.order_ids())
+            .chain(orderbook.auction.iter().flat_map(|auction| auction.market_orders.iter().map(|order| order.engine_order_id)))
+            .collect();
+        cancelled.sort_unstable();
+        for order_id in &cancelled {
+            self._orders.finish(security_id, *order_id, OrderState::Cancelled);
+        }
+        Ok(cancelled)
+    }
+
+    pub fn trading_state(&self, security_id : u32) -> Result<TradingState, EngineError>{
+        self._book.get(&security_id).map(|orderbook| orderbook.state).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    // moves the security to `state` now, see `TradingState::can_transition_to` for the allowed
+    // changes. the transition carries the uncross when the change ended an auction and the day
+    // orders the close expired.
+    pub fn set_trading_state(&mut self, security_id : u32, state : TradingState, span: &Span) -> Result<StateTransition, EngineError>{
+        let sequence = self.journal_command(JournalRecord::SetTradingState { security_id, state })?;
+        let result = self.process_transition(security_id, state, sequence, span);
+        self.journal_events(&result, Self::transition_events)?;
+        result
+    }
+
+    // entering an auction state starts collecting orders, or carries on with the auction a halt
+    // interrupted. leaving an auction for anything but a halt uncrosses it. staying in the same
+    // state is a no-op.
+    fn process_transition(&mut self, security_id : u32, to : TradingState, sequence : u64, span: &Span) -> Result<StateTransition, EngineError>{
+        let orderbook = self._book.get_mut(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let from = orderbook.state;
+        let mut transition = StateTransition { security_id, from, to, uncross : None, expired_orders : Vec::new(), sequence, timestamp : self._timestamp };
+        if from == to {
+            return Ok(transition);
+        }
+        if !from.can_transition_to(to) {
+            return Err(EngineError::InvalidStateTransition { security_id, from, to });
+        }
+        // the new state is in place before the uncross, stops are only released into continuous trading
+        orderbook.state = to;
+        match (to.auction_kind(), orderbook.auction.as_mut()) {
+            (Some(kind), Some(auction)) => auction.kind = kind,
+            (Some(kind), None) => orderbook.auction = Some(CallAuction::new(kind)),
+            (None, Some(_)) if to != TradingState::Halted => {
+                let mut outcome = self.process_uncross(security_id, span)?;
+                outcome.sequence = sequence;
+                outcome.timestamp = self._timestamp;
+                transition.uncross = Some(outcome);
+            }
+            _ => {}
+        }
+        // day orders end with the trading day, after the closing uncross had its go at them
+        if to == TradingState::Closed {
+            transition.expired_orders = self.process_expire_day_orders(security_id, span)?;
+        }
+        self.publish_market_data(security_id);
+        self._market_data_sequence += 1;
+        self._market_data.push(MarketDataEvent::TradingState(TradingStateUpdate {
+            sequence : self._market_data_sequence,
+            timestamp : self._timestamp,
+            security_id,
+            state : to
+        }));
+        Ok(transition)
+    }
+
+    fn transition_events(transition : &StateTransition) -> Vec<JournalRecord>{
+        let mut records = transition.uncross.as_ref().map_or(Vec::new(), |outcome| Self::uncross_events(transition.security_id, outcome));
+        records.extend(Self::cancelled_events(&transition.expired_orders));
+        records
+    }
+
+    // `state` takes effect at engine time `at`: the first command stamped at or after it makes
+    // the change before its own work, `run_schedule` makes it when no command comes in.
+    // changes due at the same time are made in the order they were scheduled.
+    pub fn schedule_transition(&mut self, security_id : u32, at : u64, state : TradingState) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::ScheduleTransition { security_id, at, state })?;
+        let result = self.process_schedule_transition(security_id, at, state);
+        self.journal_events(&result, |_| Vec::new())?;
+        result
+    }
+
+    fn process_schedule_transition(&mut self, security_id : u32, at : u64, state : TradingState) -> Result<(), EngineError>{
+        let orderbook = self.get_orderbook(security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let position = orderbook.schedule.partition_point(|(scheduled_at, _)| *scheduled_at <= at);
+        orderbook.schedule.insert(position, (at, state));
+        Ok(())
+    }
+
+    // (engine time, state) still to come for the security, earliest first
+    pub fn scheduled_transitions(&self, security_id : u32) -> Result<&[(u64, TradingState)], EngineError>{
+        self._book.get(&security_id).map(|orderbook| orderbook.schedule.as_slice()).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    pub fn clear_schedule(&mut self, security_id : u32) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::ClearSchedule(security_id))?;
+        let result = self.get_orderbook(security_id).ok_or(EngineError::UnknownSecurity(security_id)).map(|orderbook| orderbook.schedule.clear());
+        self.journal_events(&result, |_| Vec::new())?;
+        result
+    }
+
+    // makes the scheduled changes due at the current engine time and hands over every one made
+    // since the last call, also those made ahead of other commands. a scheduled change the
+    // security can't make from the state it is in by then is dropped and journaled as a rejection.
+    pub fn run_schedule(&mut self) -> Result<Vec<StateTransition>, EngineError>{
+        self.journal_command(JournalRecord::RunSchedule)?;
+        self.journal_events(&Ok(()), |_| Vec::new())?;
+        Ok(std::mem::take(&mut self._fired_transitions))
+    }
+
+    // a halted security keeps its book and any auction it interrupted, but only accepts
+    // cancels. halting a halted security is a no-op.
+    pub fn halt(&mut self, security_id : u32) -> Result<(), EngineError>{
+        let sequence = self.journal_command(JournalRecord::Halt(security_id))?;
+        let result = self.process_transition(security_id, TradingState::Halted, sequence, &Span::none());
+        self.journal_events(&result, Self::transition_events)?;
+        result.map(|_| ())
+    }
+
+    // back into the auction the halt interrupted, otherwise into continuous trading. resuming
+    // a security that isn't halted is a no-op.
+    pub fn resume(&mut self, security_id : u32) -> Result<(), EngineError>{
+        let sequence = self.journal_command(JournalRecord::Resume(security_id))?;
+        let result = self.process_resume(security_id, sequence);
+        self.journal_events(&result, Self::transition_events)?;
+        result.map(|_| ())
+    }
+
+    fn process_resume(&mut self, security_id : u32, sequence : u64) -> Result<StateTransition, EngineError>{
+        let orderbook = self._book.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let to = match (orderbook.state, &orderbook.auction) {
+            (TradingState::Halted, Some(auction)) if auction.kind == AuctionKind::Opening => TradingState::OpeningAuction,
+            (TradingState::Halted, Some(_)) => TradingState::ClosingAuction,
+            (TradingState::Halted, None) => TradingState::Continuous,
+            (state, _) => state
+        };
+        self.process_transition(security_id, to, sequence, &Span::none())
+    }
+
+    // moves the security into the auction state of `kind`: it collects orders without matching
+    // them until `uncross`. limit orders rest as usual, market orders (on open / on close) wait
+    // for the uncross and IOC / FOK orders are rejected.
+    pub fn start_auction(&mut self, security_id : u32, kind : AuctionKind) -> Result<(), EngineError>{
+        let sequence = self.journal_command(JournalRecord::StartAuction { security_id, kind })?;
+        let result = self.process_start_auction(security_id, kind, sequence);
+        self.journal_events(&result, |_| Vec::new())?;
+        result.map(|_| ())
+    }
+
+    fn process_start_auction(&mut self, security_id : u32, kind : AuctionKind, sequence : u64) -> Result<StateTransition, EngineError>{
+        let orderbook = self.get_orderbook(security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        if orderbook.state == TradingState::Halted {
+            return Err(EngineError::SecurityHalted(security_id));
+        }
+        if orderbook.auction.is_some() {
+            return Err(EngineError::AuctionInProgress(security_id));
+        }
+        let to = match kind {
+            AuctionKind::Opening => TradingState::OpeningAuction,
+            AuctionKind::Closing => TradingState::ClosingAuction
+        };
+        self.process_transition(security_id, to, sequence, &Span::none())
+    }
+
+    // the price the auction would uncross at if it ended now, None while nothing crosses
+    pub fn indicative_auction_price(&self, security_id : u32) -> Result<Option<AuctionPrice>, EngineError>{
+        let orderbook = self._book.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let auction = orderbook.auction.as_ref().ok_or(EngineError::NoAuction(security_id))?;
+        Ok(auction::equilibrium_price(orderbook, auction))
+    }
+
+    // ends the auction: everything executable trades at the equilibrium price and market orders
+    // left unfilled are cancelled. an opening auction goes on into continuous trading, where
+    // stops triggered by the auction price are released, a closing auction into the close,
+    // which expires the day orders left over. the auction applies no self-trade prevention, an
+    // owner's buy and sell may trade each other.
+    pub fn uncross(&mut self, security_id : u32, span: &Span) -> Result<UncrossOutcome, EngineError>{
+        let sequence = self.journal_command(JournalRecord::Uncross(security_id))?;
+        let result = self.process_end_auction(security_id, sequence, span);
+        self.journal_events(&result, Self::transition_events)?;
+        result?.uncross.ok_or(EngineError::InvariantViolation("auction ended without an uncross"))
+    }
+
+    fn uncross_events(security_id : u32, outcome : &UncrossOutcome) -> Vec<JournalRecord>{
+        let mut records : Vec<JournalRecord> = outcome.fills.iter().map(|fill| JournalRecord::Fill(*fill)).collect();
+        records.extend(outcome.cancelled_orders.iter()
+            .chain(&outcome.self_trade_cancels)
+            .map(|order_id| JournalRecord::Cancelled { security_id, order_id : *order_id }));
+        records
+    }
+
+    fn process_end_auction(&mut self, security_id : u32, sequence : u64, span: &Span) -> Result<StateTransition, EngineError>{
+        let orderbook = self._book.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let to = match orderbook.state {
+            TradingState::OpeningAuction => TradingState::Continuous,
+            TradingState::ClosingAuction => TradingState::Closed,
+            TradingState::Halted if orderbook.auction.is_some() => {
+                span.record("reason", "security halted");
+                return Err(EngineError::SecurityHalted(security_id));
+            }
+            _ => return Err(EngineError::NoAuction(security_id))
+        };
+        self.process_transition(security_id, to, sequence, span)
+    }
+
+    // runs with the security already in the state the auction ends into
+    fn process_uncross(&mut self, security_id : u32, span: &Span) -> Result<UncrossOutcome, EngineError>{
+        let _gaurd = span.enter();
+        let orderbook = self._book.get_mut(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let auction = orderbook.auction.take().ok_or(EngineError::NoAuction(security_id))?;
+        let auction_price = auction::equilibrium_price(orderbook, &auction);
+        let mut fills = match &auction_price {
+            Some(auction_price) => auction::uncross(orderbook, &auction, auction_price, security_id, &mut self._trade_id, self._timestamp)?,
+            None => Vec::new()
+        };
+        for fill in &mut fills {
+            self._sequence += 1;
+            fill.sequence = self._sequence;
+        }
+        if let Some(last_fill) = fills.last() {
+            orderbook.last_trade_price = Some(last_fill.price);
+        }
+        span.record("matched_quantity", auction_price.map_or(0, |auction_price| auction_price.matched_quantity));
+
+        // both sides are tracked like passive orders, resting ones are done once they left the book
+        for fill in &fills {
+            for (order_id, is_buy_side) in [(fill.aggressor_order_id, true), (fill.passive_order_id, false)] {
+                self._orders.fill(security_id, order_id, fill.quantity);
+                if let Some(tracked) = self._orders.get(security_id, order_id) {
+                    self._positions.record_fill(tracked.owner_id, security_id, is_buy_side, fill.price, fill.quantity);
+                }
+                let half = if is_buy_side { &orderbook.bid } else { &orderbook.ask };
+                if !half.order_registry.contains_key(&order_id) && auction.get(order_id, is_buy_side).is_none() {
+                    self._orders.finish(security_id, order_id, OrderState::Filled);
+                }
+            }
+        }
+        let mut cancelled_orders = Vec::new();
+        for order in &auction.market_orders {
+            let filled_quantity : u32 = fills.iter()
+                .filter(|fill| order.engine_order_id == if order.is_buy_side { fill.aggressor_order_id } else { fill.passive_order_id })
+                .map(|fill| fill.quantity)
+                .sum();
+            if filled_quantity == order.current_quantity {
+                self._orders.finish(security_id, order.engine_order_id, OrderState::Filled);
+            } else {
+                self._orders.finish(security_id, order.engine_order_id, OrderState::Cancelled);
+                cancelled_orders.push(order.engine_order_id);
+            }
+        }
+
+        let mut released = MatchOutcome::default();
+        self.release_stops(security_id, &mut released, span);
+        fills.extend(released.fills);
+        Ok(UncrossOutcome {
+            auction_price,
+            fills,
+            cancelled_orders,
+            released_stops : released.released_stops,
+            self_trade_cancels : released.self_trade_cancels,
+            sequence : 0,
+            timestamp : 0
+        })
+    }
+
+    fn is_resting(&self, security_id : u32, order_id : u64, is_buy_side : bool) -> bool{
+        self._book.get(&security_id).is_some_and(|orderbook| {
+            let half = if is_buy_side { &orderbook.bid } else { &orderbook.ask };
+            half.order_registry.contains_key(&order_id)
+        })
     }
 
     fn get_orderbook(
         &mut self,
         security_id : u32
     ) -> Option<&mut OrderBook> {
-        
-        let Some(book) = self._book.get_mut(&security_id) else {
-            return None;
-        };
-        Some(book)
+        self._book.get_mut(&security_id)
     }
 
+    // when the modification needs the order to re-enter the book, the outcome of that
+    // re-match (fills, new index) is handed back along with the modification kind.
     pub fn modify(
         &mut self,
         order_id: u64,
@@ -37,12 +719,78 @@ impl MatchingEngine {
         new_qty: Option<u32>,
         is_buy_side : bool,
         span: &Span,
-    ) -> Result< &'static str, anyhow::Error> {
+    ) -> Result<(&'static str, Option<MatchOutcome>), EngineError> {
+        // a plain size reduction only ever takes risk off. the order re-enters with what it has
+        // left (iceberg reserve included), not with its initial quantity.
+        if let Some(tracked) = self._orders.get(security_id, order_id)
+            && (new_price.is_some() || new_qty.is_some_and(|quantity| quantity > tracked.initial_quantity)) {
+            let remaining = self._book.get(&security_id)
+                .and_then(|orderbook| if is_buy_side { orderbook.bid.get_order(order_id) } else { orderbook.ask.get_order(order_id) })
+                .map_or(0, |order_node| order_node.current_quantity + order_node.hidden_quantity);
+            self.check_risk(RiskOrder {
+                security_id,
+                owner_id : tracked.owner_id,
+                is_buy_side,
+                price : new_price.or(tracked.price),
+                quantity : remaining
+            }, Some(order_id))?;
+        }
+        let sequence = self.journal_command(JournalRecord::ModifyOrder(EngineModifyOrder {
+            order_id,
+            security_id,
+            is_buy_side,
+            new_price,
+            new_quantity: new_qty,
+        }))?;
+        let was_resting = self.is_resting(security_id, order_id, is_buy_side);
+        let mut result = self.process_modify(order_id, security_id, new_price, new_qty, is_buy_side, span);
+        self.publish_market_data(security_id);
+        if let Ok((_, Some(outcome))) = &mut result {
+            outcome.sequence = sequence;
+            outcome.timestamp = self._timestamp;
+        }
+        self.journal_events(&result, |(_, outcome)| match outcome {
+            Some(outcome) => Self::match_events(security_id, order_id, outcome),
+            None => Vec::new()
+        })?;
+        // a rejected re-entry took the order out of the book on the way
+        if result.is_err() && was_resting && !self.is_resting(security_id, order_id, is_buy_side) {
+            self.journal_events(&Ok(()), |_| vec![JournalRecord::Cancelled { security_id, order_id }])?;
+        }
+        result
+    }
+
+    fn process_modify(
+        &mut self,
+        order_id: u64,
+        security_id : u32,
+        new_price: Option<u32>,
+        new_qty: Option<u32>,
+        is_buy_side : bool,
+        span: &Span,
+    ) -> Result<(&'static str, Option<MatchOutcome>), EngineError> {
         let _gaurd = span.enter();
+        let instrument = self._instruments.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        if let Some(price) = new_price {
+            instrument.validate_price(price)?;
+        }
+        if let Some(quantity) = new_qty {
+            instrument.validate_quantity(quantity)?;
+        }
+        let time_in_force = self._orders.get(security_id, order_id).map_or(TimeInForce::GoodTillCancel, |tracked| tracked.time_in_force);
         let orderbook = self
             .get_orderbook(security_id)
-            .context("Could not find the orderbook")?;
-        if let Ok(potential_modfication) = orderbook.modify_order(
+            .ok_or(EngineError::UnknownSecurity(security_id))?;
+        orderbook.state.check_order_entry(security_id)?;
+        // the order re-enters the book with the same owner, time in force and post-only mode,
+        // and icebergs with the same peak
+        let half = if is_buy_side { &orderbook.bid } else { &orderbook.ask };
+        let (order_type, post_only, owner_id) = match half.get_order(order_id) {
+            Some(order_node) if order_node.peak_quantity > 0 => (OrderType::Iceberg(order_node.peak_quantity), order_node.post_only, order_node.owner_id),
+            Some(order_node) => (OrderType::Limit, order_node.post_only, order_node.owner_id),
+            None => (OrderType::Limit, None, 0)
+        };
+        let potential_modfication = orderbook.modify_order(
             order_id,
             EngineModifyOrder {
                 order_id,
@@ -51,89 +799,257 @@ impl MatchingEngine {
                 is_buy_side,
                 new_quantity: new_qty,
             },
-        ) {
-            if let Some(modification_result) = potential_modfication {
-                match modification_result {
-                    ModifyOutcome::Both {
-                        new_price,
-                        new_initial_qty,
-                        old_current_qty,
-                    } => {
-                        span.record("modify_outcome", "price & qty");
-                        let _ = self.match_order(
-                            EngineNewOrder {
-                                engine_order_id: order_id,
-                                price: Some(new_price),
-                                initial_quantity: new_initial_qty,
-                                current_quantity : old_current_qty,
-                                is_buy_side,
-                                security_id,
-                                order_type: OrderType::Limit,
-                            },
-                        span);
-                        return Ok("Both")
-                    },
-                    ModifyOutcome::Repriced { new_price, old_initial_qty, old_current_qty } => 
-                        {
-                        span.record("modify_outcome", "price");
-                            let _ = self.match_order(
-                            EngineNewOrder {
-                                engine_order_id: order_id,
-                                price: Some(new_price),
-                                initial_quantity: old_initial_qty,
-                                current_quantity : old_current_qty,
-                                is_buy_side,
-                                security_id,
-                                order_type: OrderType::Limit,
-                            },
-                        span);
-                        return Ok("Repriced")
+        )?;
+        let Some(modification_result) = potential_modfication else {
+            return Ok(("No potential modification", None));
+        };
+        match modification_result {
+            ModifyOutcome::Both {
+                new_price,
+                new_initial_qty,
+                old_current_qty,
+            } => {
+                span.record("modify_outcome", "price & qty");
+                let outcome = self.enter_order(
+                    EngineNewOrder {
+                        engine_order_id: order_id,
+                        price: Some(new_price),
+                        initial_quantity: new_initial_qty,
+                        current_quantity : old_current_qty,
+                        is_buy_side,
+                        security_id,
+                        order_type,
+                        time_in_force,
+                        post_only,
+                        owner_id,
+                        client_order_id: None, // keeps the one it already has
+                        session_id: None, // same
                     },
-                    ModifyOutcome::Requantized { old_price, new_initial_qty, old_current_qty } => {
-                            let _ = self.match_order(
-                            EngineNewOrder {
-                                engine_order_id: order_id,
-                                price: Some(old_price),
-                                initial_quantity: new_initial_qty,
-                                current_quantity : old_current_qty,
-                                is_buy_side,
-                                security_id,
-                                order_type: OrderType::Limit,
-                            }, span);
-                            return Ok("Requantized")
+                span)?;
+                Ok(("Both", Some(outcome)))
+            },
+            ModifyOutcome::Repriced { new_price, old_initial_qty, old_current_qty } => {
+                span.record("modify_outcome", "price");
+                let outcome = self.enter_order(
+                    EngineNewOrder {
+                        engine_order_id: order_id,
+                        price: Some(new_price),
+                        initial_quantity: old_initial_qty,
+                        current_quantity : old_current_qty,
+                        is_buy_side,
+                        security_id,
+                        order_type,
+                        time_in_force,
+                        post_only,
+                        owner_id,
+                        client_order_id: None, // keeps the one it already has
+                        session_id: None, // same
                     },
-                    ModifyOutcome::Inplace => {
-                        span.record("modify_outcome", "qty reduction");
-                        return Ok("Inplace")
-                    }
+                span)?;
+                Ok(("Repriced", Some(outcome)))
+            },
+            ModifyOutcome::Requantized { old_price, new_initial_qty, old_current_qty } => {
+                let outcome = self.enter_order(
+                    EngineNewOrder {
+                        engine_order_id: order_id,
+                        price: Some(old_price),
+                        initial_quantity: new_initial_qty,
+                        current_quantity : old_current_qty,
+                        is_buy_side,
+                        security_id,
+                        order_type,
+                        time_in_force,
+                        post_only,
+                        owner_id,
+                        client_order_id: None, // keeps the one it already has
+                        session_id: None, // same
+                    }, span)?;
+                Ok(("Requantized", Some(outcome)))
+            },
+            ModifyOutcome::Inplace => {
+                span.record("modify_outcome", "qty reduction");
+                if let Some(new_qty) = new_qty {
+                    self._orders.set_initial_quantity(security_id, order_id, new_qty);
                 }
+                Ok(("Inplace", None))
             }
-            return Ok("No potential modification")
+        }
+    }
+
+    pub fn cancel(&mut self, order_id: u64,security_id : u32, span: &Span, is_buy_side : bool) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::CancelOrder(EngineCancelOrder{is_buy_side, security_id, order_id}))?;
+        let result = self.process_cancel(order_id, security_id, span, is_buy_side);
+        if result.is_ok() {
+            self._orders.finish(security_id, order_id, OrderState::Cancelled);
+        }
+        self.publish_market_data(security_id);
+        self.journal_events(&result, |_| vec![JournalRecord::Cancelled { security_id, order_id }])?;
+        result
+    }
+
+    // cancels every order the filter selects in one command, resting orders, untriggered
+    // stops and market orders waiting for an auction alike, across all securities (in id
+    // order) unless it names one. an order that fails to cancel stays where it is and the
+    // rest go ahead. the cancelled orders are handed back in the order they were taken out.
+    pub fn mass_cancel(&mut self, filter : MassCancelFilter, span: &Span) -> Result<Vec<EngineCancelOrder>, EngineError>{
+        self.journal_command(JournalRecord::MassCancel(filter))?;
+        let result = self.process_mass_cancel(filter, span);
+        self.journal_events(&result, |cancelled| Self::cancelled_events(cancelled))?;
+        result
+    }
+
+    fn process_mass_cancel(&mut self, filter : MassCancelFilter, span: &Span) -> Result<Vec<EngineCancelOrder>, EngineError>{
+        let mut security_ids : Vec<u32> = match filter.security_id {
+            Some(security_id) if !self._book.contains_key(&security_id) => {
+                span.record("reason", "unknown security");
+                return Err(EngineError::UnknownSecurity(security_id));
+            }
+            Some(security_id) => vec![security_id],
+            None => self._book.keys().copied().collect()
+        };
+        security_ids.sort_unstable();
+        let mut selected = Vec::new();
+        for security_id in security_ids {
+            if let Some(orderbook) = self._book.get(&security_id) {
+                selected.extend(orderbook.mass_cancel_selection(security_id, &filter));
+            }
+        }
+        let cancelled = self.cancel_each(selected, span);
+        span.record("cancelled_count", cancelled.len());
+        Ok(cancelled)
+    }
+
+    // cancels the orders one at a time. an order that fails to cancel stays where it is and the
+    // rest go ahead, only the ones cancelled are handed back. the books they were in publish
+    // once all are done.
+    fn cancel_each(&mut self, orders : Vec<EngineCancelOrder>, span: &Span) -> Vec<EngineCancelOrder>{
+        let mut cancelled = Vec::new();
+        for order in orders {
+            if self.process_cancel(order.order_id, order.security_id, span, order.is_buy_side).is_ok() {
+                self._orders.finish(order.security_id, order.order_id, OrderState::Cancelled);
+                cancelled.push(order);
+            }
+        }
+        let mut security_ids : Vec<u32> = cancelled.iter().map(|order| order.security_id).collect();
+        security_ids.sort_unstable();
+        security_ids.dedup();
+        for security_id in security_ids {
+            self.publish_market_data(security_id);
+        }
+        cancelled
+    }
+
+    // by default every order of the session goes when it ends, a session keeping its good till
+    // cancel orders only loses its day orders
+    pub fn set_session_keeps_gtc(&mut self, session_id : u64, keep : bool) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::SetSessionKeepsGtc { session_id, keep })?;
+        if keep {
+            self._sessions_keeping_gtc.insert(session_id);
         } else {
-            return Ok("No modification occured");
+            self._sessions_keeping_gtc.remove(&session_id);
+        }
+        self.journal_events(&Ok(()), |_| Vec::new())
+    }
+
+    // cancel-on-disconnect: cancels the session's resting and untriggered stop orders, sorted
+    // by security id then order id. an order that fails to cancel doesn't hold up the rest.
+    // the session id can be used again afterwards.
+    pub fn end_session(&mut self, session_id : u64, span: &Span) -> Result<Vec<EngineCancelOrder>, EngineError>{
+        self.journal_command(JournalRecord::EndSession(session_id))?;
+        let result = Ok(self.process_end_session(session_id, span));
+        self.journal_events(&result, |cancelled| Self::cancelled_events(cancelled))?;
+        result
+    }
+
+    fn process_end_session(&mut self, session_id : u64, span: &Span) -> Vec<EngineCancelOrder>{
+        let keeps_gtc = self._sessions_keeping_gtc.contains(&session_id);
+        let orders = self._orders.session_orders(session_id).into_iter()
+            .filter(|(_, _, _, time_in_force)| !(keeps_gtc && *time_in_force == TimeInForce::GoodTillCancel))
+            .map(|(security_id, order_id, is_buy_side, _)| EngineCancelOrder { order_id, security_id, is_buy_side })
+            .collect();
+        self.cancel_each(orders, span)
+    }
+
+    // ends the trading day of the security: its day orders, resting or waiting on a stop, are
+    // cancelled by order id. good till cancel orders carry over. closing the security does the
+    // same on its own.
+    pub fn expire_day_orders(&mut self, security_id : u32, span: &Span) -> Result<Vec<EngineCancelOrder>, EngineError>{
+        self.journal_command(JournalRecord::ExpireDayOrders(security_id))?;
+        let result = self.process_expire_day_orders(security_id, span);
+        self.journal_events(&result, |cancelled| Self::cancelled_events(cancelled))?;
+        result
+    }
+
+    fn process_expire_day_orders(&mut self, security_id : u32, span: &Span) -> Result<Vec<EngineCancelOrder>, EngineError>{
+        if !self._book.contains_key(&security_id) {
+            return Err(EngineError::UnknownSecurity(security_id));
         }
+        let orders = self._orders.day_orders(security_id).into_iter()
+            .map(|(order_id, is_buy_side)| EngineCancelOrder { order_id, security_id, is_buy_side })
+            .collect();
+        Ok(self.cancel_each(orders, span))
+    }
+
+    fn cancelled_events(cancelled : &[EngineCancelOrder]) -> Vec<JournalRecord>{
+        cancelled.iter().map(|order| JournalRecord::Cancelled { security_id : order.security_id, order_id : order.order_id }).collect()
+    }
+
+    // the live order `owner_id` placed under `client_order_id`
+    fn resolve_client_order_id(&self, owner_id : u64, client_order_id : &str) -> Result<(u32, u64, bool), EngineError>{
+        self._orders.client_order(owner_id, client_order_id)
+            .ok_or_else(|| EngineError::UnknownClientOrderId { owner_id, client_order_id : client_order_id.to_string() })
     }
 
-    pub fn cancel(&mut self, order_id: u64,security_id : u32, span: &Span, is_buy_side : bool) -> Result<CancelOutcome, anyhow::Error>{
+    // journaled as a plain cancel of the engine order id it resolves to
+    pub fn cancel_by_client_order_id(&mut self, owner_id : u64, client_order_id : &str, span: &Span) -> Result<(), EngineError>{
+        let (security_id, order_id, is_buy_side) = self.resolve_client_order_id(owner_id, client_order_id)?;
+        self.cancel(order_id, security_id, span, is_buy_side)
+    }
+
+    // journaled as a plain modify of the engine order id it resolves to
+    pub fn modify_by_client_order_id(
+        &mut self,
+        owner_id : u64,
+        client_order_id : &str,
+        new_price: Option<u32>,
+        new_qty: Option<u32>,
+        span: &Span,
+    ) -> Result<(&'static str, Option<MatchOutcome>), EngineError> {
+        let (security_id, order_id, is_buy_side) = self.resolve_client_order_id(owner_id, client_order_id)?;
+        self.modify(order_id, security_id, new_price, new_qty, is_buy_side, span)
+    }
+
+    pub fn order_status_by_client_order_id(&self, owner_id : u64, client_order_id : &str) -> Result<OrderStatus, EngineError>{
+        let (security_id, order_id, _) = self.resolve_client_order_id(owner_id, client_order_id)?;
+        self.order_status(security_id, order_id)
+    }
+
+    fn process_cancel(&mut self, order_id: u64,security_id : u32, span: &Span, is_buy_side : bool) -> Result<(), EngineError>{
         let orderbook = self
             .get_orderbook(security_id)
-            .context("Could not find the orderbook")?;
-        if let Err(_) = orderbook.cancel_order(order_id, EngineCancelOrder{is_buy_side,security_id, order_id}){
+            .ok_or(EngineError::UnknownSecurity(security_id))?;
+        // untriggered stops and market orders waiting for an auction live outside the half books
+        if orderbook.triggers.remove(order_id, is_buy_side).is_some()
+            || orderbook.auction.as_mut().and_then(|auction| auction.remove(order_id, is_buy_side)).is_some() {
+            span.record("success_status", true);
+            return Ok(());
+        }
+        if let Err(e) = orderbook.cancel_order(order_id, EngineCancelOrder{is_buy_side,security_id, order_id}){
             span.record("reason", "orderbook cancellation failed");
             span.record("success_status", false);
-            return Ok(CancelOutcome::Failed);
-        }; 
+            return Err(e);
+        };
         span.record("success_status", true);
-        return Ok(CancelOutcome::Success);
+        Ok(())
     }
 
-    pub fn depth(&self, security_id : u32, levels_count :Option<u32>, span: &Span ) -> Result<BookDepth, anyhow::Error>{
+    pub fn depth(&self, security_id : u32, levels_count :Option<u32>, span: &Span ) -> Result<BookDepth, EngineError>{
         let _gaurd = span.enter();
         span.record("security_id", security_id.to_string());
         let Some(order_book) = self._book.get(&security_id) else {
             span.record("status", "failed");
             span.record("reason", "orderbook doesn't exist");
-            return Err(anyhow!(""))
+            return Err(EngineError::UnknownSecurity(security_id))
         };
         match order_book.depth(levels_count){
             Ok(book_depth) => {
@@ -141,552 +1057,2442 @@ impl MatchingEngine {
                 span.record("reason", "None");
                 Ok(book_depth)
             },
-            Err(e) => Err(anyhow!("{}", e))
+            Err(e) => Err(e)
         }
     }
 
-    pub fn match_order(&mut self, order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, anyhow::Error> {
-        
-        let _gaurd = span.enter();
-
-        let orderbook = match self._book.get_mut(&order.security_id){
-            Some(orderbook) => {
-                orderbook
-            }
-            None => {
-                self._book.entry(order.security_id).or_insert(OrderBook::new())
-            }
+    // live orders are answered from the book, filled and cancelled ones only while they are
+    // among the last `set_order_status_retention` orders to finish
+    pub fn order_status(&self, security_id : u32, order_id : u64) -> Result<OrderStatus, EngineError>{
+        let tracked = self._orders.get(security_id, order_id).ok_or(EngineError::UnknownOrder(order_id))?;
+        let mut status = OrderStatus {
+            security_id,
+            order_id,
+            client_order_id : tracked.client_order_id.clone(),
+            is_buy_side : tracked.is_buy_side,
+            price : tracked.price,
+            initial_quantity : tracked.initial_quantity,
+            current_quantity : 0,
+            filled_quantity : tracked.filled_quantity,
+            queue_position : None,
+            state : tracked.state
         };
+        if tracked.state != OrderState::Live {
+            return Ok(status);
+        }
+        let orderbook = self._book.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        let half = if tracked.is_buy_side { &orderbook.bid } else { &orderbook.ask };
+        if let Some(order_node) = half.get_order(order_id) {
+            status.price = Some(order_node.market_limit);
+            status.initial_quantity = order_node.initial_quantity;
+            status.current_quantity = order_node.current_quantity + order_node.hidden_quantity;
+            status.queue_position = half.queue_position(order_id).map(|position| position.orders_ahead + 1);
+        } else if let Some(waiting) = orderbook.triggers.get(order_id, tracked.is_buy_side)
+            .or_else(|| orderbook.auction.as_ref().and_then(|auction| auction.get(order_id, tracked.is_buy_side))) {
+            status.current_quantity = waiting.current_quantity;
+        }
+        Ok(status)
+    }
 
-        if !order.is_buy_side {
-            // for ASK order
-            match order.order_type {
-                OrderType::Market(None) => {
-                    // need to immediatly execute the order on the best of other half
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.bid.price_map.last_entry() else {
-                                break;
-                            };
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                if let Some(head_idx) = price_level.head{
-
-                                    match orderbook.bid.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                                fill_quantity -= first_order_node.current_quantity;
-                                                
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                                let next = first_order_node.next;
-                                                orderbook.bid.order_pool[head_idx] = None;
-                                                orderbook.bid.free_list.push(head_idx);
-                                                orders_touched += 1;
-                                                if let Some(next_order_idx) = next {
-                                                    price_level.head = Some(next_order_idx);
-                                                } else {
-                                                    span.record("reason", "exhausted");
-                                                    price_level.total_quantity = 0;
-                                                    price_level.head = None;
-                                                    price_level.tail = None;
-                                                    price_level.order_count = 0;
-                                                    break;
-                                                }
-                                            } else {
-                                                first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                                fill_quantity = 0;
-                                                orders_touched += 1;
-                                                span.record("filled", true);
-                                            }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
-                                        }
-                                    };
-                                }else {
-                                    // price level has no head. i.e head = None
-                                    break;
-                                }
-                            }
-                            remove_node = price_level.total_quantity == 0;
-                        }
-                        if remove_node {
-                            match orderbook.bid.price_map.pop_last(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
-                        }
-                    }
-                    span.record("order_type", "market");
-                    span.record("is_buy_side", false);
-                    span.record("levels_consumed", levels_consumed);
-                    span.record("orders_touched", orders_touched);
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
-                }
-                OrderType::Market(market_limit) => {
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.bid.price_map.last_entry() else {
-                                break;
-                            };
+    pub fn set_order_status_retention(&mut self, retention : usize){
+        self._orders.set_retention(retention);
+    }
 
-                            match market_limit {
-                                Some(price) => {
-                                    if price > *price_node.key(){
-                                        break;
-                                    }
-                                }
-                                None => {
-                                    return Err(anyhow!("did not recieve price for market-limit(SELL)"))
-                                }
-                            }
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                if let Some(head_idx) = price_level.head{
-
-                                    match orderbook.bid.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                                fill_quantity -= first_order_node.current_quantity;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                                let next = first_order_node.next;
-                                                orderbook.bid.order_pool[head_idx] = None;
-                                                orderbook.bid.free_list.push(head_idx);
-                                                orders_touched += 1;
-                                                if let Some(next_order_idx) = next {
-                                                    price_level.head = Some(next_order_idx);
-                                                } else {
-                                                    span.record("reason", "exhausted");
-                                                    price_level.total_quantity = 0;
-                                                    price_level.head = None;
-                                                    price_level.tail = None;
-                                                    price_level.order_count = 0;
-                                                    break;
-                                                }
-                                            } else {
-                                                first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                                fill_quantity = 0;
-                                                orders_touched += 1;
-                                                span.record("filled", true);
-                                            }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
-                                        }
-                                    };
-                                }else {
-                                    break;
-                                }
-                            }
-                            remove_node = price_level.total_quantity == 0;
-                        }
-                        if remove_node {
-                            match orderbook.bid.price_map.pop_last(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
-                        }
-                    }
-                    span.record("order_type", "market");
-                    span.record("is_buy_side", false);
-                    span.record("levels_consumed", levels_consumed);
-                    span.record("orders_touched", orders_touched);
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
+    pub fn queue_position(&self, security_id : u32, order_id : u64, is_buy_side : bool) -> Result<QueuePosition, EngineError>{
+        let orderbook = self._book.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        orderbook.queue_position(order_id, is_buy_side).ok_or(EngineError::UnknownOrder(order_id))
+    }
+
+    // from now on every command that moves the order in its queue publishes its new position.
+    // the watch ends with `unwatch_queue_position` or when the order leaves the book.
+    pub fn watch_queue_position(&mut self, security_id : u32, order_id : u64, is_buy_side : bool) -> Result<QueuePosition, EngineError>{
+        self.journal_command(JournalRecord::WatchQueuePosition { security_id, order_id, is_buy_side })?;
+        let result = self._book.get_mut(&security_id).ok_or(EngineError::UnknownSecurity(security_id)).and_then(|orderbook| {
+            let position = orderbook.queue_position(order_id, is_buy_side).ok_or(EngineError::UnknownOrder(order_id))?;
+            orderbook.queue_watches.insert(order_id, (is_buy_side, position));
+            Ok(position)
+        });
+        self.journal_events(&result, |_| Vec::new())?;
+        result
+    }
+
+    pub fn unwatch_queue_position(&mut self, security_id : u32, order_id : u64) -> Result<(), EngineError>{
+        self.journal_command(JournalRecord::UnwatchQueuePosition { security_id, order_id })?;
+        let result = self._book.get_mut(&security_id).ok_or(EngineError::UnknownSecurity(security_id)).and_then(|orderbook| {
+            orderbook.queue_watches.remove(&order_id).map(|_| ()).ok_or(EngineError::UnknownOrder(order_id))
+        });
+        self.journal_events(&result, |_| Vec::new())?;
+        result
+    }
+
+    // what the owner has traded in the security and what its resting orders there add up to
+    pub fn position(&self, owner_id : u64, security_id : u32) -> Result<Position, EngineError>{
+        if !self._book.contains_key(&security_id) {
+            return Err(EngineError::UnknownSecurity(security_id));
+        }
+        Ok(self.position_of(owner_id, security_id))
+    }
+
+    // every security the owner has traded or has resting orders in, by security id. delisted
+    // securities keep what was traded in them.
+    pub fn positions(&self, owner_id : u64) -> Vec<Position>{
+        let mut security_ids = self._positions.securities(owner_id);
+        security_ids.extend(self._book.iter()
+            .filter(|(_, orderbook)| orderbook.bid.exposure.contains_key(&owner_id) || orderbook.ask.exposure.contains_key(&owner_id))
+            .map(|(security_id, _)| *security_id));
+        security_ids.sort_unstable();
+        security_ids.dedup();
+        security_ids.into_iter().map(|security_id| self.position_of(owner_id, security_id)).collect()
+    }
+
+    fn position_of(&self, owner_id : u64, security_id : u32) -> Position{
+        let orderbook = self._book.get(&security_id);
+        Position {
+            owner_id,
+            security_id,
+            traded : self._positions.get(owner_id, security_id),
+            open_buy : orderbook.map(|orderbook| orderbook.bid.owner_exposure(owner_id)).unwrap_or_default(),
+            open_sell : orderbook.map(|orderbook| orderbook.ask.owner_exposure(owner_id)).unwrap_or_default()
+        }
+    }
+
+    pub fn best_bid(&self, security_id : u32) -> Result<Option<PriceLevelDepth>, EngineError>{
+        self._book.get(&security_id).map(OrderBook::best_bid).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    pub fn best_ask(&self, security_id : u32) -> Result<Option<PriceLevelDepth>, EngineError>{
+        self._book.get(&security_id).map(OrderBook::best_ask).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    pub fn spread(&self, security_id : u32) -> Result<Option<u32>, EngineError>{
+        self._book.get(&security_id).map(OrderBook::spread).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    pub fn mid(&self, security_id : u32) -> Result<Option<f64>, EngineError>{
+        self._book.get(&security_id).map(OrderBook::mid).ok_or(EngineError::UnknownSecurity(security_id))
+    }
+
+    pub fn match_order(&mut self, order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, EngineError> {
+        let (security_id, order_id) = (order.security_id, order.engine_order_id);
+        self.check_risk(RiskOrder {
+            security_id,
+            owner_id : order.owner_id,
+            is_buy_side : order.is_buy_side,
+            price : match order.order_type {
+                OrderType::Market(market_limit) => market_limit,
+                _ => order.price
+            },
+            quantity : order.initial_quantity
+        }, None)?;
+        let sequence = self.journal_command(JournalRecord::NewOrder(order.clone()))?;
+        let mut result = self.process_new_order(order, span);
+        self.publish_market_data(security_id);
+        if let Ok(outcome) = &mut result {
+            outcome.sequence = sequence;
+            outcome.timestamp = self._timestamp;
+        }
+        self.journal_events(&result, |outcome| Self::match_events(security_id, order_id, outcome))?;
+        result
+    }
+
+    // a rejected order is journaled as a bare rejection at the current engine time. a modify
+    // passes the order it replaces, whose resting quantity then isn't counted twice.
+    fn check_risk(&mut self, order : RiskOrder, replaces : Option<u64>) -> Result<(), EngineError>{
+        let Some(risk_check) = self._risk_check.as_ref() else {
+            return Ok(());
+        };
+        let mut position = self.position_of(order.owner_id, order.security_id);
+        if let Some(order_node) = replaces.and_then(|order_id| {
+            let orderbook = self._book.get(&order.security_id)?;
+            if order.is_buy_side { orderbook.bid.get_order(order_id) } else { orderbook.ask.get_order(order_id) }
+        }) {
+            let open = if order.is_buy_side { &mut position.open_buy } else { &mut position.open_sell };
+            let quantity = (order_node.current_quantity + order_node.hidden_quantity) as u64;
+            open.quantity = open.quantity.saturating_sub(quantity);
+            open.notional = open.notional.saturating_sub(order_node.market_limit as u64 * quantity);
+        }
+        let market = self._book.get(&order.security_id).map_or(MarketContext::default(), |orderbook| MarketContext {
+            best_bid : orderbook.best_bid().map(|level| level.price_level),
+            best_ask : orderbook.best_ask().map(|level| level.price_level),
+            last_trade_price : orderbook.last_trade_price
+        });
+        let Err(rejection) = risk_check.check(&order, &market, &position) else {
+            return Ok(());
+        };
+        self._timestamp = self._timestamp.max(self._clock.now());
+        let result = Err(EngineError::RiskRejected(rejection));
+        self.journal_events(&result, |_ : &()| Vec::new())?;
+        result
+    }
+
+    fn process_new_order(&mut self, order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, EngineError> {
+
+        let _gaurd = span.enter();
+
+        let security_id = order.security_id;
+        let instrument = self._instruments.get(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        if let Err(e) = instrument.validate_order(&order) {
+            span.record("reason", "failed reference data validation");
+            return Err(e);
+        }
+        if let Err(e) = self._orders.check_client_order_id(&order) {
+            span.record("reason", "duplicate client order id");
+            return Err(e);
+        }
+        self.enter_order(order, span)
+    }
+
+    // the part of a new order shared with the re-entry of a modified one, whose remainder isn't
+    // held to the entry bounds again (a partial fill can leave it below the minimum quantity)
+    fn enter_order(&mut self, order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, EngineError> {
+        let security_id = order.security_id;
+        let orderbook = self._book.get_mut(&security_id).ok_or(EngineError::UnknownSecurity(security_id))?;
+        if let Err(e) = orderbook.state.check_order_entry(security_id) {
+            span.record("reason", "security not accepting orders");
+            return Err(e);
+        }
+        let mut outcome = match order.order_type {
+            OrderType::Stop(stop_price) | OrderType::StopLimit(stop_price) => {
+                if matches!(order.order_type, OrderType::StopLimit(_)) && order.price.is_none() {
+                    return Err(EngineError::InvalidPrice("stop-limit order without a limit price"));
                 }
-                OrderType::Limit => {
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.bid.price_map.last_entry() else {
-                                break;
-                            };
+                span.record("order_type", "stop");
+                self._orders.accept(&order);
+                orderbook.triggers.insert(stop_price, order);
+                MatchOutcome::default()
+            }
+            OrderType::Market(None) if orderbook.auction.is_some() && order.time_in_force.rests() => {
+                span.record("order_type", "market on auction");
+                self._orders.accept(&order);
+                if let Some(auction) = orderbook.auction.as_mut() {
+                    auction.market_orders.push(order);
+                }
+                MatchOutcome::default()
+            }
+            _ => self.execute_tracked(order, span)?
+        };
+        self.release_stops(security_id, &mut outcome, span);
+        Ok(outcome)
+    }
 
-                            match order.price {
-                                Some(price) => {
-                                    if price > *price_node.key(){
-                                        break;
-                                    }
-                                }
-                                None => {
-                                    return Err(anyhow!("did not recieve price for limit order (SELL)"))
-                                }
-                            }
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                if let Some(head_idx) = price_level.head{
-                                    match orderbook.bid.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                        fill_quantity -= first_order_node.current_quantity;
-                                        price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                        let next = first_order_node.next;
-                                        orderbook.bid.order_pool[head_idx] = None;
-                                        orderbook.bid.free_list.push(head_idx);
-                                        orders_touched += 1;
-                                        if let Some(next_order_idx) = next {
-                                            price_level.head = Some(next_order_idx);
-                                        } else {
-                                            span.record("reason", "partially_filled");
-                                            price_level.total_quantity = 0;
-                                            price_level.head = None;
-                                            price_level.tail = None;
-                                            price_level.order_count = 0;
-                                            break;
-                                        }
-                                    } else {
-                                        first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                        price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                        fill_quantity = 0;
-                                        orders_touched += 1;
-                                        span.record("filled", true);
-                                    }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
-                                        }
-                                    };
-                                }else {
-                                    break;
-                                }
-                            }
-                            remove_node = price_level.total_quantity == 0;
+    // every execution can move the last trade price and release more stops, so keep
+    // draining the trigger book until nothing else is triggered. stops are only released into
+    // continuous trading, an opening uncross releases those its price triggered.
+    fn release_stops(&mut self, security_id : u32, outcome : &mut MatchOutcome, span: &Span){
+        while let Some(orderbook) = self._book.get_mut(&security_id) && orderbook.state == TradingState::Continuous {
+            let Some(last_trade_price) = orderbook.last_trade_price else {
+                break;
+            };
+            let Some(mut released) = orderbook.triggers.pop_triggered(last_trade_price) else {
+                break;
+            };
+            released.order_type = match released.order_type {
+                OrderType::StopLimit(_) => OrderType::Limit,
+                _ => OrderType::Market(None)
+            };
+            let order_id = released.engine_order_id;
+            outcome.released_stops.push(order_id);
+            match self.execute_tracked(released, span) {
+                Ok(released_outcome) => {
+                    outcome.fills.extend(released_outcome.fills);
+                    outcome.self_trade_cancels.extend(released_outcome.self_trade_cancels);
+                }
+                // a released stop that can't be executed (e.g. a post-only that would cross) is cancelled
+                Err(_) => outcome.cancelled_stops.push(order_id)
+            }
+        }
+    }
+
+    // `execute` plus the bookkeeping behind `order_status`, for the order and every resting
+    // order it traded with or cancelled
+    fn execute_tracked(&mut self, order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, EngineError> {
+        let (security_id, order_id, is_buy_side) = (order.security_id, order.engine_order_id, order.is_buy_side);
+        let accepted = order.clone();
+        let outcome = match self.execute(order, span) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // a modify that failed to re-enter has already left the book
+                self._orders.finish(security_id, order_id, OrderState::Cancelled);
+                return Err(e);
+            }
+        };
+        self._orders.accept(&accepted);
+        let opposite_half = self._book.get(&security_id).map(|orderbook| if is_buy_side { &orderbook.ask } else { &orderbook.bid });
+        for fill in &outcome.fills {
+            self._orders.fill(security_id, order_id, fill.quantity);
+            self._orders.fill(security_id, fill.passive_order_id, fill.quantity);
+            self._positions.record_fill(accepted.owner_id, security_id, is_buy_side, fill.price, fill.quantity);
+            if let Some(passive) = self._orders.get(security_id, fill.passive_order_id) {
+                self._positions.record_fill(passive.owner_id, security_id, !is_buy_side, fill.price, fill.quantity);
+            }
+            if opposite_half.is_some_and(|half| !half.order_registry.contains_key(&fill.passive_order_id)) {
+                self._orders.finish(security_id, fill.passive_order_id, OrderState::Filled);
+            }
+        }
+        for cancelled_id in &outcome.self_trade_cancels {
+            self._orders.finish(security_id, *cancelled_id, OrderState::Cancelled);
+        }
+        if outcome.order_index.is_none() {
+            let state = if outcome.cancelled_quantity == 0 { OrderState::Filled } else { OrderState::Cancelled };
+            self._orders.finish(security_id, order_id, state);
+        }
+        Ok(outcome)
+    }
+
+    // runs a single market or limit order against the book, without looking at the trigger book
+    fn execute(&mut self, mut order: EngineNewOrder, span: &Span) -> Result<MatchOutcome, EngineError> {
+
+        let instrument = self._instruments.get(&order.security_id).ok_or(EngineError::UnknownSecurity(order.security_id))?;
+        let orderbook = self._book.get_mut(&order.security_id).ok_or(EngineError::UnknownSecurity(order.security_id))?;
+        // during an auction orders only rest, the uncross matches them
+        let accumulating = orderbook.auction.is_some();
+        if accumulating && (!order.time_in_force.rests() || matches!(order.order_type, OrderType::Market(_))) {
+            span.record("reason", "not accepted during an auction");
+            return Err(EngineError::UnsupportedOrder("only good till cancel and day limit orders and market orders without a limit are accepted during an auction"));
+        }
+
+        if let Some(post_only) = order.post_only {
+            if !matches!(order.order_type, OrderType::Limit | OrderType::Iceberg(_)) || !order.time_in_force.rests() {
+                return Err(EngineError::UnsupportedOrder("post-only is only supported on good till cancel and day limit orders"));
+            }
+            let price = order.price.ok_or(EngineError::InvalidPrice("post-only order without a price"))?;
+            let opposite_best = if order.is_buy_side {
+                orderbook.ask.price_map.first_key_value()
+            } else {
+                orderbook.bid.price_map.last_key_value()
+            }.map(|(best_price, _)| *best_price);
+            if !accumulating && let Some(best_price) = opposite_best {
+                let crosses = if order.is_buy_side { price >= best_price } else { price <= best_price };
+                if crosses {
+                    match post_only {
+                        PostOnly::Reject => {
+                            span.record("reason", "post-only would cross");
+                            return Err(EngineError::PostOnlyWouldCross(best_price));
                         }
-                        if remove_node {
-                            match orderbook.bid.price_map.pop_last(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
+                        PostOnly::Slide => {
+                            let slid_price = if order.is_buy_side {
+                                best_price.checked_sub(instrument.tick_size)
+                            } else {
+                                best_price.checked_add(instrument.tick_size)
+                            }.ok_or(EngineError::InvalidPrice("no price one tick behind the best to slide the post-only order to"))?;
+                            // the price band holds for the slid price as much as for the one sent
+                            if let Err(e) = instrument.validate_price(slid_price) {
+                                span.record("reason", "post-only slid out of the price band");
+                                return Err(e);
+                            }
+                            span.record("reason", "post-only slid");
+                            order.price = Some(slid_price);
                         }
                     }
-                    if fill_quantity > 0 {
-                        let alloted_index = orderbook.create_sell_order(
-                            order.engine_order_id,
-                            OrderNode {
-                                order_id : order.engine_order_id,
-                                initial_quantity: order.initial_quantity,
-                                current_quantity: fill_quantity,
-                                market_limit: order.price.unwrap(),
-                                next: None,
-                                prev: None,
-                            },
-                        )?;
-                        span.record("order_type", "limit");
-                        span.record("is_buy_side", false);
-                        span.record("levels_consumed", levels_consumed);
-                        span.record("orders_touched", orders_touched);
-                        return Ok(MatchOutcome{
-                        order_index : Some(alloted_index as u32),
-                        levels_consumed,
-                        orders_touched
-                    })
+                }
+            }
+        }
+
+        // Market(None) sweeps the opposite half without any cieling/floor,
+        // Market(Some) stops at the market limit and Limit stops at the order price.
+        let (price_bound, order_type) = match order.order_type {
+            OrderType::Market(market_limit) => (market_limit, "market"),
+            OrderType::Limit => {
+                match order.price {
+                    Some(price) => (Some(price), "limit"),
+                    None => {
+                        return Err(EngineError::InvalidPrice("limit order without a price"))
+                    }
+                }
+            }
+            OrderType::Iceberg(peak_quantity) => {
+                if peak_quantity == 0 {
+                    return Err(EngineError::InvalidQuantity("iceberg peak quantity has to be greater than zero"))
+                }
+                match order.price {
+                    Some(price) => (Some(price), "iceberg"),
+                    None => {
+                        return Err(EngineError::InvalidPrice("iceberg order without a price"))
                     }
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
                 }
             }
+            OrderType::Stop(_) | OrderType::StopLimit(_) => {
+                return Err(EngineError::InvariantViolation("stop orders have to be triggered before they can be matched"))
+            }
+        };
+
+        let opposite_half = if order.is_buy_side { &mut orderbook.ask } else { &mut orderbook.bid };
+
+        // FOK is checked up front against the opposite half so nothing is touched when it can't be met
+        if order.time_in_force == TimeInForce::FillOrKill
+            && opposite_half.crossable_quantity(
+                order.is_buy_side,
+                price_bound,
+                order.current_quantity,
+                self._self_trade_prevention.map(|mode| (order.owner_id, mode)),
+                orderbook.matching_algorithm
+            ) < order.current_quantity {
+            span.record("reason", "fill or kill not satisfiable");
+            return Ok(MatchOutcome {
+                cancelled_quantity : order.current_quantity,
+                ..Default::default()
+            });
+        }
+        let (fill_quantity, mut outcome) = if accumulating {
+            (order.current_quantity, MatchOutcome::default())
+        } else {
+            Self::sweep(orderbook, &order, price_bound, &mut self._trade_id, self._self_trade_prevention, self._timestamp, span)?
+        };
+        for fill in &mut outcome.fills {
+            self._sequence += 1;
+            fill.sequence = self._sequence;
+        }
+        if let Some(last_fill) = outcome.fills.last() {
+            orderbook.last_trade_price = Some(last_fill.price);
+        }
+
+        span.record("order_type", order_type);
+        span.record("is_buy_side", order.is_buy_side);
+        span.record("levels_consumed", outcome.levels_consumed);
+        span.record("orders_touched", outcome.orders_touched);
+
+        if fill_quantity == 0 {
+            return Ok(outcome);
+        }
+        if !matches!(order.order_type, OrderType::Limit | OrderType::Iceberg(_)) || !order.time_in_force.rests() {
+            // market leftovers and IOC/FOK remainders never rest
+            outcome.cancelled_quantity = fill_quantity;
+            return Ok(outcome);
+        }
+        // an iceberg only shows its peak, the rest waits in reserve
+        let peak_quantity = match order.order_type {
+            OrderType::Iceberg(peak_quantity) => peak_quantity,
+            _ => 0
+        };
+        let visible_quantity = if peak_quantity > 0 { fill_quantity.min(peak_quantity) } else { fill_quantity };
+        let resting_order = OrderNode {
+            order_id : order.engine_order_id,
+            initial_quantity: order.initial_quantity,
+            current_quantity: visible_quantity,
+            market_limit: order.price.unwrap(),
+            post_only: order.post_only,
+            peak_quantity,
+            hidden_quantity: fill_quantity - visible_quantity,
+            owner_id: order.owner_id,
+            entry_time: self._timestamp,
+            next: None,
+            prev: None,
+        };
+        let alloted_index = if order.is_buy_side {
+            orderbook.create_buy_order(order.engine_order_id, resting_order)?
         } else {
-            match order.order_type {
-                OrderType::Market(None) => {
-                    // need to immediatly execute the order on the best of other half
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.ask.price_map.first_entry() else {
+            orderbook.create_sell_order(order.engine_order_id, resting_order)?
+        };
+        outcome.order_index = Some(alloted_index as u32);
+        Ok(outcome)
+    }
+
+    // Walks the opposite half from its best price level towards `price_bound`, consuming
+    // resting orders in FIFO order. Every execution is reported as a `Fill` priced at the
+    // resting order. Returns the aggressor quantity left unfilled, quantity removed by
+    // self-trade prevention is reported in `cancelled_quantity` instead.
+    fn sweep(
+        orderbook : &mut OrderBook,
+        order : &EngineNewOrder,
+        price_bound : Option<u32>,
+        trade_id : &mut u64,
+        self_trade_prevention : Option<SelfTradePrevention>,
+        timestamp : u64,
+        span : &Span
+    ) -> Result<(u32, MatchOutcome), EngineError> {
+        let OrderBook { ask, bid, order_events, matching_algorithm, lot_size, .. } = orderbook;
+        let matching_algorithm = *matching_algorithm;
+        let HalfBook { price_map, order_registry, order_pool, free_list, touched_levels, exposure } = if order.is_buy_side { ask } else { bid };
+        let passive_is_buy_side = !order.is_buy_side;
+        let mut fill_quantity = order.current_quantity;
+        let mut outcome = MatchOutcome::default();
+        while fill_quantity > 0 {
+            let remove_node: bool;
+            {
+                let best_price = if order.is_buy_side { price_map.keys().next() } else { price_map.keys().next_back() };
+                if let Some(best_price) = best_price.copied() {
+                    HalfBook::touch_level(touched_levels, price_map, best_price);
+                }
+                // buy orders hit the lowest ask, sell orders hit the highest bid
+                let best_level = if order.is_buy_side { price_map.first_entry() } else { price_map.last_entry() };
+                let Some(mut price_node) = best_level else {
+                    break;
+                };
+                let level_price = *price_node.key();
+                if let Some(price) = price_bound {
+                    let crosses = if order.is_buy_side { price >= level_price } else { price <= level_price };
+                    if !crosses {
+                        break;
+                    }
+                }
+                let price_level = price_node.get_mut();
+                let mut allocations : VecDeque<(usize, u32)> = VecDeque::new(); // pro-rata shares of this round still to be traded
+                while price_level.total_quantity > 0 && fill_quantity > 0 {
+                    // fifo always trades with the head. pro-rata first gets the aggressor's own orders
+                    // out of the way (self-trade prevention), then splits what's left across the level.
+                    let (idx, share) = match matching_algorithm {
+                        MatchingAlgorithm::Fifo => {
+                            let Some(head_idx) = price_level.head else {
+                                // price level has no head. i.e head = None
                                 break;
                             };
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                if let Some(head_idx) = price_level.head{
-                                    match orderbook.ask.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                                fill_quantity -= first_order_node.current_quantity;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                                let next = first_order_node.next;
-                                                orderbook.ask.order_pool[head_idx] = None;
-                                                orderbook.ask.free_list.push(head_idx);
-                                                orders_touched += 1;
-                                                if let Some(next_order_idx) = next {
-                                                    price_level.head = Some(next_order_idx);
-                                                } else {
-                                                    span.record("reason", "exhausted");
-                                                    price_level.total_quantity = 0;
-                                                    price_level.head = None;
-                                                    price_level.tail = None;
-                                                    price_level.order_count = 0;
-                                                    break;
-                                                }
-                                            } else {
-                                                first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                                fill_quantity = 0;
-                                                orders_touched += 1;
-                                                span.record("filled", true);
-                                            }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
-                                        }
-                                    };
+                            (head_idx, None)
+                        }
+                        MatchingAlgorithm::ProRata { top_order_priority, min_allocation } => {
+                            let own_order = if allocations.is_empty() && self_trade_prevention.is_some() {
+                                HalfBook::find_owner_order(order_pool, price_level, order.owner_id)
+                            } else {
+                                None
+                            };
+                            if let Some(own_idx) = own_order {
+                                (own_idx, None)
+                            } else {
+                                if allocations.is_empty() {
+                                    allocations = HalfBook::pro_rata_allocation(order_pool, price_level, fill_quantity, *lot_size, top_order_priority, min_allocation);
                                 }
-                                else {
+                                let Some((idx, share)) = allocations.pop_front() else {
                                     break;
-                                }
+                                };
+                                (idx, Some(share))
                             }
-                            remove_node = price_level.total_quantity == 0;
                         }
-                        if remove_node {
-                            match orderbook.ask.price_map.pop_first(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
+                    };
+                    let (passive_order_id, resting_quantity, hidden_quantity, resting_owner_id, next) = match order_pool[idx].as_ref() {
+                        Some(first_order_node) => (
+                            first_order_node.order_id,
+                            first_order_node.current_quantity,
+                            first_order_node.hidden_quantity,
+                            first_order_node.owner_id,
+                            first_order_node.next
+                        ),
+                        None => {
+                            return Err(EngineError::InvariantViolation("failed to get the order to match from the order pool"));
                         }
-                    }
-                    span.record("order_type", "market");
-                    span.record("is_buy_side", true);
-                    span.record("levels_consumed", levels_consumed);
-                    span.record("orders_touched", orders_touched);
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
-                }
-                OrderType::Market(market_limit) => {
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.ask.price_map.first_entry() else {
-                                break;
-                            };
+                    };
 
-                            match market_limit {
-                                Some(price) => {
-                                    if price < *price_node.key(){
-                                        break;
-                                    }
-                                }
-                                None => {
-                                    return Err(anyhow!("did not recieve price for market-limit(BUY)"))
-                                }
+                    if let Some(mode) = self_trade_prevention && resting_owner_id == order.owner_id {
+                        span.record("reason", "self trade prevented");
+                        let cancel_resting = match mode {
+                            SelfTradePrevention::CancelNewest => {
+                                outcome.cancelled_quantity += fill_quantity;
+                                fill_quantity = 0;
+                                false
                             }
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                let head_pointer = price_level.head;
-                                if let Some(head_idx) = head_pointer{
-                                    match orderbook.ask.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                                fill_quantity -= first_order_node.current_quantity;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                                let next = first_order_node.next;
-                                                orderbook.ask.order_pool[head_idx] = None;
-                                                orderbook.ask.free_list.push(head_idx);
-                                                orders_touched += 1;
-                                                if let Some(next_order_idx) = next {
-                                                    price_level.head = Some(next_order_idx);
-                                                } else {
-                                                    span.record("reason", "exhausted");
-                                                    price_level.head = None;
-                                                    price_level.total_quantity = 0;
-                                                    price_level.head = None;
-                                                    price_level.tail = None;
-                                                    price_level.order_count = 0;
-                                                    break;
-                                                }
-                                            } else {
-                                                first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                                fill_quantity = 0;
-                                                orders_touched += 1;
-                                                span.record("filled", true);
-                                            }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
+                            SelfTradePrevention::CancelOldest => true,
+                            SelfTradePrevention::CancelBoth => {
+                                outcome.cancelled_quantity += fill_quantity;
+                                fill_quantity = 0;
+                                true
+                            }
+                            SelfTradePrevention::DecrementAndCancel => {
+                                let decrement = fill_quantity.min(resting_quantity + hidden_quantity);
+                                fill_quantity -= decrement;
+                                outcome.cancelled_quantity += decrement;
+                                if decrement < resting_quantity + hidden_quantity {
+                                    HalfBook::remove_exposure(exposure, resting_owner_id, level_price, decrement);
+                                    // take it out of the iceberg reserve first so the shown quantity stays put
+                                    if let Some(first_order_node) = order_pool[idx].as_mut() {
+                                        let from_hidden = decrement.min(hidden_quantity);
+                                        first_order_node.hidden_quantity -= from_hidden;
+                                        first_order_node.current_quantity -= decrement - from_hidden;
+                                        price_level.total_quantity -= decrement - from_hidden;
+                                        if decrement > from_hidden {
+                                            order_events.push(OrderUpdate::new(passive_order_id, passive_is_buy_side, level_price, decrement - from_hidden, OrderAction::Cancel));
                                         }
-                                    };
-                                }
-                                else {
-                                    break;
+                                    }
+                                    false
+                                } else {
+                                    true
                                 }
                             }
-                            remove_node = price_level.total_quantity == 0;
-                        }
-                        if remove_node {
-                            match orderbook.ask.price_map.pop_first(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
+                        };
+                        if cancel_resting {
+                            HalfBook::remove_exposure(exposure, resting_owner_id, level_price, resting_quantity + hidden_quantity);
+                            price_level.total_quantity = price_level.total_quantity.checked_sub(resting_quantity).ok_or(EngineError::InvariantViolation("error occured in sub of total qty - resting qty"))?;
+                            HalfBook::unlink(order_pool, free_list, order_registry, price_level, idx);
+                            order_events.push(OrderUpdate::new(passive_order_id, passive_is_buy_side, level_price, resting_quantity, OrderAction::Delete));
+                            outcome.self_trade_cancels.push(passive_order_id);
                         }
+                        continue;
                     }
-                    span.record("order_type", "market");
-                    span.record("is_buy_side", true);
-                    span.record("levels_consumed", levels_consumed);
-                    span.record("orders_touched", orders_touched);
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
-                }
-                OrderType::Limit => {
-                    let mut fill_quantity = order.initial_quantity;
-                    let mut levels_consumed = 0;
-                    let mut orders_touched = 0;
-                    while fill_quantity > 0 {
-                        let remove_node: bool;
-                        {
-                            let Some(mut price_node) = orderbook.ask.price_map.first_entry() else {
-                                break;
-                            };
 
-                            match order.price {
-                                Some(price) => {
-                                    if price < *price_node.key(){
-                                        break;
-                                    }
-                                }
-                                None => {
-                                    return Err(anyhow!("did not recieve price for limit(BUY)"))
-                                }
+                    let traded_quantity = share.unwrap_or(fill_quantity.min(resting_quantity));
+                    *trade_id += 1;
+                    outcome.fills.push(Fill {
+                        sequence : 0, // numbered by the caller once the sweep is done
+                        timestamp,
+                        trade_id : *trade_id,
+                        security_id : order.security_id,
+                        aggressor_order_id : order.engine_order_id,
+                        passive_order_id,
+                        price : level_price,
+                        quantity : traded_quantity,
+                        aggressor_is_buy_side : order.is_buy_side
+                    });
+                    order_events.push(OrderUpdate::new(passive_order_id, passive_is_buy_side, level_price, traded_quantity, OrderAction::Executed { trade_id : *trade_id }));
+                    fill_quantity -= traded_quantity;
+                    HalfBook::remove_exposure(exposure, resting_owner_id, level_price, traded_quantity);
+                    price_level.total_quantity = price_level.total_quantity.checked_sub(traded_quantity).ok_or(EngineError::InvariantViolation("error occured in sub of total qty - traded qty"))?;
+                    outcome.orders_touched += 1;
+
+                    if traded_quantity == resting_quantity && hidden_quantity > 0 {
+                        // iceberg peak consumed, refill from reserve and lose time priority
+                        let refill_quantity = match order_pool[idx].as_mut() {
+                            Some(first_order_node) => {
+                                let refill_quantity = hidden_quantity.min(first_order_node.peak_quantity);
+                                first_order_node.current_quantity = refill_quantity;
+                                first_order_node.hidden_quantity -= refill_quantity;
+                                first_order_node.entry_time = timestamp;
+                                refill_quantity
                             }
-                            let price_level = price_node.get_mut();
-                            while price_level.total_quantity > 0 && fill_quantity > 0 {
-                                if let Some(head_idx) = price_level.head{
-
-                                    match orderbook.ask.order_pool[head_idx].as_mut(){
-                                        Some(first_order_node) => {
-
-                                            if fill_quantity >= first_order_node.current_quantity {
-                                                fill_quantity -= first_order_node.current_quantity;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(first_order_node.current_quantity).ok_or(anyhow!("error occured in sub of total qty - current qyt"))?;
-                                                let next = first_order_node.next;
-                                                orderbook.ask.order_pool[head_idx] = None;
-                                                orderbook.ask.free_list.push(head_idx);
-                                                orders_touched += 1;
-                                                if let Some(next_order_idx) = next {
-                                                    price_level.head = Some(next_order_idx);
-                                                } else {
-                                                    span.record("reason", "partially_filled");
-                                                    price_level.total_quantity = 0;
-                                                    price_level.head = None;
-                                                    price_level.tail = None;
-                                                    price_level.order_count = 0;
-                                                    break;
-                                                }
-                                            } else {
-                                                first_order_node.current_quantity = first_order_node.current_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fnq - fq"))?;
-                                                price_level.total_quantity = price_level.total_quantity.checked_sub(fill_quantity).ok_or(anyhow!("error occured subtracting fntq - fq"))?;
-                                                fill_quantity = 0;
-                                                orders_touched += 1;
-                                                span.record("filled", true);
-                                            }
-                                        }
-                                        None => {
-                                            return Err(anyhow!("failed to get head_idx from order pool"));
-                                        }
-                                    };
-                                }else {
-                                    break;
-                                }
+                            None => {
+                                return Err(EngineError::InvariantViolation("failed to get iceberg node from order pool for refill"));
                             }
-                            remove_node = price_level.total_quantity == 0;
+                        };
+                        price_level.total_quantity += refill_quantity;
+                        order_events.push(OrderUpdate::new(passive_order_id, passive_is_buy_side, level_price, refill_quantity, OrderAction::Replace));
+                        HalfBook::move_to_tail(order_pool, price_level, idx)?;
+                    } else if traded_quantity == resting_quantity {
+                        // resting order fully filled, unlink it from the level
+                        HalfBook::unlink(order_pool, free_list, order_registry, price_level, idx);
+                        order_events.push(OrderUpdate::new(passive_order_id, passive_is_buy_side, level_price, 0, OrderAction::Delete));
+                        if next.is_none() {
+                            span.record("reason", "exhausted");
                         }
-                        if remove_node {
-                            match orderbook.ask.price_map.pop_first(){
-                                Some(_) => {
-                                    levels_consumed += 1;
-                                }
-                                None => {
-                                    break;
-                                }
-                            };
-                        }
-                    }
-                    if fill_quantity > 0{
-                        let alloted_index = orderbook.create_buy_order(
-                            order.engine_order_id,
-                            OrderNode {
-                                order_id : order.engine_order_id,
-                                initial_quantity: order.initial_quantity,
-                                current_quantity: fill_quantity,
-                                market_limit: order.price.unwrap(),
-                                next: None,
-                                prev: None,
-                            },
-                        )?;
-                        span.record("order_type", "limit");
-                        span.record("is_buy_side", true);
-                        span.record("levels_consumed", levels_consumed);
-                        span.record("orders_touched", orders_touched);
-                        return Ok(MatchOutcome{
-                        order_index : Some(alloted_index as u32),
-                        levels_consumed,
-                        orders_touched
-                    })
+                    } else if let Some(first_order_node) = order_pool[idx].as_mut() {
+                        first_order_node.current_quantity -= traded_quantity;
                     }
-                    Ok(MatchOutcome{
-                        order_index : None,
-                        levels_consumed,
-                        orders_touched
-                    })
                 }
+                remove_node = price_level.total_quantity == 0;
+            }
+            if remove_node {
+                let popped = if order.is_buy_side { price_map.pop_first() } else { price_map.pop_last() };
+                if popped.is_none() {
+                    break;
+                }
+                outcome.levels_consumed += 1;
             }
         }
+        if fill_quantity == 0 && outcome.cancelled_quantity == 0 {
+            span.record("filled", true);
+        }
+        Ok((fill_quantity, outcome))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Span;
+    use super::MatchingEngine;
+    use crate::order_book::auction::AuctionKind;
+    use crate::order_book::clock::SimulatedClock;
+    use crate::order_book::error::EngineError;
+    use crate::order_book::position::Position;
+    use crate::order_book::risk::{MarketContext, ParticipantLimits, RiskCheck, RiskLimits, RiskOrder, RiskRejection};
+    use crate::order_book::test_support::{engine, levels, limit_order, status};
+    use crate::order_book::trading_state::TradingState;
+    use crate::order_book::types::{
+        BboUpdate, EngineCancelOrder, EngineNewOrder, Instrument, LevelAction, LevelUpdate, MarketDataEvent, MassCancelFilter, MatchOutcome, MatchingAlgorithm, OrderAction, OrderState, OrderType, OrderUpdate, PostOnly,
+        PriceLevelDepth, QueuePosition, SelfTradePrevention, TimeInForce
+    };
+
+    fn market_order(order_id : u64, is_buy_side : bool, quantity : u32, market_limit : Option<u32>) -> EngineNewOrder{
+        EngineNewOrder { price : None, order_type : OrderType::Market(market_limit), ..limit_order(order_id, is_buy_side, 0, quantity) }
+    }
+
+    #[test]
+    fn a_crossing_limit_order_reports_one_fill_per_resting_order(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 102, 5), &span).unwrap();
+
+        // fills at the resting prices, the 2 left at 101 rest on the bid
+        let outcome = engine.match_order(limit_order(4, true, 101, 12), &span).unwrap();
+        let fills : Vec<(u64, u64, u64, u32, u32, bool)> = outcome.fills.iter()
+            .map(|fill| (fill.trade_id, fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity, fill.aggressor_is_buy_side))
+            .collect();
+        assert_eq!(fills, [(1, 4, 1, 100, 5, true), (2, 4, 2, 100, 5, true)]);
+        assert!(outcome.order_index.is_some());
+        let depth = engine.depth(1, None, &span).unwrap();
+        assert_eq!(depth.bid_depth.iter().map(|level| (level.price_level, level.quantity)).collect::<Vec<_>>(), [(101, 2)]);
+    }
+
+    #[test]
+    fn a_market_order_sweeps_every_level_and_drops_the_rest(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, true, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 98, 5), &span).unwrap();
+
+        let outcome = engine.match_order(market_order(3, false, 12, None), &span).unwrap();
+        let fills : Vec<(u64, u64, u64, u32, u32, bool)> = outcome.fills.iter()
+            .map(|fill| (fill.trade_id, fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity, fill.aggressor_is_buy_side))
+            .collect();
+        assert_eq!(fills, [(1, 3, 1, 100, 5, false), (2, 3, 2, 98, 5, false)]);
+        assert!(outcome.order_index.is_none());
+        let depth = engine.depth(1, None, &span).unwrap();
+        assert!(depth.bid_depth.is_empty() && depth.ask_depth.is_empty());
+    }
+
+    #[test]
+    fn a_market_limit_order_stops_at_its_limit(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 102, 5), &span).unwrap();
+
+        let outcome = engine.match_order(market_order(4, true, 12, Some(101)), &span).unwrap();
+        let fills : Vec<(u64, u64, u64, u32, u32, bool)> = outcome.fills.iter()
+            .map(|fill| (fill.trade_id, fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity, fill.aggressor_is_buy_side))
+            .collect();
+        assert_eq!(fills, [(1, 4, 1, 100, 5, true), (2, 4, 2, 101, 5, true)]);
+        assert!(outcome.order_index.is_none());
+
+        // trade ids keep counting across orders
+        let outcome = engine.match_order(market_order(5, true, 1, Some(102)), &span).unwrap();
+        assert_eq!(outcome.fills[0].trade_id, 3);
+        assert_eq!(outcome.fills[0].passive_order_id, 3);
+        let depth = engine.depth(1, None, &span).unwrap();
+        assert_eq!(depth.ask_depth.iter().map(|level| (level.price_level, level.quantity)).collect::<Vec<_>>(), [(102, 4)]);
+        assert!(depth.bid_depth.is_empty());
+    }
+
+    #[test]
+    fn an_immediate_or_cancel_order_drops_what_does_not_cross(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 102, 5), &span).unwrap();
+
+        let order = EngineNewOrder { time_in_force : TimeInForce::ImmediateOrCancel, ..limit_order(3, true, 101, 8) };
+        let outcome = engine.match_order(order, &span).unwrap();
+        let fills : Vec<(u64, u32, u32)> = outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.price, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 100, 5)]);
+        assert_eq!(outcome.cancelled_quantity, 3);
+        assert!(outcome.order_index.is_none());
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(102, 5)]);
+    }
+
+    fn fill_or_kill(order_id : u64, price : u32, quantity : u32) -> EngineNewOrder{
+        EngineNewOrder { time_in_force : TimeInForce::FillOrKill, ..limit_order(order_id, true, price, quantity) }
+    }
+
+    #[test]
+    fn a_fill_or_kill_without_enough_liquidity_leaves_the_book_alone(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 102, 5), &span).unwrap();
+
+        // only 10 are offered at 101 or better
+        let outcome = engine.match_order(fill_or_kill(4, 101, 11), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(outcome.cancelled_quantity, 11);
+        assert_eq!(levels(&engine, false), [(100, 5), (101, 5), (102, 5)]);
+
+        let outcome = engine.match_order(fill_or_kill(5, 101, 10), &span).unwrap();
+        let fills : Vec<(u64, u32, u32)> = outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.price, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 100, 5), (2, 101, 5)]);
+        assert_eq!(outcome.cancelled_quantity, 0);
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(102, 5)]);
+    }
+
+    fn post_only(order_id : u64, is_buy_side : bool, price : u32, mode : PostOnly) -> EngineNewOrder{
+        EngineNewOrder { post_only : Some(mode), ..limit_order(order_id, is_buy_side, price, 5) }
+    }
+
+    #[test]
+    fn a_post_only_reject_order_that_would_cross_is_refused(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+
+        assert!(engine.match_order(post_only(2, true, 100, PostOnly::Reject), &span).is_err());
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(100, 5)]);
+
+        // one tick behind the touch doesn't cross and rests as is
+        let outcome = engine.match_order(post_only(3, true, 99, PostOnly::Reject), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(levels(&engine, true), [(99, 5)]);
+    }
+
+    #[test]
+    fn a_post_only_slide_order_rests_one_tick_behind_the_touch(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 95, 5), &span).unwrap();
+
+        let outcome = engine.match_order(post_only(3, true, 102, PostOnly::Slide), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        let outcome = engine.match_order(post_only(4, false, 95, PostOnly::Slide), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        // the sell slides behind the bid that just slid in at 99
+        assert_eq!(levels(&engine, true), [(99, 5), (95, 5)]);
+        assert_eq!(levels(&engine, false), [(100, 10)]);
+    }
+
+    #[test]
+    fn a_modified_post_only_order_keeps_its_mode(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(post_only(2, true, 98, PostOnly::Slide), &span).unwrap();
+
+        // repriced through the ask, it slides instead of taking
+        let (_, outcome) = engine.modify(2, 1, Some(101), None, true, &span).unwrap();
+        assert!(outcome.unwrap().fills.is_empty());
+        assert_eq!(levels(&engine, true), [(99, 5)]);
+        assert_eq!(levels(&engine, false), [(100, 5)]);
+    }
+
+    fn stop(order_id : u64, is_buy_side : bool, stop_price : u32, quantity : u32) -> EngineNewOrder{
+        EngineNewOrder { price : None, order_type : OrderType::Stop(stop_price), ..limit_order(order_id, is_buy_side, 0, quantity) }
+    }
+
+    fn stop_limit(order_id : u64, is_buy_side : bool, stop_price : u32, price : u32, quantity : u32) -> EngineNewOrder{
+        EngineNewOrder { order_type : OrderType::StopLimit(stop_price), ..limit_order(order_id, is_buy_side, price, quantity) }
+    }
+
+    #[test]
+    fn a_stop_waits_for_the_last_trade_to_reach_its_stop_price(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 103, 10), &span).unwrap();
+        assert!(engine.match_order(stop(10, true, 101, 5), &span).unwrap().fills.is_empty());
+        engine.match_order(stop_limit(11, false, 99, 98, 5), &span).unwrap();
+        assert_eq!(levels(&engine, false), [(100, 5), (101, 5), (103, 10)]);
+
+        // a trade at 100 is below the buy stop and above the sell stop
+        let outcome = engine.match_order(limit_order(4, true, 100, 5), &span).unwrap();
+        assert!(outcome.released_stops.is_empty());
+
+        let outcome = engine.match_order(limit_order(5, true, 101, 2), &span).unwrap();
+        assert_eq!(outcome.released_stops, [10]);
+        let fills : Vec<(u64, u64, u32, u32)> = outcome.fills.iter().map(|fill| (fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity)).collect();
+        assert_eq!(fills, [(5, 2, 101, 2), (10, 2, 101, 3), (10, 3, 103, 2)]);
+        assert_eq!(levels(&engine, false), [(103, 8)]);
+    }
+
+    #[test]
+    fn a_stop_limit_rests_at_its_limit_once_released(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, true, 100, 5), &span).unwrap();
+        engine.match_order(stop_limit(10, false, 100, 99, 5), &span).unwrap();
+
+        let outcome = engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        assert_eq!(outcome.released_stops, [10]);
+        assert_eq!(outcome.fills.len(), 1);
+        assert!(levels(&engine, true).iter().all(|(_, quantity)| *quantity == 0));
+        assert_eq!(levels(&engine, false), [(99, 5)]);
+    }
+
+    #[test]
+    fn stops_cascade_in_a_fixed_order(){
+        let span = Span::none();
+        let run = || {
+            let mut engine = engine();
+            engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+            engine.match_order(limit_order(2, false, 102, 5), &span).unwrap();
+            engine.match_order(limit_order(3, false, 104, 5), &span).unwrap();
+            engine.match_order(stop(10, true, 100, 5), &span).unwrap();
+            engine.match_order(stop_limit(11, true, 102, 104, 5), &span).unwrap();
+            engine.match_order(stop(12, true, 102, 5), &span).unwrap();
+            engine.match_order(limit_order(4, true, 100, 5), &span).unwrap()
+        };
+        // 10 trades at 102, which releases 11 before 12 (same stop, earlier arrival); 11
+        // takes the last ask and 12 finds nothing left
+        let outcome = run();
+        assert_eq!(outcome.released_stops, [10, 11, 12]);
+        assert!(outcome.cancelled_stops.is_empty());
+        let fills : Vec<(u64, u64, u64, u32)> = outcome.fills.iter().map(|fill| (fill.trade_id, fill.aggressor_order_id, fill.passive_order_id, fill.price)).collect();
+        assert_eq!(fills, [(1, 4, 1, 100), (2, 10, 2, 102), (3, 11, 3, 104)]);
+        let again = run();
+        assert_eq!(again.released_stops, outcome.released_stops);
+        assert_eq!(again.fills.len(), outcome.fills.len());
+    }
+
+    #[test]
+    fn a_released_stop_that_cannot_execute_is_reported_as_cancelled(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        let order = EngineNewOrder { post_only : Some(PostOnly::Reject), ..stop_limit(10, true, 101, 102, 5) };
+        engine.match_order(order, &span).unwrap();
+
+        // released as a post-only bid at 102 with 101 still offered
+        let outcome = engine.match_order(limit_order(3, true, 101, 6), &span).unwrap();
+        assert_eq!(outcome.released_stops, [10]);
+        assert_eq!(outcome.cancelled_stops, [10]);
+        assert_eq!(levels(&engine, false), [(101, 4)]);
+    }
+
+    #[test]
+    fn a_cancelled_stop_is_never_released(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 10), &span).unwrap();
+        engine.match_order(stop(10, true, 100, 5), &span).unwrap();
+        engine.cancel(10, 1, &span, true).unwrap();
+
+        let outcome = engine.match_order(limit_order(2, true, 100, 5), &span).unwrap();
+        assert!(outcome.released_stops.is_empty());
+        assert_eq!(levels(&engine, false), [(100, 5)]);
+    }
+
+    fn iceberg(order_id : u64, is_buy_side : bool, price : u32, quantity : u32, peak_quantity : u32) -> EngineNewOrder{
+        EngineNewOrder { order_type : OrderType::Iceberg(peak_quantity), ..limit_order(order_id, is_buy_side, price, quantity) }
+    }
+
+    #[test]
+    fn only_the_peak_of_an_iceberg_shows_in_depth(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(iceberg(1, false, 100, 10, 4), &span).unwrap();
+        engine.match_order(iceberg(2, true, 98, 3, 5), &span).unwrap();
+        assert_eq!(levels(&engine, false), [(100, 4)]);
+        // a peak larger than the order shows the whole order
+        assert_eq!(levels(&engine, true), [(98, 3)]);
+    }
+
+    #[test]
+    fn a_refilled_iceberg_goes_to_the_back_of_its_level(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(iceberg(1, false, 100, 10, 4), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+
+        let outcome = engine.match_order(limit_order(3, true, 100, 4), &span).unwrap();
+        let fills : Vec<(u64, u32)> = outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 4)]);
+        assert_eq!(levels(&engine, false), [(100, 9)]);
+
+        // the refilled peak queues behind order 2
+        let outcome = engine.match_order(limit_order(4, true, 100, 6), &span).unwrap();
+        let fills : Vec<(u64, u32)> = outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.quantity)).collect();
+        assert_eq!(fills, [(2, 5), (1, 1)]);
+        assert_eq!(levels(&engine, false), [(100, 3)]);
+    }
+
+    #[test]
+    fn the_hidden_reserve_counts_towards_a_fill_or_kill(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(iceberg(1, false, 100, 10, 4), &span).unwrap();
+
+        let outcome = engine.match_order(fill_or_kill(2, 100, 11), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(levels(&engine, false), [(100, 4)]);
+
+        let outcome = engine.match_order(fill_or_kill(3, 100, 10), &span).unwrap();
+        let fills : Vec<(u64, u32)> = outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 4), (1, 4), (1, 2)]);
+        assert_eq!(outcome.cancelled_quantity, 0);
+        assert!(levels(&engine, false).is_empty());
+    }
+
+    fn owned_by(owner_id : u64, order : EngineNewOrder) -> EngineNewOrder{
+        EngineNewOrder { owner_id, ..order }
+    }
+
+    // owner 7 rests 5 at 100 ahead of owner 8's 5, then sends a buy of `quantity` at 100
+    fn self_trade(mode : Option<SelfTradePrevention>, quantity : u32) -> (MatchingEngine, MatchOutcome){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.set_self_trade_prevention(mode).unwrap();
+        engine.match_order(owned_by(7, limit_order(1, false, 100, 5)), &span).unwrap();
+        engine.match_order(owned_by(8, limit_order(2, false, 100, 5)), &span).unwrap();
+        let outcome = engine.match_order(owned_by(7, limit_order(3, true, 100, quantity)), &span).unwrap();
+        (engine, outcome)
+    }
+
+    fn passive_fills(outcome : &MatchOutcome) -> Vec<(u64, u32)>{
+        outcome.fills.iter().map(|fill| (fill.passive_order_id, fill.quantity)).collect()
+    }
+
+    #[test]
+    fn without_self_trade_prevention_an_owner_trades_with_itself(){
+        let (_, outcome) = self_trade(None, 8);
+        assert_eq!(passive_fills(&outcome), [(1, 5), (2, 3)]);
+    }
+
+    #[test]
+    fn cancel_newest_drops_the_incoming_order(){
+        let (engine, outcome) = self_trade(Some(SelfTradePrevention::CancelNewest), 8);
+        assert!(outcome.fills.is_empty());
+        assert!(outcome.self_trade_cancels.is_empty());
+        assert_eq!(outcome.cancelled_quantity, 8);
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(100, 10)]);
+    }
+
+    #[test]
+    fn cancel_oldest_drops_the_resting_order_and_keeps_matching(){
+        let (engine, outcome) = self_trade(Some(SelfTradePrevention::CancelOldest), 8);
+        assert_eq!(outcome.self_trade_cancels, [1]);
+        assert_eq!(passive_fills(&outcome), [(2, 5)]);
+        assert_eq!(outcome.cancelled_quantity, 0);
+        assert_eq!(levels(&engine, true), [(100, 3)]);
+        assert!(levels(&engine, false).is_empty());
+    }
+
+    #[test]
+    fn cancel_both_drops_the_resting_and_the_incoming_order(){
+        let (engine, outcome) = self_trade(Some(SelfTradePrevention::CancelBoth), 8);
+        assert_eq!(outcome.self_trade_cancels, [1]);
+        assert!(outcome.fills.is_empty());
+        assert_eq!(outcome.cancelled_quantity, 8);
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(100, 5)]);
+    }
+
+    #[test]
+    fn decrement_and_cancel_takes_the_smaller_quantity_off_both(){
+        // the resting 5 is the smaller one: it goes, the incoming 8 goes on with 3
+        let (engine, outcome) = self_trade(Some(SelfTradePrevention::DecrementAndCancel), 8);
+        assert_eq!(outcome.self_trade_cancels, [1]);
+        assert_eq!(passive_fills(&outcome), [(2, 3)]);
+        assert_eq!(outcome.cancelled_quantity, 5);
+        assert_eq!(levels(&engine, false), [(100, 2)]);
+
+        // the incoming 3 is the smaller one: it goes, the resting order keeps 2
+        let (engine, outcome) = self_trade(Some(SelfTradePrevention::DecrementAndCancel), 3);
+        assert!(outcome.self_trade_cancels.is_empty());
+        assert!(outcome.fills.is_empty());
+        assert_eq!(outcome.cancelled_quantity, 3);
+        assert!(levels(&engine, true).is_empty());
+        assert_eq!(levels(&engine, false), [(100, 7)]);
+    }
+
+    #[test]
+    fn a_fill_or_kill_does_not_count_its_owners_orders_under_self_trade_prevention(){
+        let span = Span::none();
+        for mode in [SelfTradePrevention::CancelNewest, SelfTradePrevention::CancelOldest, SelfTradePrevention::CancelBoth, SelfTradePrevention::DecrementAndCancel] {
+            let mut engine = engine();
+            engine.set_self_trade_prevention(Some(mode)).unwrap();
+            engine.match_order(owned_by(4, limit_order(1, false, 100, 5)), &span).unwrap();
+            engine.match_order(owned_by(2, limit_order(2, false, 101, 5)), &span).unwrap();
+
+            // owner 4 can't trade with its own 5, that leaves 5 for a 10 lot
+            let outcome = engine.match_order(owned_by(4, fill_or_kill(3, 101, 10)), &span).unwrap();
+            assert!(outcome.fills.is_empty(), "{:?}", mode);
+            assert!(outcome.self_trade_cancels.is_empty(), "{:?}", mode);
+            assert_eq!(outcome.cancelled_quantity, 10);
+            assert_eq!(levels(&engine, false).len(), 2);
+        }
+
+        // cancel oldest removes the own order and goes on to the foreign one
+        let mut engine = engine();
+        engine.set_self_trade_prevention(Some(SelfTradePrevention::CancelOldest)).unwrap();
+        engine.match_order(owned_by(4, limit_order(1, false, 100, 5)), &span).unwrap();
+        engine.match_order(owned_by(2, limit_order(2, false, 101, 5)), &span).unwrap();
+        let outcome = engine.match_order(owned_by(4, fill_or_kill(3, 101, 5)), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(2, 5)]);
+        assert_eq!(outcome.self_trade_cancels, [1]);
+    }
+
+    #[test]
+    fn rejections_carry_a_typed_error(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+
+        assert_eq!(engine.match_order(post_only(2, true, 101, PostOnly::Reject), &span).unwrap_err(), EngineError::PostOnlyWouldCross(100));
+        assert_eq!(engine.match_order(iceberg(3, true, 99, 10, 0), &span).unwrap_err(), EngineError::InvalidQuantity("iceberg peak quantity has to be greater than zero"));
+        assert_eq!(engine.cancel(4, 1, &span, true).unwrap_err(), EngineError::UnknownOrder(4));
+        assert_eq!(engine.cancel(1, 2, &span, false).unwrap_err(), EngineError::UnknownSecurity(2));
+        assert_eq!(engine.cancel(1, 1, &span, false), Ok(()));
+        assert_eq!(engine.cancel(1, 1, &span, false).unwrap_err(), EngineError::UnknownOrder(1));
+    }
+
+    #[test]
+    fn securities_have_to_be_registered_once_before_trading(){
+        let span = Span::none();
+        let mut engine = engine();
+        let order = EngineNewOrder { security_id : 2, ..limit_order(1, true, 100, 5) };
+        assert_eq!(engine.match_order(order, &span).unwrap_err(), EngineError::UnknownSecurity(2));
+
+        engine.register_security(Instrument::new(2, "OTHER")).unwrap();
+        let duplicate = Instrument::new(1, "AGAIN");
+        assert_eq!(engine.register_security(duplicate).unwrap_err(), EngineError::DuplicateSecurity(1));
+        let symbols : Vec<(u32, &str)> = engine.securities().iter().map(|instrument| (instrument.security_id, instrument.symbol.as_str())).collect();
+        assert_eq!(symbols, [(1, "TEST"), (2, "OTHER")]);
+
+        let order = EngineNewOrder { security_id : 2, ..limit_order(1, true, 100, 5) };
+        assert!(engine.match_order(order, &span).is_ok());
+    }
+
+    #[test]
+    fn delisting_cancels_every_order_of_the_security(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(3, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(stop(2, true, 105, 5), &span).unwrap();
+
+        assert_eq!(engine.delist_security(1).unwrap(), [1, 2, 3]);
+        assert!(engine.instrument(1).is_none());
+        assert!(engine.securities().is_empty());
+        assert_eq!(engine.match_order(limit_order(4, true, 99, 5), &span).unwrap_err(), EngineError::UnknownSecurity(1));
+        assert_eq!(engine.delist_security(1).unwrap_err(), EngineError::UnknownSecurity(1));
+    }
+
+    #[test]
+    fn a_halted_security_only_accepts_cancels(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 102, 5), &span).unwrap();
+        engine.halt(1).unwrap();
+
+        assert_eq!(engine.match_order(limit_order(3, true, 101, 5), &span).unwrap_err(), EngineError::SecurityHalted(1));
+        assert_eq!(engine.modify(1, 1, Some(100), None, false, &span).unwrap_err(), EngineError::SecurityHalted(1));
+        engine.cancel(2, 1, &span, false).unwrap();
+        assert_eq!(levels(&engine, false), [(101, 5)]);
+
+        engine.resume(1).unwrap();
+        let outcome = engine.match_order(limit_order(3, true, 101, 5), &span).unwrap();
+        assert_eq!(outcome.fills.len(), 1);
+        assert_eq!(engine.halt(2).unwrap_err(), EngineError::UnknownSecurity(2));
+    }
+
+    // security 1 with a tick of 5, a lot of 10 and both bands closed
+    fn banded_engine() -> MatchingEngine{
+        let mut engine = MatchingEngine::new();
+        engine.register_security(Instrument {
+            tick_size : 5,
+            lot_size : 10,
+            min_quantity : 20,
+            max_quantity : 1000,
+            min_price : 50,
+            max_price : 200,
+            ..Instrument::new(1, "TEST")
+        }).unwrap();
+        engine
+    }
+
+    #[test]
+    fn prices_off_the_tick_or_outside_the_band_are_rejected(){
+        let span = Span::none();
+        let mut engine = banded_engine();
+        assert_eq!(engine.match_order(limit_order(1, true, 102, 20), &span).unwrap_err(), EngineError::PriceNotOnTick { price : 102, tick_size : 5 });
+        assert_eq!(engine.match_order(limit_order(2, true, 45, 20), &span).unwrap_err(), EngineError::PriceOutOfRange { price : 45, min_price : 50, max_price : 200 });
+        assert_eq!(engine.match_order(limit_order(3, true, 205, 20), &span).unwrap_err(), EngineError::PriceOutOfRange { price : 205, min_price : 50, max_price : 200 });
+        // market limits and stop prices are prices too
+        assert_eq!(engine.match_order(market_order(4, true, 20, Some(101)), &span).unwrap_err(), EngineError::PriceNotOnTick { price : 101, tick_size : 5 });
+        assert_eq!(engine.match_order(stop(5, true, 210, 20), &span).unwrap_err(), EngineError::PriceOutOfRange { price : 210, min_price : 50, max_price : 200 });
+        assert!(engine.match_order(limit_order(6, true, 100, 20), &span).is_ok());
+        assert_eq!(levels(&engine, true), [(100, 20)]);
+    }
+
+    #[test]
+    fn quantities_off_the_lot_or_outside_the_band_are_rejected(){
+        let span = Span::none();
+        let mut engine = banded_engine();
+        assert_eq!(engine.match_order(limit_order(1, true, 100, 25), &span).unwrap_err(), EngineError::QuantityNotOnLot { quantity : 25, lot_size : 10 });
+        assert_eq!(engine.match_order(limit_order(2, true, 100, 10), &span).unwrap_err(), EngineError::QuantityOutOfRange { quantity : 10, min_quantity : 20, max_quantity : 1000 });
+        assert_eq!(engine.match_order(limit_order(3, true, 100, 1010), &span).unwrap_err(), EngineError::QuantityOutOfRange { quantity : 1010, min_quantity : 20, max_quantity : 1000 });
+        assert_eq!(engine.match_order(iceberg(4, true, 100, 100, 15), &span).unwrap_err(), EngineError::QuantityNotOnLot { quantity : 15, lot_size : 10 });
+        assert!(levels(&engine, true).is_empty());
+    }
+
+    #[test]
+    fn reference_data_is_checked_at_registration(){
+        let mut engine = MatchingEngine::new();
+        let instrument = Instrument { tick_size : 0, ..Instrument::new(1, "TEST") };
+        assert_eq!(engine.register_security(instrument).unwrap_err(), EngineError::InvalidReferenceData("tick size has to be greater than zero"));
+        let instrument = Instrument { min_quantity : 10, max_quantity : 5, ..Instrument::new(1, "TEST") };
+        assert!(matches!(engine.register_security(instrument), Err(EngineError::InvalidReferenceData(_))));
+        assert!(engine.securities().is_empty());
+    }
+
+    #[test]
+    fn a_modify_checks_what_it_changes_but_not_the_remainder(){
+        let span = Span::none();
+        let mut engine = banded_engine();
+        engine.match_order(limit_order(1, false, 100, 30), &span).unwrap();
+        assert_eq!(engine.modify(1, 1, Some(103), None, false, &span).unwrap_err(), EngineError::PriceNotOnTick { price : 103, tick_size : 5 });
+        assert_eq!(engine.modify(1, 1, None, Some(35), false, &span).unwrap_err(), EngineError::QuantityNotOnLot { quantity : 35, lot_size : 10 });
+        assert_eq!(levels(&engine, false), [(100, 30)]);
+
+        // a partial fill leaves 10, under the minimum, and it still re-enters at the new price
+        engine.match_order(limit_order(2, true, 100, 20), &span).unwrap();
+        let (_, outcome) = engine.modify(1, 1, Some(105), None, false, &span).unwrap();
+        assert!(outcome.unwrap().fills.is_empty());
+        assert_eq!(levels(&engine, false), [(105, 10)]);
+    }
+
+    #[test]
+    fn a_post_only_order_cannot_slide_out_of_the_band(){
+        let span = Span::none();
+        let mut engine = banded_engine();
+        engine.match_order(limit_order(1, false, 50, 20), &span).unwrap();
+
+        // one tick behind 50 is 45, under the minimum price
+        let order = EngineNewOrder { post_only : Some(PostOnly::Slide), ..limit_order(2, true, 55, 20) };
+        assert_eq!(engine.match_order(order, &span).unwrap_err(), EngineError::PriceOutOfRange { price : 45, min_price : 50, max_price : 200 });
+        assert!(levels(&engine, true).is_empty());
+
+        // the slide moves by the instrument's tick
+        let mut engine = banded_engine();
+        engine.match_order(limit_order(4, false, 100, 20), &span).unwrap();
+        let order = EngineNewOrder { post_only : Some(PostOnly::Slide), ..limit_order(5, true, 110, 20) };
+        engine.match_order(order, &span).unwrap();
+        assert_eq!(levels(&engine, true), [(95, 20)]);
+    }
+
+    #[test]
+    fn commands_and_events_share_one_sequence(){
+        let span = Span::none();
+        // registering security 1 was the first command
+        let mut engine = engine();
+        assert_eq!(engine.last_sequence(), 1);
+
+        // the command, then its rested event
+        let outcome = engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        assert_eq!(outcome.sequence, 2);
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        assert_eq!(engine.last_sequence(), 5);
+
+        // fills are numbered right after their command, then the rest of the bid rests
+        let outcome = engine.match_order(limit_order(3, true, 101, 12), &span).unwrap();
+        assert_eq!(outcome.sequence, 6);
+        assert_eq!(outcome.fills.iter().map(|fill| fill.sequence).collect::<Vec<_>>(), [7, 8]);
+        assert_eq!(engine.last_sequence(), 9);
+
+        // a rejected command still takes a number, and so does its rejection
+        engine.cancel(9, 1, &span, true).unwrap_err();
+        assert_eq!(engine.last_sequence(), 11);
+    }
+
+    #[test]
+    fn engine_time_comes_from_the_clock_and_never_goes_backwards(){
+        let span = Span::none();
+        let clock = SimulatedClock::new(1_000);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(Box::new(clock.clone()));
+        engine.register_security(Instrument::new(1, "TEST")).unwrap();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+
+        // every event of a command carries the command's time
+        clock.advance(500);
+        let outcome = engine.match_order(limit_order(3, true, 100, 10), &span).unwrap();
+        assert_eq!(outcome.timestamp, 1_500);
+        assert!(outcome.fills.iter().all(|fill| fill.timestamp == 1_500));
+
+        // a clock stepping back stalls engine time instead
+        clock.set(900);
+        let outcome = engine.match_order(limit_order(4, false, 100, 5), &span).unwrap();
+        assert_eq!(outcome.timestamp, 1_500);
+        clock.set(2_000);
+        let outcome = engine.match_order(limit_order(5, true, 100, 5), &span).unwrap();
+        assert_eq!((outcome.timestamp, outcome.fills[0].timestamp), (2_000, 2_000));
+    }
+
+    fn level_updates(engine : &mut MatchingEngine) -> Vec<LevelUpdate>{
+        engine.drain_market_data().into_iter().filter_map(|event| match event {
+            MarketDataEvent::Level(update) => Some(update),
+            _ => None
+        }).collect()
+    }
+
+    // (side, price, action, quantity, order count)
+    fn level_changes(engine : &mut MatchingEngine) -> Vec<(bool, u32, LevelAction, u32, u32)>{
+        level_updates(engine).into_iter()
+            .map(|update| (update.is_buy_side, update.price, update.action, update.quantity, update.order_count))
+            .collect()
+    }
+
+    #[test]
+    fn each_command_publishes_the_levels_it_changed(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(3, true, 99, 5), &span).unwrap();
+        assert_eq!(level_changes(&mut engine), [
+            (false, 101, LevelAction::New, 5, 1),
+            (false, 101, LevelAction::Change, 10, 2),
+            (true, 99, LevelAction::New, 5, 1)
+        ]);
+
+        // sweeps one level away, the rest opens a bid level. bids come first
+        engine.match_order(limit_order(4, true, 101, 12), &span).unwrap();
+        assert_eq!(level_changes(&mut engine), [(true, 101, LevelAction::New, 2, 1), (false, 101, LevelAction::Delete, 0, 0)]);
+
+        engine.cancel(3, 1, &span, true).unwrap();
+        engine.modify(4, 1, Some(100), None, true, &span).unwrap();
+        assert_eq!(level_changes(&mut engine), [
+            (true, 99, LevelAction::Delete, 0, 0),
+            (true, 100, LevelAction::New, 2, 1),
+            (true, 101, LevelAction::Delete, 0, 0)
+        ]);
+
+        // a rejected command changes nothing
+        engine.cancel(9, 1, &span, true).unwrap_err();
+        assert!(level_changes(&mut engine).is_empty());
+    }
+
+    #[test]
+    fn a_level_changed_and_restored_within_a_command_is_not_published(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(iceberg(1, false, 100, 20, 5), &span).unwrap();
+        engine.drain_market_data();
+
+        // the peak is taken and refilled from the reserve, the level looks the same as before
+        engine.match_order(limit_order(2, true, 100, 5), &span).unwrap();
+        assert!(level_changes(&mut engine).is_empty());
+
+        // only the visible peak is published
+        engine.match_order(limit_order(3, true, 100, 12), &span).unwrap();
+        assert_eq!(level_changes(&mut engine), [(false, 100, LevelAction::Change, 3, 1)]);
+    }
+
+    #[test]
+    fn level_updates_are_numbered_after_the_depth_they_follow(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 5), &span).unwrap();
+        engine.drain_market_data();
+
+        // a consumer seeds from the depth and applies everything numbered after it
+        let seeded_at = engine.market_data_sequence();
+        let mut asks = levels(&engine, false);
+        engine.match_order(limit_order(3, false, 102, 5), &span).unwrap();
+        engine.match_order(limit_order(4, true, 102, 12), &span).unwrap();
+        let updates = level_updates(&mut engine);
+        // numbered after the seed, order and bbo updates take the numbers in between
+        assert!(updates.windows(2).all(|pair| pair[0].sequence < pair[1].sequence));
+        assert!(updates[0].sequence > seeded_at);
+        assert!(updates.last().unwrap().sequence < engine.market_data_sequence());
+        for update in updates.iter().filter(|update| !update.is_buy_side) {
+            asks.retain(|(price, _)| *price != update.price);
+            if update.action != LevelAction::Delete {
+                asks.push((update.price, update.quantity));
+            }
+        }
+        assert_eq!(asks, levels(&engine, false));
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn delisting_deletes_every_level(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(3, true, 98, 5), &span).unwrap();
+        engine.drain_market_data();
+
+        engine.delist_security(1).unwrap();
+        assert_eq!(level_changes(&mut engine), [
+            (true, 98, LevelAction::Delete, 0, 0),
+            (true, 99, LevelAction::Delete, 0, 0),
+            (false, 101, LevelAction::Delete, 0, 0)
+        ]);
+    }
+
+    fn order_updates(engine : &mut MatchingEngine) -> Vec<OrderUpdate>{
+        engine.drain_market_data().into_iter().filter_map(|event| match event {
+            MarketDataEvent::Order(update) => Some(update),
+            _ => None
+        }).collect()
+    }
+
+    // (order id, side, price, quantity, action)
+    fn order_changes(engine : &mut MatchingEngine) -> Vec<(u64, bool, u32, u32, OrderAction)>{
+        order_updates(engine).into_iter()
+            .map(|update| (update.order_id, update.is_buy_side, update.price, update.quantity, update.action))
+            .collect()
+    }
+
+    #[test]
+    fn resting_orders_are_added_executed_and_deleted(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 101, 5), &span).unwrap();
+        assert_eq!(order_changes(&mut engine), [(1, false, 101, 5, OrderAction::Add), (2, false, 101, 5, OrderAction::Add)]);
+
+        // the aggressor never rested, only the passive side shows up
+        engine.match_order(limit_order(3, true, 101, 7), &span).unwrap();
+        assert_eq!(order_changes(&mut engine), [
+            (1, false, 101, 5, OrderAction::Executed { trade_id : 1 }),
+            (1, false, 101, 0, OrderAction::Delete),
+            (2, false, 101, 2, OrderAction::Executed { trade_id : 2 })
+        ]);
+
+        engine.cancel(2, 1, &span, false).unwrap();
+        assert_eq!(order_changes(&mut engine), [(2, false, 101, 3, OrderAction::Delete)]);
+    }
+
+    #[test]
+    fn order_updates_come_before_the_level_updates_of_the_same_command(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        let events = engine.drain_market_data();
+        assert!(matches!(events[..], [MarketDataEvent::Order(_), MarketDataEvent::Level(_), MarketDataEvent::Bbo(_)]));
+        // all feeds share one sequence
+        let sequences : Vec<u64> = events.iter().map(|event| match event {
+            MarketDataEvent::Order(update) => update.sequence,
+            MarketDataEvent::Level(update) => update.sequence,
+            MarketDataEvent::Bbo(update) => update.sequence,
+            MarketDataEvent::QueuePosition(update) => update.sequence,
+            MarketDataEvent::TradingState(update) => update.sequence
+        }).collect();
+        assert_eq!(sequences, [1, 2, 3]);
+    }
+
+    #[test]
+    fn a_modify_that_does_not_trade_is_one_replace(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 102, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 8), &span).unwrap();
+        engine.drain_market_data();
+
+        // the delete and the add back merge into a replace
+        engine.modify(2, 1, Some(100), None, true, &span).unwrap();
+        assert_eq!(order_changes(&mut engine), [(2, true, 100, 8, OrderAction::Replace)]);
+
+        // trading in between keeps them apart
+        engine.modify(2, 1, Some(102), None, true, &span).unwrap();
+        assert_eq!(order_changes(&mut engine), [
+            (2, true, 100, 8, OrderAction::Delete),
+            (1, false, 102, 5, OrderAction::Executed { trade_id : 1 }),
+            (1, false, 102, 0, OrderAction::Delete),
+            (2, true, 102, 3, OrderAction::Add)
+        ]);
+    }
+
+    #[test]
+    fn an_iceberg_refill_is_a_replace_and_a_self_trade_decrement_a_cancel(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(iceberg(1, false, 100, 12, 5), &span).unwrap();
+        engine.drain_market_data();
+        engine.match_order(limit_order(2, true, 100, 5), &span).unwrap();
+        assert_eq!(order_changes(&mut engine), [
+            (1, false, 100, 5, OrderAction::Executed { trade_id : 1 }),
+            (1, false, 100, 5, OrderAction::Replace)
+        ]);
+
+        let (mut engine, _) = self_trade(Some(SelfTradePrevention::DecrementAndCancel), 3);
+        let changes = order_changes(&mut engine);
+        assert_eq!(changes.last(), Some(&(1, false, 100, 3, OrderAction::Cancel)));
+    }
+
+    #[test]
+    fn delisting_deletes_every_order(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(3, true, 99, 4), &span).unwrap();
+        engine.drain_market_data();
+
+        engine.delist_security(1).unwrap();
+        assert_eq!(order_changes(&mut engine), [
+            (2, true, 99, 5, OrderAction::Delete),
+            (3, true, 99, 4, OrderAction::Delete),
+            (1, false, 101, 5, OrderAction::Delete)
+        ]);
+    }
+
+    fn bbo_updates(engine : &mut MatchingEngine) -> Vec<BboUpdate>{
+        engine.drain_market_data().into_iter().filter_map(|event| match event {
+            MarketDataEvent::Bbo(update) => Some(update),
+            _ => None
+        }).collect()
+    }
+
+    fn top(price_level : u32, quantity : u32) -> Option<PriceLevelDepth>{
+        Some(PriceLevelDepth { price_level, quantity })
+    }
+
+    #[test]
+    fn best_prices_spread_and_mid(){
+        let span = Span::none();
+        let mut engine = engine();
+        assert_eq!((engine.best_bid(1).unwrap(), engine.best_ask(1).unwrap()), (None, None));
+        engine.match_order(limit_order(1, false, 102, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 103, 5), &span).unwrap();
+        // one side alone has no spread or mid
+        assert_eq!((engine.spread(1).unwrap(), engine.mid(1).unwrap()), (None, None));
+
+        engine.match_order(limit_order(3, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(4, true, 99, 3), &span).unwrap();
+        assert_eq!((engine.best_bid(1).unwrap(), engine.best_ask(1).unwrap()), (top(99, 8), top(102, 5)));
+        assert_eq!((engine.spread(1).unwrap(), engine.mid(1).unwrap()), (Some(3), Some(100.5)));
+        assert_eq!(engine.best_bid(2).unwrap_err(), EngineError::UnknownSecurity(2));
+    }
+
+    #[test]
+    fn the_bbo_is_published_only_when_the_top_changes(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 102, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 5), &span).unwrap();
+        let published : Vec<_> = bbo_updates(&mut engine).into_iter().map(|update| (update.best_bid, update.best_ask)).collect();
+        assert_eq!(published, [(None, top(102, 5)), (top(99, 5), top(102, 5))]);
+
+        // behind the best, nothing to publish
+        engine.match_order(limit_order(3, false, 103, 5), &span).unwrap();
+        assert!(bbo_updates(&mut engine).is_empty());
+
+        // more quantity at the best is a change
+        engine.match_order(limit_order(4, true, 99, 3), &span).unwrap();
+        let published : Vec<_> = bbo_updates(&mut engine).into_iter().map(|update| (update.best_bid, update.best_ask)).collect();
+        assert_eq!(published, [(top(99, 8), top(102, 5))]);
+
+        engine.match_order(limit_order(5, true, 102, 5), &span).unwrap();
+        let published : Vec<_> = bbo_updates(&mut engine).into_iter().map(|update| (update.best_bid, update.best_ask)).collect();
+        assert_eq!(published, [(top(99, 8), top(103, 5))]);
+    }
+
+    #[test]
+    fn depth_lists_both_sides_from_the_best_price_out(){
+        let span = Span::none();
+        let mut engine = engine();
+        for (order_id, price) in [(1, 103), (2, 101), (3, 102)] {
+            engine.match_order(limit_order(order_id, false, price, 5), &span).unwrap();
+        }
+        for (order_id, price) in [(4, 98), (5, 100), (6, 99)] {
+            engine.match_order(limit_order(order_id, true, price, 5), &span).unwrap();
+        }
+        assert_eq!(levels(&engine, false), [(101, 5), (102, 5), (103, 5)]);
+        assert_eq!(levels(&engine, true), [(100, 5), (99, 5), (98, 5)]);
+
+        // a level count keeps the levels closest to the touch
+        let depth = engine.depth(1, Some(2), &span).unwrap();
+        let prices = |side : &[PriceLevelDepth]| side.iter().map(|level| level.price_level).collect::<Vec<_>>();
+        assert_eq!((prices(&depth.bid_depth), prices(&depth.ask_depth)), (vec![100, 99], vec![101, 102]));
+    }
+
+    #[test]
+    fn order_status_follows_an_order_through_its_life(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 10), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        assert_eq!(status(&engine, 2), Some((OrderState::Live, Some(100), 0, 5, Some(2))));
+
+        engine.match_order(limit_order(3, true, 100, 4), &span).unwrap();
+        assert_eq!(status(&engine, 1), Some((OrderState::Live, Some(100), 4, 6, Some(1))));
+        assert_eq!(status(&engine, 3), Some((OrderState::Filled, Some(100), 4, 0, None)));
+
+        // a reprice keeps what was filled and goes to the back of the new level
+        engine.match_order(limit_order(4, false, 101, 5), &span).unwrap();
+        engine.modify(1, 1, Some(101), None, false, &span).unwrap();
+        assert_eq!(status(&engine, 1), Some((OrderState::Live, Some(101), 4, 6, Some(2))));
+
+        engine.match_order(limit_order(5, true, 101, 11), &span).unwrap();
+        assert_eq!(status(&engine, 2), Some((OrderState::Filled, Some(100), 5, 0, None)));
+        assert_eq!(status(&engine, 4), Some((OrderState::Filled, Some(101), 5, 0, None)));
+        assert_eq!(status(&engine, 1), Some((OrderState::Live, Some(101), 5, 5, Some(1))));
+
+        engine.cancel(1, 1, &span, false).unwrap();
+        assert_eq!(status(&engine, 1), Some((OrderState::Cancelled, Some(101), 5, 0, None)));
+        assert_eq!(engine.order_status(1, 9).unwrap_err(), EngineError::UnknownOrder(9));
+    }
+
+    #[test]
+    fn unfilled_remainders_stops_and_icebergs_have_a_status(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        let order = EngineNewOrder { time_in_force : TimeInForce::ImmediateOrCancel, ..limit_order(2, true, 100, 8) };
+        engine.match_order(order, &span).unwrap();
+        assert_eq!(status(&engine, 2), Some((OrderState::Cancelled, Some(100), 5, 0, None)));
+
+        // waiting in the trigger book, with no price yet
+        engine.match_order(stop(3, true, 105, 5), &span).unwrap();
+        assert_eq!(status(&engine, 3), Some((OrderState::Live, None, 0, 5, None)));
+
+        // the reserve counts as still open
+        engine.match_order(iceberg(4, false, 102, 12, 5), &span).unwrap();
+        assert_eq!(status(&engine, 4), Some((OrderState::Live, Some(102), 0, 12, Some(1))));
+    }
+
+    #[test]
+    fn a_modified_order_that_fails_to_re_enter_is_cancelled(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(post_only(2, true, 98, PostOnly::Reject), &span).unwrap();
+        engine.modify(2, 1, Some(100), None, true, &span).unwrap_err();
+        assert_eq!(status(&engine, 2), Some((OrderState::Cancelled, Some(98), 0, 0, None)));
+    }
+
+    #[test]
+    fn finished_orders_are_kept_for_the_retention_window_only(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.set_order_status_retention(2);
+        for order_id in 1..=3 {
+            engine.match_order(limit_order(order_id, false, 100, 5), &span).unwrap();
+        }
+        for order_id in 1..=3 {
+            engine.cancel(order_id, 1, &span, false).unwrap();
+        }
+        // the first to finish goes first
+        assert_eq!(engine.order_status(1, 1).unwrap_err(), EngineError::UnknownOrder(1));
+        assert_eq!(status(&engine, 2).map(|status| status.0), Some(OrderState::Cancelled));
+
+        // live orders are never evicted, a smaller window evicts right away
+        engine.match_order(limit_order(4, false, 100, 5), &span).unwrap();
+        engine.set_order_status_retention(0);
+        assert_eq!((status(&engine, 2), status(&engine, 3)), (None, None));
+        assert_eq!(status(&engine, 4).map(|status| status.0), Some(OrderState::Live));
+    }
+
+    fn queue_updates(engine : &mut MatchingEngine) -> Vec<(u64, QueuePosition)>{
+        engine.drain_market_data().into_iter().filter_map(|event| match event {
+            MarketDataEvent::QueuePosition(update) => Some((update.order_id, update.position)),
+            _ => None
+        }).collect()
+    }
+
+    fn ahead(orders_ahead : u32, quantity_ahead : u32) -> QueuePosition{
+        QueuePosition { orders_ahead, quantity_ahead }
+    }
+
+    #[test]
+    fn queue_position_counts_the_visible_orders_ahead(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, true, 100, 5), &span).unwrap();
+        // only the peak of an iceberg is ahead
+        engine.match_order(iceberg(2, true, 100, 20, 4), &span).unwrap();
+        engine.match_order(limit_order(3, true, 100, 7), &span).unwrap();
+        engine.match_order(limit_order(4, true, 99, 7), &span).unwrap();
+        assert_eq!(engine.queue_position(1, 1, true).unwrap(), ahead(0, 0));
+        assert_eq!(engine.queue_position(1, 3, true).unwrap(), ahead(2, 9));
+        assert_eq!(engine.queue_position(1, 4, true).unwrap(), ahead(0, 0));
+        assert_eq!(status(&engine, 3).unwrap().4, Some(3));
+
+        assert_eq!(engine.queue_position(1, 3, false).unwrap_err(), EngineError::UnknownOrder(3));
+        assert_eq!(engine.queue_position(2, 3, true).unwrap_err(), EngineError::UnknownSecurity(2));
+    }
+
+    #[test]
+    fn a_watched_order_is_told_when_its_queue_moves(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 5), &span).unwrap();
+        assert_eq!(engine.watch_queue_position(1, 3, false).unwrap(), ahead(2, 10));
+        engine.drain_market_data();
+
+        // a trade with the order in front
+        engine.match_order(limit_order(4, true, 100, 3), &span).unwrap();
+        assert_eq!(queue_updates(&mut engine), [(3, ahead(2, 7))]);
+        engine.cancel(1, 1, &span, false).unwrap();
+        assert_eq!(queue_updates(&mut engine), [(3, ahead(1, 5))]);
+
+        // joining behind it, or at another level, doesn't move it
+        engine.match_order(limit_order(5, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(6, false, 101, 5), &span).unwrap();
+        assert!(queue_updates(&mut engine).is_empty());
+
+        engine.unwatch_queue_position(1, 3).unwrap();
+        engine.cancel(2, 1, &span, false).unwrap();
+        assert!(queue_updates(&mut engine).is_empty());
+        assert_eq!(engine.unwatch_queue_position(1, 3).unwrap_err(), EngineError::UnknownOrder(3));
+    }
+
+    #[test]
+    fn a_watch_ends_when_the_order_leaves_the_book(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.watch_queue_position(1, 2, false).unwrap();
+        engine.cancel(2, 1, &span, false).unwrap();
+        assert!(queue_updates(&mut engine).is_empty());
+
+        // back in the book under the same id, it isn't watched any more
+        engine.match_order(limit_order(3, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.cancel(1, 1, &span, false).unwrap();
+        assert!(queue_updates(&mut engine).is_empty());
+        assert_eq!(engine.watch_queue_position(1, 9, false).unwrap_err(), EngineError::UnknownOrder(9));
+    }
+
+    fn with_client_id(owner_id : u64, client_order_id : &str, order : EngineNewOrder) -> EngineNewOrder{
+        EngineNewOrder { client_order_id : Some(client_order_id.to_string()), ..owned_by(owner_id, order) }
+    }
+
+    #[test]
+    fn a_client_order_id_is_unique_among_the_owners_live_orders(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(with_client_id(7, "A", limit_order(1, false, 101, 5)), &span).unwrap();
+
+        let duplicate = EngineError::DuplicateClientOrderId { owner_id : 7, client_order_id : "A".to_string() };
+        assert_eq!(engine.match_order(with_client_id(7, "A", limit_order(2, false, 102, 5)), &span).unwrap_err(), duplicate);
+        assert_eq!(levels(&engine, false), [(101, 5)]);
+        // another owner can use the same one
+        engine.match_order(with_client_id(8, "A", limit_order(3, false, 102, 5)), &span).unwrap();
+
+        // free again once the order is done
+        engine.match_order(limit_order(4, true, 101, 5), &span).unwrap();
+        engine.match_order(with_client_id(7, "A", limit_order(5, true, 99, 5)), &span).unwrap();
+        assert_eq!(engine.order_status_by_client_order_id(7, "A").unwrap().order_id, 5);
+        assert_eq!(engine.order_status(1, 1).unwrap().client_order_id.as_deref(), Some("A"));
+    }
+
+    #[test]
+    fn orders_can_be_modified_and_cancelled_by_client_order_id(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(with_client_id(7, "A", limit_order(1, true, 99, 5)), &span).unwrap();
+
+        // the re-entered order keeps its client order id
+        engine.modify_by_client_order_id(7, "A", Some(100), None, &span).unwrap();
+        assert_eq!(levels(&engine, true), [(100, 5)]);
+        let status = engine.order_status_by_client_order_id(7, "A").unwrap();
+        assert_eq!((status.order_id, status.price), (1, Some(100)));
+
+        engine.cancel_by_client_order_id(7, "A", &span).unwrap();
+        assert!(levels(&engine, true).is_empty());
+        let unknown = EngineError::UnknownClientOrderId { owner_id : 7, client_order_id : "A".to_string() };
+        assert_eq!(engine.cancel_by_client_order_id(7, "A", &span).unwrap_err(), unknown);
+        assert_eq!(engine.order_status_by_client_order_id(8, "A").unwrap_err(), EngineError::UnknownClientOrderId { owner_id : 8, client_order_id : "A".to_string() });
+    }
+
+    fn cancelled_ids(cancelled : &[EngineCancelOrder]) -> Vec<(u32, u64)>{
+        cancelled.iter().map(|order| (order.security_id, order.order_id)).collect()
+    }
+
+    #[test]
+    fn mass_cancel_filters_by_security_side_owner_and_band(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.register_security(Instrument::new(2, "OTHER")).unwrap();
+        engine.match_order(owned_by(7, limit_order(1, true, 98, 5)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(2, true, 99, 5)), &span).unwrap();
+        engine.match_order(owned_by(8, limit_order(3, true, 99, 5)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(4, false, 101, 5)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(5, false, 103, 5)), &span).unwrap();
+        engine.match_order(EngineNewOrder { security_id : 2, ..owned_by(7, limit_order(6, true, 99, 5)) }, &span).unwrap();
+
+        let owner_bids = MassCancelFilter { security_id : Some(1), is_buy_side : Some(true), owner_id : Some(7), ..Default::default() };
+        assert_eq!(cancelled_ids(&engine.mass_cancel(owner_bids, &span).unwrap()), [(1, 2), (1, 1)]);
+        assert_eq!(levels(&engine, true), [(99, 5)]);
+
+        let band = MassCancelFilter { min_price : Some(99), max_price : Some(102), ..Default::default() };
+        assert_eq!(cancelled_ids(&engine.mass_cancel(band, &span).unwrap()), [(1, 3), (1, 4), (2, 6)]);
+        assert_eq!(levels(&engine, true), []);
+        assert_eq!(levels(&engine, false), [(103, 5)]);
+        assert_eq!(status(&engine, 4).unwrap().0, OrderState::Cancelled);
+
+        // nothing left to match is not an error
+        assert!(engine.mass_cancel(band, &span).unwrap().is_empty());
+        let unknown = MassCancelFilter { security_id : Some(9), ..Default::default() };
+        assert_eq!(engine.mass_cancel(unknown, &span).unwrap_err(), EngineError::UnknownSecurity(9));
+    }
+
+    #[test]
+    fn mass_cancel_takes_out_untriggered_stops_by_their_stop_price(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, true, 99, 5), &span).unwrap();
+        engine.match_order(stop(10, true, 105, 5), &span).unwrap();
+        engine.match_order(stop_limit(11, false, 95, 94, 5), &span).unwrap();
+        engine.match_order(stop(12, false, 90, 5), &span).unwrap();
+
+        let band = MassCancelFilter { min_price : Some(91), max_price : Some(105), ..Default::default() };
+        assert_eq!(cancelled_ids(&engine.mass_cancel(band, &span).unwrap()), [(1, 1), (1, 10), (1, 11)]);
+        assert_eq!(status(&engine, 10).unwrap().0, OrderState::Cancelled);
+        assert_eq!(status(&engine, 12).unwrap().0, OrderState::Live);
+        // a cancelled stop no longer triggers
+        engine.match_order(limit_order(2, false, 105, 5), &span).unwrap();
+        engine.match_order(limit_order(3, true, 105, 5), &span).unwrap();
+        assert_eq!(levels(&engine, true), []);
+        assert_eq!(levels(&engine, false), []);
+    }
+
+    #[test]
+    fn mass_cancel_goes_on_past_an_order_that_fails_to_cancel(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 98, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 101, 5), &span).unwrap();
+        // order 1 still sits in its level but the book has lost track of it, so cancelling it fails
+        engine._book.get_mut(&1).unwrap().bid.order_registry.remove(&1);
+
+        let cancelled = engine.mass_cancel(MassCancelFilter::default(), &span).unwrap();
+        assert_eq!(cancelled_ids(&cancelled), [(1, 2), (1, 3)]);
+        assert_eq!(status(&engine, 1).unwrap().0, OrderState::Live);
+        assert_eq!(levels(&engine, true), [(99, 5)]);
+        assert_eq!(levels(&engine, false), []);
+    }
+
+    fn in_session(session_id : u64, time_in_force : TimeInForce, order : EngineNewOrder) -> EngineNewOrder{
+        EngineNewOrder { time_in_force, session_id : Some(session_id), ..order }
+    }
+
+    fn order_ids(cancelled : &[EngineCancelOrder]) -> Vec<u64>{
+        cancelled.iter().map(|order| order.order_id).collect()
+    }
+
+    #[test]
+    fn ending_a_session_cancels_its_orders_and_stops_everywhere(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.register_security(Instrument::new(2, "OTHER")).unwrap();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, limit_order(4, true, 99, 5)), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::Day, limit_order(2, false, 101, 5)), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, stop(3, true, 105, 5)), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, EngineNewOrder { security_id : 2, ..limit_order(1, true, 50, 5) }), &span).unwrap();
+        engine.match_order(in_session(8, TimeInForce::GoodTillCancel, limit_order(5, true, 98, 5)), &span).unwrap();
+        engine.match_order(limit_order(6, true, 97, 5), &span).unwrap();
+
+        let cancelled = engine.end_session(7, &span).unwrap();
+        assert_eq!(cancelled_ids(&cancelled), [(1, 2), (1, 3), (1, 4), (2, 1)]);
+        assert_eq!(levels(&engine, true), [(98, 5), (97, 5)]);
+        assert_eq!(levels(&engine, false), []);
+        assert_eq!(status(&engine, 3).unwrap().0, OrderState::Cancelled);
+        assert_eq!(status(&engine, 5).unwrap().0, OrderState::Live);
+        // the session is over, ending it again finds nothing
+        assert!(engine.end_session(7, &span).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_filled_order_is_no_longer_part_of_its_session(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, limit_order(1, false, 100, 5)), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, limit_order(2, false, 101, 5)), &span).unwrap();
+        engine.match_order(limit_order(3, true, 100, 5), &span).unwrap();
+
+        assert_eq!(order_ids(&engine.end_session(7, &span).unwrap()), [2]);
+        assert_eq!(status(&engine, 1).unwrap().0, OrderState::Filled);
+    }
+
+    #[test]
+    fn a_modified_order_keeps_its_session_and_time_in_force(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(in_session(7, TimeInForce::Day, limit_order(1, true, 99, 5)), &span).unwrap();
+        engine.modify(1, 1, Some(98), Some(8), true, &span).unwrap();
+        engine.set_session_keeps_gtc(7, true).unwrap();
+
+        // still a day order, so keeping good till cancel orders doesn't save it
+        assert_eq!(order_ids(&engine.end_session(7, &span).unwrap()), [1]);
+        assert_eq!(levels(&engine, true), []);
+    }
+
+    #[test]
+    fn a_session_keeping_gtc_only_loses_its_day_orders(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.set_session_keeps_gtc(7, true).unwrap();
+        engine.match_order(in_session(7, TimeInForce::GoodTillCancel, limit_order(1, true, 99, 5)), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::Day, limit_order(2, true, 98, 5)), &span).unwrap();
+
+        assert_eq!(order_ids(&engine.end_session(7, &span).unwrap()), [2]);
+        assert_eq!(status(&engine, 1).unwrap().0, OrderState::Live);
+
+        // opting back in, the good till cancel order goes with the session too
+        engine.set_session_keeps_gtc(7, false).unwrap();
+        assert_eq!(order_ids(&engine.end_session(7, &span).unwrap()), [1]);
+    }
+
+    #[test]
+    fn day_orders_expire_with_the_trading_day(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.register_security(Instrument::new(2, "OTHER")).unwrap();
+        engine.match_order(limit_order(1, true, 99, 5), &span).unwrap();
+        engine.match_order(in_session(7, TimeInForce::Day, limit_order(3, true, 98, 5)), &span).unwrap();
+        engine.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(2, false, 101, 5) }, &span).unwrap();
+        engine.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..stop(4, true, 105, 5) }, &span).unwrap();
+        engine.match_order(EngineNewOrder { security_id : 2, time_in_force : TimeInForce::Day, ..limit_order(5, true, 50, 5) }, &span).unwrap();
+
+        assert_eq!(order_ids(&engine.expire_day_orders(1, &span).unwrap()), [2, 3, 4]);
+        assert_eq!(levels(&engine, true), [(99, 5)]);
+        assert_eq!(levels(&engine, false), []);
+        assert_eq!(status(&engine, 1).unwrap().0, OrderState::Live);
+        assert_eq!(engine.order_status(2, 5).unwrap().state, OrderState::Live);
+        assert_eq!(engine.expire_day_orders(9, &span).unwrap_err(), EngineError::UnknownSecurity(9));
+    }
+
+    #[test]
+    fn day_orders_rest_and_take_post_only_like_good_till_cancel(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(2, true, 100, 8) }, &span).unwrap();
+        assert_eq!(levels(&engine, true), [(100, 3)]);
+        let day_post_only = EngineNewOrder { time_in_force : TimeInForce::Day, post_only : Some(PostOnly::Reject), ..limit_order(3, true, 99, 5) };
+        engine.match_order(day_post_only, &span).unwrap();
+        assert_eq!(levels(&engine, true), [(100, 3), (99, 5)]);
+    }
+
+    fn with_limits(engine : &mut MatchingEngine, limits : RiskLimits){
+        engine.set_risk_check(Some(Box::new(ParticipantLimits::new(limits))));
+    }
+
+    #[test]
+    fn an_order_over_a_limit_is_rejected_with_the_reason_and_never_reaches_the_book(){
+        let span = Span::none();
+        let mut engine = engine();
+        with_limits(&mut engine, RiskLimits { max_order_quantity : Some(10), max_notional : Some(1_000), ..Default::default() });
+        engine.match_order(limit_order(1, false, 100, 10), &span).unwrap();
+        let before = engine.last_sequence();
+
+        let too_big = engine.match_order(limit_order(2, true, 100, 11), &span).unwrap_err();
+        assert_eq!(too_big, EngineError::RiskRejected(RiskRejection::MaxOrderQuantity { quantity : 11, limit : 10 }));
+        assert_eq!(too_big.to_string(), "rejected by pre-trade risk: quantity 11 above the limit of 10");
+        let too_dear = engine.match_order(limit_order(3, true, 101, 10), &span).unwrap_err();
+        assert_eq!(too_dear, EngineError::RiskRejected(RiskRejection::MaxNotional { notional : 1_010, limit : 1_000 }));
+        // a market buy is valued at the 100 it would pay
+        engine.match_order(market_order(4, true, 10, None), &span).unwrap();
+        assert_eq!(levels(&engine, false), []);
+        assert_eq!(status(&engine, 2), None);
+        assert_eq!(status(&engine, 3), None);
+        // each rejection is numbered like any other event
+        assert!(engine.last_sequence() > before + 2);
+    }
+
+    #[test]
+    fn the_collar_follows_the_bbo_and_then_the_last_trade(){
+        let span = Span::none();
+        let mut engine = engine();
+        with_limits(&mut engine, RiskLimits { price_collar_bps : Some(1_000), ..Default::default() });
+        // nothing to measure against yet
+        engine.match_order(limit_order(1, false, 200, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 150, 5), &span).unwrap();
+        // 10% through the 200 offer
+        let collared = EngineError::RiskRejected(RiskRejection::PriceCollar { price : 221, reference_price : 200, collar_bps : 1_000 });
+        assert_eq!(engine.match_order(limit_order(3, true, 221, 5), &span).unwrap_err(), collared);
+        engine.match_order(limit_order(3, true, 220, 5), &span).unwrap();
+        // the offer is gone, buys are now collared against the trade at 200
+        assert_eq!(levels(&engine, false), []);
+        let collared = EngineError::RiskRejected(RiskRejection::PriceCollar { price : 221, reference_price : 200, collar_bps : 1_000 });
+        assert_eq!(engine.match_order(limit_order(4, true, 221, 5), &span).unwrap_err(), collared);
+        // sells still go by the 150 bid
+        let collared = EngineError::RiskRejected(RiskRejection::PriceCollar { price : 134, reference_price : 150, collar_bps : 1_000 });
+        assert_eq!(engine.match_order(limit_order(5, false, 134, 5), &span).unwrap_err(), collared);
+        engine.match_order(limit_order(5, false, 135, 5), &span).unwrap();
+    }
+
+    #[test]
+    fn limits_are_per_participant(){
+        let span = Span::none();
+        let mut engine = engine();
+        let mut limits = ParticipantLimits::new(RiskLimits { max_order_quantity : Some(10), ..Default::default() });
+        limits.set_limits(8, RiskLimits::default());
+        engine.set_risk_check(Some(Box::new(limits)));
+        assert!(engine.match_order(owned_by(7, limit_order(1, true, 100, 11)), &span).is_err());
+        engine.match_order(owned_by(8, limit_order(2, true, 100, 11)), &span).unwrap();
+        // taking the check out lets everyone through
+        engine.set_risk_check(None);
+        engine.match_order(owned_by(7, limit_order(3, true, 100, 11)), &span).unwrap();
+        assert_eq!(levels(&engine, true), [(100, 22)]);
+    }
+
+    #[test]
+    fn a_modify_is_checked_on_the_terms_it_re_enters_with(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 100, 5), &span).unwrap();
+        engine.match_order(iceberg(2, true, 90, 12, 4), &span).unwrap();
+        with_limits(&mut engine, RiskLimits { price_collar_bps : Some(1_000), ..Default::default() });
+
+        // repriced through the collar around the 100 offer
+        let collared = EngineError::RiskRejected(RiskRejection::PriceCollar { price : 111, reference_price : 100, collar_bps : 1_000 });
+        assert_eq!(engine.modify(2, 1, Some(111), None, true, &span).unwrap_err(), collared);
+        // all 12 (reserve included) at 101 is over the notional
+        with_limits(&mut engine, RiskLimits { max_notional : Some(1_200), ..Default::default() });
+        let too_dear = EngineError::RiskRejected(RiskRejection::MaxNotional { notional : 1_212, limit : 1_200 });
+        assert_eq!(engine.modify(2, 1, Some(101), None, true, &span).unwrap_err(), too_dear);
+        // the rejected modifies left the order where it was
+        assert_eq!(status(&engine, 2).unwrap(), (OrderState::Live, Some(90), 0, 12, Some(1)));
+        engine.modify(2, 1, Some(99), None, true, &span).unwrap();
+        // a size reduction only takes risk off and isn't checked
+        with_limits(&mut engine, RiskLimits { max_notional : Some(1), ..Default::default() });
+        engine.modify(2, 1, None, Some(8), true, &span).unwrap();
+    }
+
+    #[derive(Debug)]
+    struct NoOddLots;
+
+    impl RiskCheck for NoOddLots {
+        fn check(&self, order : &RiskOrder, _market : &MarketContext, _position : &Position) -> Result<(), RiskRejection>{
+            if order.quantity.is_multiple_of(10) { Ok(()) } else { Err(RiskRejection::Other("odd lot".to_string())) }
+        }
+    }
+
+    #[test]
+    fn any_risk_check_can_be_plugged_in(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.set_risk_check(Some(Box::new(NoOddLots)));
+        engine.match_order(limit_order(1, true, 100, 10), &span).unwrap();
+        let rejected = engine.match_order(limit_order(2, true, 100, 15), &span).unwrap_err();
+        assert_eq!(rejected.to_string(), "rejected by pre-trade risk: odd lot");
+    }
+
+    // (quantity, notional) resting on one side for the owner, and its net traded position
+    fn exposure(engine : &MatchingEngine, owner_id : u64, is_buy_side : bool) -> (u64, u64){
+        let open = engine.position(owner_id, 1).unwrap().open(is_buy_side);
+        (open.quantity, open.notional)
+    }
+
+    fn net(engine : &MatchingEngine, owner_id : u64) -> i64{
+        engine.position(owner_id, 1).unwrap().net_quantity()
+    }
+
+    #[test]
+    fn exposure_follows_orders_in_and_out_of_the_book(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(owned_by(7, limit_order(1, true, 100, 10)), &span).unwrap();
+        engine.match_order(owned_by(7, iceberg(2, true, 99, 20, 5)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(3, false, 105, 4)), &span).unwrap();
+        // the iceberg reserve counts too
+        assert_eq!(exposure(&engine, 7, true), (30, 10 * 100 + 20 * 99));
+        assert_eq!(exposure(&engine, 7, false), (4, 4 * 105));
+
+        // a partial fill takes off what traded
+        engine.match_order(owned_by(8, limit_order(4, false, 100, 6)), &span).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (24, 4 * 100 + 20 * 99));
+        assert_eq!((net(&engine, 7), net(&engine, 8)), (6, -6));
+        // so does a cancel, whatever is left
+        engine.cancel(1, 1, &span, true).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (20, 20 * 99));
+        // and a sweep through the iceberg's refills
+        engine.match_order(owned_by(8, limit_order(5, false, 99, 20)), &span).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (0, 0));
+        assert_eq!((net(&engine, 7), net(&engine, 8)), (26, -26));
+        let traded = engine.position(7, 1).unwrap().traded;
+        assert_eq!((traded.bought_quantity, traded.bought_notional, traded.sold_quantity), (26, 6 * 100 + 20 * 99, 0));
+        // the untouched ask is all that's left
+        assert_eq!(engine.positions(7).len(), 1);
+        assert_eq!(exposure(&engine, 7, false), (4, 4 * 105));
+    }
+
+    #[test]
+    fn exposure_follows_a_modify(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(owned_by(7, limit_order(1, true, 100, 10)), &span).unwrap();
+        engine.modify(1, 1, Some(98), None, true, &span).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (10, 980));
+        // a smaller order size doesn't take off what is still open
+        engine.modify(1, 1, None, Some(8), true, &span).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (10, 980));
+        // a modify that trades on re-entry leaves only the remainder
+        engine.match_order(owned_by(8, limit_order(2, false, 99, 4)), &span).unwrap();
+        engine.modify(1, 1, Some(99), None, true, &span).unwrap();
+        assert_eq!(exposure(&engine, 7, true), (6, 6 * 99));
+        assert_eq!(net(&engine, 7), 4);
+    }
+
+    #[test]
+    fn self_trade_prevention_and_mass_cancels_release_exposure(){
+        let (mut engine, _) = self_trade(Some(SelfTradePrevention::DecrementAndCancel), 3);
+        // owner 7's 5 at 100 lost the 3 it would have crossed with
+        assert_eq!(exposure(&engine, 7, false), (2, 200));
+        assert_eq!(net(&engine, 7), 0);
+        engine.mass_cancel(MassCancelFilter { owner_id : Some(8), ..Default::default() }, &Span::none()).unwrap();
+        assert_eq!(exposure(&engine, 8, false), (0, 0));
+        assert!(engine.positions(8).is_empty());
+    }
+
+    #[test]
+    fn positions_are_kept_per_security(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.register_security(Instrument::new(2, "OTHER")).unwrap();
+        engine.match_order(owned_by(7, EngineNewOrder { security_id : 2, ..limit_order(1, false, 50, 5) }), &span).unwrap();
+        engine.match_order(owned_by(8, EngineNewOrder { security_id : 2, ..limit_order(2, true, 50, 2) }), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(3, true, 100, 1)), &span).unwrap();
+
+        let positions = engine.positions(7);
+        assert_eq!(positions.iter().map(|position| position.security_id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!((positions[0].net_quantity(), positions[0].open_buy.quantity), (0, 1));
+        assert_eq!((positions[1].net_quantity(), positions[1].open_sell.quantity), (-2, 3));
+        assert!(engine.positions(9).is_empty());
+        assert_eq!(engine.position(9, 1).unwrap(), Position { owner_id : 9, security_id : 1, ..Default::default() });
+        assert_eq!(engine.position(7, 3).unwrap_err(), EngineError::UnknownSecurity(3));
+    }
+
+    #[test]
+    fn open_exposure_limits_do_not_count_a_modified_order_twice(){
+        let span = Span::none();
+        let mut engine = engine();
+        with_limits(&mut engine, RiskLimits { max_open_quantity : Some(15), max_position : Some(20), ..Default::default() });
+        engine.match_order(owned_by(7, limit_order(1, true, 100, 10)), &span).unwrap();
+        let too_much = EngineError::RiskRejected(RiskRejection::MaxOpenQuantity { open_quantity : 16, limit : 15 });
+        assert_eq!(engine.match_order(owned_by(7, limit_order(2, true, 99, 6)), &span).unwrap_err(), too_much);
+        // repricing order 1 only counts its 10 once
+        engine.modify(1, 1, Some(101), None, true, &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(2, true, 99, 5)), &span).unwrap();
+        // bought 15, with 15 more bid the worst case is long 30
+        engine.match_order(owned_by(8, limit_order(3, false, 99, 15)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(4, true, 98, 5)), &span).unwrap();
+        let too_long = EngineError::RiskRejected(RiskRejection::MaxPosition { position : 21, limit : 20 });
+        assert_eq!(engine.match_order(owned_by(7, limit_order(5, true, 98, 1)), &span).unwrap_err(), too_long);
+    }
+
+    fn pro_rata_engine(top_order_priority : bool, min_allocation : u32) -> MatchingEngine{
+        let mut engine = MatchingEngine::new();
+        let matching_algorithm = MatchingAlgorithm::ProRata { top_order_priority, min_allocation };
+        engine.register_security(Instrument { matching_algorithm, ..Instrument::new(1, "TEST") }).unwrap();
+        engine
+    }
+
+    #[test]
+    fn a_pro_rata_level_is_shared_in_proportion_before_the_next_one(){
+        let span = Span::none();
+        let mut engine = pro_rata_engine(false, 0);
+        engine.match_order(limit_order(1, false, 100, 10), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 30), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 60), &span).unwrap();
+        engine.match_order(limit_order(4, false, 101, 20), &span).unwrap();
+
+        let outcome = engine.match_order(limit_order(5, true, 100, 50), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(1, 5), (2, 15), (3, 30)]);
+        assert_eq!(levels(&engine, false), [(100, 50), (101, 20)]);
+        // the rest of 100 in full, then on to 101
+        let outcome = engine.match_order(limit_order(6, true, 101, 60), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(1, 5), (2, 15), (3, 30), (4, 10)]);
+        assert_eq!(levels(&engine, false), [(101, 10)]);
+    }
+
+    #[test]
+    fn pro_rata_top_order_priority_and_minimum_allocation(){
+        let span = Span::none();
+        let mut engine = pro_rata_engine(true, 2);
+        engine.match_order(limit_order(1, false, 100, 4), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 95), &span).unwrap();
+        // 4 to the top order, then 16 over 5 and 95 is 0 and 15. the 1 left over goes to
+        // order 2, first in the queue with room
+        let outcome = engine.match_order(limit_order(4, true, 100, 20), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(1, 4), (2, 1), (3, 15)]);
+        assert_eq!(status(&engine, 2).unwrap(), (OrderState::Live, Some(100), 1, 4, Some(1)));
+    }
+
+    #[test]
+    fn a_pro_rata_iceberg_shares_by_its_peak_and_refills_for_the_next_round(){
+        let span = Span::none();
+        let mut engine = pro_rata_engine(false, 0);
+        engine.match_order(iceberg(1, false, 100, 20, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 10), &span).unwrap();
+        // both shown quantities go, the iceberg refills behind
+        let outcome = engine.match_order(limit_order(3, true, 100, 15), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(1, 5), (2, 10)]);
+        assert_eq!(levels(&engine, false), [(100, 5)]);
+        // one round per refill until the reserve runs out
+        let outcome = engine.match_order(limit_order(4, true, 100, 20), &span).unwrap();
+        assert_eq!(passive_fills(&outcome), [(1, 5), (1, 5), (1, 5)]);
+        assert_eq!(levels(&engine, false), []);
+        assert_eq!(levels(&engine, true), [(100, 5)]);
+    }
+
+    #[test]
+    fn pro_rata_deals_with_the_aggressors_own_orders_before_the_split(){
+        let span = Span::none();
+        let mut engine = pro_rata_engine(false, 0);
+        engine.set_self_trade_prevention(Some(SelfTradePrevention::CancelOldest)).unwrap();
+        engine.match_order(owned_by(8, limit_order(1, false, 100, 5)), &span).unwrap();
+        engine.match_order(owned_by(7, limit_order(2, false, 100, 5)), &span).unwrap();
+        engine.match_order(owned_by(9, limit_order(3, false, 100, 10)), &span).unwrap();
+        // owner 7's order is cancelled wherever it sits, 10 is split over the other 15
+        let outcome = engine.match_order(owned_by(7, limit_order(4, true, 100, 10)), &span).unwrap();
+        assert_eq!(outcome.self_trade_cancels, [2]);
+        assert_eq!(passive_fills(&outcome), [(1, 4), (3, 6)]);
+
+        // cancelling the aggressor instead, it never gets to the orders ahead of its own
+        engine.set_self_trade_prevention(Some(SelfTradePrevention::CancelNewest)).unwrap();
+        engine.match_order(owned_by(7, limit_order(5, false, 100, 5)), &span).unwrap();
+        let outcome = engine.match_order(owned_by(7, limit_order(6, true, 100, 5)), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(levels(&engine, false), [(100, 10)]);
+    }
+
+    #[test]
+    fn a_pro_rata_fill_or_kill_counts_nothing_past_a_level_with_the_aggressors_own_order(){
+        let span = Span::none();
+        let run = |mut engine : MatchingEngine| {
+            engine.set_self_trade_prevention(Some(SelfTradePrevention::CancelNewest)).unwrap();
+            engine.match_order(owned_by(8, limit_order(1, false, 100, 10)), &span).unwrap();
+            engine.match_order(owned_by(7, limit_order(2, false, 100, 5)), &span).unwrap();
+            let outcome = engine.match_order(owned_by(7, EngineNewOrder { time_in_force : TimeInForce::FillOrKill, ..limit_order(3, true, 100, 10) }), &span).unwrap();
+            (passive_fills(&outcome), outcome.cancelled_quantity, levels(&engine, false))
+        };
+        // fifo gets its 10 from the head before reaching its own order
+        assert_eq!(run(engine()), (vec![(1, 10)], 0, vec![(100, 5)]));
+        // pro-rata would run into it first, so nothing trades
+        assert_eq!(run(pro_rata_engine(false, 0)), (vec![], 10, vec![(100, 15)]));
+    }
+
+    #[test]
+    fn the_uncross_trades_everything_executable_at_one_price(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.start_auction(1, AuctionKind::Opening).unwrap();
+        engine.match_order(limit_order(1, true, 102, 10), &span).unwrap();
+        engine.match_order(limit_order(2, true, 101, 10), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 15), &span).unwrap();
+        engine.match_order(limit_order(4, false, 103, 5), &span).unwrap();
+        // nothing matches while the auction collects orders
+        assert_eq!(levels(&engine, true), [(102, 10), (101, 10)]);
+
+        // 15 trade at 100 and at 101, both leave 5 bought over, so the higher one
+        let indicative = engine.indicative_auction_price(1).unwrap().unwrap();
+        assert_eq!((indicative.price, indicative.matched_quantity, indicative.imbalance()), (101, 15, 5));
+
+        let outcome = engine.uncross(1, &span).unwrap();
+        assert_eq!(outcome.auction_price, Some(indicative));
+        let fills : Vec<(u64, u64, u32, u32)> = outcome.fills.iter().map(|fill| (fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 3, 101, 10), (2, 3, 101, 5)]);
+        assert!(outcome.cancelled_orders.is_empty());
+        assert_eq!(status(&engine, 3).unwrap().0, OrderState::Filled);
+        assert_eq!(status(&engine, 2), Some((OrderState::Live, Some(101), 5, 5, Some(1))));
+
+        // the book goes on trading continuously
+        assert_eq!(engine.match_order(limit_order(5, false, 101, 5), &span).unwrap().fills.len(), 1);
+        assert_eq!(engine.indicative_auction_price(1).unwrap_err(), EngineError::NoAuction(1));
+    }
+
+    #[test]
+    fn the_auction_price_with_a_sell_surplus_is_the_lowest_of_the_tied_prices(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.start_auction(1, AuctionKind::Closing).unwrap();
+        engine.match_order(limit_order(1, true, 101, 15), &span).unwrap();
+        engine.match_order(limit_order(2, false, 99, 10), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 10), &span).unwrap();
+
+        // 15 trade at 100 and at 101, both leave 5 sold over
+        let auction_price = engine.indicative_auction_price(1).unwrap().unwrap();
+        assert_eq!((auction_price.price, auction_price.matched_quantity, auction_price.imbalance()), (100, 15, -5));
+    }
+
+    #[test]
+    fn the_smallest_surplus_wins_over_market_pressure(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.start_auction(1, AuctionKind::Opening).unwrap();
+        engine.match_order(limit_order(1, true, 101, 10), &span).unwrap();
+        engine.match_order(limit_order(2, true, 100, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 10), &span).unwrap();
+        engine.match_order(limit_order(4, false, 101, 2), &span).unwrap();
+
+        // 10 trade at 100 leaving 5 bought over and at 101 leaving 2 sold over
+        let auction_price = engine.indicative_auction_price(1).unwrap().unwrap();
+        assert_eq!((auction_price.price, auction_price.matched_quantity, auction_price.imbalance()), (101, 10, -2));
+    }
+
+    #[test]
+    fn a_balanced_auction_uncrosses_closest_to_the_last_trade_price(){
+        let span = Span::none();
+        let balanced = |engine : &mut MatchingEngine| {
+            engine.start_auction(1, AuctionKind::Opening).unwrap();
+            engine.match_order(limit_order(11, true, 101, 10), &span).unwrap();
+            engine.match_order(limit_order(12, false, 99, 10), &span).unwrap();
+            engine.indicative_auction_price(1).unwrap().unwrap().price
+        };
+        // nothing traded yet, the lowest price
+        assert_eq!(balanced(&mut engine()), 99);
+        // the reference price is a candidate itself, outside the crossing range the closest end wins
+        for (last_trade_price, auction_price) in [(100, 100), (105, 101)] {
+            let mut traded = engine();
+            traded.match_order(limit_order(1, true, last_trade_price, 1), &span).unwrap();
+            traded.match_order(limit_order(2, false, last_trade_price, 1), &span).unwrap();
+            assert_eq!(balanced(&mut traded), auction_price);
+        }
+    }
+
+    #[test]
+    fn market_orders_the_uncross_leaves_unfilled_are_cancelled(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.start_auction(1, AuctionKind::Opening).unwrap();
+        engine.match_order(market_order(1, true, 20, None), &span).unwrap();
+        engine.match_order(market_order(2, false, 3, None), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 5), &span).unwrap();
+        assert_eq!(status(&engine, 1), Some((OrderState::Live, None, 0, 20, None)));
+
+        let outcome = engine.uncross(1, &span).unwrap();
+        assert_eq!(outcome.auction_price.map(|auction_price| (auction_price.price, auction_price.matched_quantity)), Some((100, 8)));
+        assert_eq!(outcome.cancelled_orders, [1]);
+        assert_eq!(status(&engine, 1).map(|status| (status.0, status.2)), Some((OrderState::Cancelled, 8)));
+        assert_eq!(status(&engine, 2).unwrap().0, OrderState::Filled);
+        assert_eq!(status(&engine, 3).unwrap().0, OrderState::Filled);
+        // nothing of the market buy is left to rest
+        assert!(levels(&engine, true).is_empty());
+
+        // with nothing to trade against, all of it
+        engine.start_auction(1, AuctionKind::Closing).unwrap();
+        engine.match_order(market_order(4, false, 5, None), &span).unwrap();
+        let outcome = engine.uncross(1, &span).unwrap();
+        assert_eq!(outcome.auction_price, None);
+        assert_eq!(outcome.cancelled_orders, [4]);
+        assert_eq!(status(&engine, 4).map(|status| (status.0, status.2)), Some((OrderState::Cancelled, 0)));
+    }
+
+    #[test]
+    fn a_mass_cancel_without_a_price_band_takes_out_market_orders_waiting_for_the_auction(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.start_auction(1, AuctionKind::Opening).unwrap();
+        engine.match_order(market_order(1, true, 5, None), &span).unwrap();
+        engine.match_order(limit_order(2, true, 99, 5), &span).unwrap();
+
+        let banded = MassCancelFilter { min_price : Some(90), ..Default::default() };
+        let cancelled : Vec<u64> = engine.mass_cancel(banded, &span).unwrap().iter().map(|order| order.order_id).collect();
+        assert_eq!(cancelled, [2]);
+        let cancelled : Vec<u64> = engine.mass_cancel(MassCancelFilter::default(), &span).unwrap().iter().map(|order| order.order_id).collect();
+        assert_eq!(cancelled, [1]);
+        assert_eq!(engine.uncross(1, &span).unwrap().cancelled_orders, Vec::<u64>::new());
+    }
+
+    #[test]
+    fn a_closed_security_only_accepts_cancels_until_it_opens_again(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.match_order(limit_order(1, false, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 102, 5), &span).unwrap();
+        engine.set_trading_state(1, TradingState::Closed, &span).unwrap();
+
+        let not_accepting = |state| EngineError::NotAcceptingOrders { security_id : 1, state };
+        assert_eq!(engine.match_order(limit_order(3, true, 101, 5), &span).unwrap_err(), not_accepting(TradingState::Closed));
+        assert_eq!(engine.modify(1, 1, Some(100), None, false, &span).unwrap_err(), not_accepting(TradingState::Closed));
+        engine.set_trading_state(1, TradingState::PreOpen, &span).unwrap();
+        assert_eq!(engine.match_order(limit_order(3, true, 101, 5), &span).unwrap_err(), not_accepting(TradingState::PreOpen));
+        engine.cancel(2, 1, &span, false).unwrap();
+        assert_eq!(levels(&engine, false), [(101, 5)]);
+
+        // the opening auction collects the crossing bid, its uncross opens the security
+        engine.set_trading_state(1, TradingState::OpeningAuction, &span).unwrap();
+        assert!(engine.match_order(limit_order(3, true, 101, 5), &span).unwrap().fills.is_empty());
+        let transition = engine.set_trading_state(1, TradingState::Continuous, &span).unwrap();
+        assert_eq!((transition.from, transition.to), (TradingState::OpeningAuction, TradingState::Continuous));
+        assert_eq!(transition.uncross.unwrap().fills.len(), 1);
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::Continuous);
+    }
+
+    #[test]
+    fn a_transition_the_session_does_not_allow_is_rejected(){
+        let span = Span::none();
+        let mut engine = engine();
+        let rejected = engine.set_trading_state(1, TradingState::PreOpen, &span).unwrap_err();
+        assert_eq!(rejected, EngineError::InvalidStateTransition { security_id : 1, from : TradingState::Continuous, to : TradingState::PreOpen });
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::Continuous);
+
+        // staying put is a no-op
+        let transition = engine.set_trading_state(1, TradingState::Continuous, &span).unwrap();
+        assert_eq!((transition.from, transition.to), (TradingState::Continuous, TradingState::Continuous));
+        assert_eq!(engine.set_trading_state(2, TradingState::Closed, &span).unwrap_err(), EngineError::UnknownSecurity(2));
+    }
+
+    #[test]
+    fn a_halted_auction_carries_on_when_resumed(){
+        let span = Span::none();
+        let mut engine = engine();
+        engine.set_trading_state(1, TradingState::ClosingAuction, &span).unwrap();
+        engine.match_order(limit_order(1, true, 101, 5), &span).unwrap();
+        engine.match_order(limit_order(2, false, 100, 5), &span).unwrap();
+        engine.halt(1).unwrap();
+
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::Halted);
+        assert_eq!(engine.uncross(1, &span).unwrap_err(), EngineError::SecurityHalted(1));
+        engine.resume(1).unwrap();
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::ClosingAuction);
+        assert_eq!(engine.uncross(1, &span).unwrap().fills.len(), 1);
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::Closed);
+    }
+
+    #[test]
+    fn the_close_expires_day_orders_after_the_closing_uncross(){
+        let span = Span::none();
+        let mut engine = engine();
+        let day_order = |order_id, is_buy_side, price, quantity| EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(order_id, is_buy_side, price, quantity) };
+        engine.match_order(day_order(1, true, 99, 5), &span).unwrap();
+        engine.match_order(limit_order(2, true, 98, 5), &span).unwrap();
+        engine.match_order(limit_order(3, false, 100, 3), &span).unwrap();
+        engine.set_trading_state(1, TradingState::ClosingAuction, &span).unwrap();
+        engine.match_order(day_order(4, true, 100, 3), &span).unwrap();
+
+        let transition = engine.set_trading_state(1, TradingState::Closed, &span).unwrap();
+        assert_eq!(transition.uncross.unwrap().fills.len(), 1);
+        // the day order the uncross filled is done already
+        let expired : Vec<u64> = transition.expired_orders.iter().map(|order| order.order_id).collect();
+        assert_eq!(expired, [1]);
+        assert_eq!(status(&engine, 4).unwrap().0, OrderState::Filled);
+        assert_eq!(status(&engine, 1).unwrap().0, OrderState::Cancelled);
+        assert_eq!(levels(&engine, true), [(98, 5)]);
+    }
+
+    #[test]
+    fn scheduled_transitions_are_made_once_engine_time_reaches_them(){
+        let span = Span::none();
+        let clock = SimulatedClock::new(1_000);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(Box::new(clock.clone()));
+        engine.register_security(Instrument::new(1, "TEST")).unwrap();
+        engine.schedule_transition(1, 3_000, TradingState::Closed).unwrap();
+        engine.schedule_transition(1, 2_000, TradingState::ClosingAuction).unwrap();
+        engine.schedule_transition(1, 3_000, TradingState::PreOpen).unwrap();
+        // can't be made from the closing auction it finds
+        engine.schedule_transition(1, 2_500, TradingState::Continuous).unwrap();
+        assert_eq!(engine.scheduled_transitions(1).unwrap(), [
+            (2_000, TradingState::ClosingAuction), (2_500, TradingState::Continuous), (3_000, TradingState::Closed), (3_000, TradingState::PreOpen)
+        ]);
+        assert!(engine.run_schedule().unwrap().is_empty());
+
+        // the first command once due makes the change before its own work
+        clock.set(2_000);
+        let outcome = engine.match_order(limit_order(1, true, 100, 5), &span).unwrap();
+        assert!(outcome.fills.is_empty());
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::ClosingAuction);
+        let fired = engine.run_schedule().unwrap();
+        assert_eq!(fired.iter().map(|transition| (transition.to, transition.sequence, transition.timestamp)).collect::<Vec<_>>(), [(TradingState::ClosingAuction, outcome.sequence, 2_000)]);
+
+        // with nothing coming in, run_schedule makes them. changes due together go in the order scheduled
+        clock.set(3_000);
+        let fired : Vec<(TradingState, TradingState)> = engine.run_schedule().unwrap().iter().map(|transition| (transition.from, transition.to)).collect();
+        assert_eq!(fired, [(TradingState::ClosingAuction, TradingState::Closed), (TradingState::Closed, TradingState::PreOpen)]);
+        assert!(engine.scheduled_transitions(1).unwrap().is_empty());
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::PreOpen);
+    }
+
+    #[test]
+    fn a_cleared_schedule_changes_nothing(){
+        let clock = SimulatedClock::new(1_000);
+        let mut engine = MatchingEngine::new();
+        engine.set_clock(Box::new(clock.clone()));
+        engine.register_security(Instrument::new(1, "TEST")).unwrap();
+        engine.schedule_transition(1, 2_000, TradingState::Closed).unwrap();
+        engine.clear_schedule(1).unwrap();
+
+        clock.set(2_000);
+        assert!(engine.run_schedule().unwrap().is_empty());
+        assert_eq!(engine.trading_state(1).unwrap(), TradingState::Continuous);
+        assert_eq!(engine.schedule_transition(2, 2_000, TradingState::Closed).unwrap_err(), EngineError::UnknownSecurity(2));
     }
 }
@@ -1,4 +1,15 @@
 pub mod orderbook;
 pub mod types;
 pub mod matching_engine;
-pub mod tracing;
\ No newline at end of file
+pub mod tracing;
+pub mod error;
+pub mod journal;
+pub mod snapshot;
+pub mod clock;
+pub mod order_status;
+pub mod risk;
+pub mod position;
+pub mod auction;
+pub mod trading_state;
+#[cfg(test)]
+mod test_support;
\ No newline at end of file
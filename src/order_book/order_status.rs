@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use crate::order_book::error::EngineError;
+use crate::order_book::types::{EngineNewOrder, OrderState, TimeInForce};
+
+// how many filled/cancelled orders stay queryable unless configured otherwise
+pub const DEFAULT_TERMINAL_RETENTION : usize = 10_000;
+
+// what the engine remembers about an order beyond the book itself. live orders are kept until
+// they finish, finished ones only for the last `retention` orders to finish.
+#[derive(Debug, Clone)]
+pub struct TrackedOrder{
+    pub owner_id : u64,
+    pub client_order_id : Option<String>,
+    pub session_id : Option<u64>,
+    pub time_in_force : TimeInForce,
+    pub is_buy_side : bool,
+    pub price : Option<u32>,
+    pub initial_quantity : u32,
+    pub filled_quantity : u32,
+    pub state : OrderState
+}
+
+#[derive(Debug)]
+pub struct OrderTracker{
+    pub(crate) orders : HashMap<(u32, u64), TrackedOrder>, // keyed by (security id, order id)
+    pub(crate) terminal : VecDeque<(u32, u64)>, // finished orders, oldest first
+    pub(crate) retention : usize,
+    pub(crate) client_orders : HashMap<(u64, String), (u32, u64)> // (owner id, client order id) -> key of the live order carrying it
+}
+
+impl Default for OrderTracker {
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl OrderTracker {
+    pub fn new() -> Self{
+        Self { orders : HashMap::new(), terminal : VecDeque::new(), retention : DEFAULT_TERMINAL_RETENTION, client_orders : HashMap::new() }
+    }
+
+    pub fn get(&self, security_id : u32, order_id : u64) -> Option<&TrackedOrder>{
+        self.orders.get(&(security_id, order_id))
+    }
+
+    // (security id, order id, side) of the owner's live order carrying `client_order_id`
+    pub fn client_order(&self, owner_id : u64, client_order_id : &str) -> Option<(u32, u64, bool)>{
+        let (security_id, order_id) = *self.client_orders.get(&(owner_id, client_order_id.to_string()))?;
+        let tracked = self.get(security_id, order_id)?;
+        Some((security_id, order_id, tracked.is_buy_side))
+    }
+
+    // a client order id can only be reused once the order carrying it is done
+    pub fn check_client_order_id(&self, order : &EngineNewOrder) -> Result<(), EngineError>{
+        let Some(client_order_id) = order.client_order_id.as_ref() else {
+            return Ok(());
+        };
+        match self.client_orders.get(&(order.owner_id, client_order_id.clone())) {
+            Some(key) if *key != (order.security_id, order.engine_order_id) => {
+                Err(EngineError::DuplicateClientOrderId { owner_id : order.owner_id, client_order_id : client_order_id.clone() })
+            }
+            _ => Ok(())
+        }
+    }
+
+    // live orders entered through the session, as (security id, order id, side, time in force)
+    // sorted by security id then order id
+    pub fn session_orders(&self, session_id : u64) -> Vec<(u32, u64, bool, TimeInForce)>{
+        let mut orders : Vec<(u32, u64, bool, TimeInForce)> = self.orders.iter()
+            .filter(|(_, tracked)| tracked.state == OrderState::Live && tracked.session_id == Some(session_id))
+            .map(|((security_id, order_id), tracked)| (*security_id, *order_id, tracked.is_buy_side, tracked.time_in_force))
+            .collect();
+        orders.sort_unstable_by_key(|(security_id, order_id, _, _)| (*security_id, *order_id));
+        orders
+    }
+
+    // live day orders of the security as (order id, side), by order id
+    pub fn day_orders(&self, security_id : u32) -> Vec<(u64, bool)>{
+        let mut orders : Vec<(u64, bool)> = self.orders.iter()
+            .filter(|((order_security_id, _), tracked)| {
+                *order_security_id == security_id && tracked.state == OrderState::Live && tracked.time_in_force == TimeInForce::Day
+            })
+            .map(|((_, order_id), tracked)| (*order_id, tracked.is_buy_side))
+            .collect();
+        orders.sort_unstable();
+        orders
+    }
+
+    // the order is (again) live with these terms. an order re-entering after a modify keeps
+    // what it has filled so far, its client order id and its session.
+    pub fn accept(&mut self, order : &EngineNewOrder){
+        let key = (order.security_id, order.engine_order_id);
+        let (filled_quantity, previous_client_order_id, previous_session_id) = match self.orders.get(&key) {
+            Some(tracked) if tracked.state == OrderState::Live => (tracked.filled_quantity, tracked.client_order_id.clone(), tracked.session_id),
+            _ => (0, None, None)
+        };
+        let client_order_id = order.client_order_id.clone().or(previous_client_order_id);
+        if let Some(client_order_id) = client_order_id.as_ref() {
+            self.client_orders.insert((order.owner_id, client_order_id.clone()), key);
+        }
+        self.orders.insert(key, TrackedOrder {
+            owner_id : order.owner_id,
+            client_order_id,
+            session_id : order.session_id.or(previous_session_id),
+            time_in_force : order.time_in_force,
+            is_buy_side : order.is_buy_side,
+            price : order.price,
+            initial_quantity : order.initial_quantity,
+            filled_quantity,
+            state : OrderState::Live
+        });
+    }
+
+    pub fn fill(&mut self, security_id : u32, order_id : u64, quantity : u32){
+        if let Some(tracked) = self.orders.get_mut(&(security_id, order_id)) {
+            tracked.filled_quantity += quantity;
+        }
+    }
+
+    pub fn set_initial_quantity(&mut self, security_id : u32, order_id : u64, initial_quantity : u32){
+        if let Some(tracked) = self.orders.get_mut(&(security_id, order_id)) {
+            tracked.initial_quantity = initial_quantity;
+        }
+    }
+
+    pub fn is_live(&self, security_id : u32, order_id : u64) -> bool{
+        self.get(security_id, order_id).is_some_and(|tracked| tracked.state == OrderState::Live)
+    }
+
+    // moves a live order into a terminal state, forgetting the oldest finished order once
+    // more than `retention` are kept
+    pub fn finish(&mut self, security_id : u32, order_id : u64, state : OrderState){
+        let Some(tracked) = self.orders.get_mut(&(security_id, order_id)) else {
+            return;
+        };
+        if tracked.state != OrderState::Live {
+            return;
+        }
+        tracked.state = state;
+        if let Some(client_order_id) = tracked.client_order_id.clone() {
+            let client_key = (tracked.owner_id, client_order_id);
+            if self.client_orders.get(&client_key) == Some(&(security_id, order_id)) {
+                self.client_orders.remove(&client_key);
+            }
+        }
+        self.terminal.push_back((security_id, order_id));
+        self.evict();
+    }
+
+    pub fn set_retention(&mut self, retention : usize){
+        self.retention = retention;
+        self.evict();
+    }
+
+    fn evict(&mut self){
+        while self.terminal.len() > self.retention {
+            let Some(key) = self.terminal.pop_front() else {
+                break;
+            };
+            // the id may have been reused by a live order since
+            if self.orders.get(&key).is_some_and(|tracked| tracked.state != OrderState::Live) {
+                self.orders.remove(&key);
+            }
+        }
+    }
+}
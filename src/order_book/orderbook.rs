@@ -1,16 +1,87 @@
-use std::collections::{BTreeMap, HashMap, btree_map::Entry};
-use anyhow::anyhow;
+use std::collections::{BTreeMap, HashMap, VecDeque, btree_map::Entry};
 use tracing::instrument;
-use crate::order_book::types::{BookDepth, EngineCancelOrder, EngineModifyOrder, ModifyOutcome, OrderNode, PriceLevel, PriceLevelDepth};
+use crate::order_book::auction::CallAuction;
+use crate::order_book::error::EngineError;
+use crate::order_book::position::Exposure;
+use crate::order_book::trading_state::TradingState;
+use crate::order_book::types::{BookDepth, EngineCancelOrder, EngineModifyOrder, EngineNewOrder, LevelAction, MassCancelFilter, MatchingAlgorithm, ModifyOutcome, OrderAction, OrderNode, OrderUpdate, PriceLevel, PriceLevelDepth, QueuePosition, SelfTradePrevention};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct OrderBook{
     pub ask : HalfBook,
-    pub bid : HalfBook
+    pub bid : HalfBook,
+    pub triggers : TriggerBook,
+    pub last_trade_price : Option<u32>,
+    pub state : TradingState,
+    pub schedule : Vec<(u64, TradingState)>, // (engine time, state) still to come, earliest first
+    pub order_events : Vec<OrderUpdate>, // every change to a resting order since the last drain, in order
+    pub published_bbo : (Option<PriceLevelDepth>, Option<PriceLevelDepth>), // (bid, ask) as last sent to market data
+    pub queue_watches : BTreeMap<u64, (bool, QueuePosition)>, // order id -> (side, position as last sent to market data)
+    pub matching_algorithm : MatchingAlgorithm, // the instrument's, kept here for the matching loop
+    pub lot_size : u32, // the instrument's, pro-rata shares are whole lots
+    pub auction : Option<CallAuction> // Some while orders are collected for an uncross instead of matched
 }
 impl OrderBook {
     pub fn new () -> Self{
-        Self { ask : HalfBook::new(), bid : HalfBook::new() }
+        Self { ask : HalfBook::new(), bid : HalfBook::new(), triggers : TriggerBook::new(), last_trade_price : None, state : TradingState::Continuous, schedule : Vec::new(), order_events : Vec::new(), published_bbo : (None, None), queue_watches : BTreeMap::new(), matching_algorithm : MatchingAlgorithm::Fifo, lot_size : 1, auction : None }
+    }
+
+    pub fn queue_position(&self, order_id : u64, is_buy_side : bool) -> Option<QueuePosition>{
+        if is_buy_side { self.bid.queue_position(order_id) } else { self.ask.queue_position(order_id) }
+    }
+
+    pub fn best_bid(&self) -> Option<PriceLevelDepth>{
+        self.bid.price_map.last_key_value().map(|(price, price_level)| PriceLevelDepth { price_level : *price, quantity : price_level.total_quantity })
+    }
+
+    pub fn best_ask(&self) -> Option<PriceLevelDepth>{
+        self.ask.price_map.first_key_value().map(|(price, price_level)| PriceLevelDepth { price_level : *price, quantity : price_level.total_quantity })
+    }
+
+    // None unless both sides have orders
+    pub fn spread(&self) -> Option<u32>{
+        Some(self.best_ask()?.price_level.saturating_sub(self.best_bid()?.price_level))
+    }
+
+    pub fn mid(&self) -> Option<f64>{
+        Some((self.best_ask()?.price_level as f64 + self.best_bid()?.price_level as f64) / 2.0)
+    }
+
+    // the orders a mass cancel with this filter takes out (its security id is not looked at
+    // here): bids best first then asks best first, each level in queue order, then the
+    // untriggered stops, matched on their stop price.
+    pub fn mass_cancel_selection(&self, security_id : u32, filter : &MassCancelFilter) -> Vec<EngineCancelOrder>{
+        let sides : Vec<bool> = [true, false].into_iter().filter(|side| filter.is_buy_side.is_none_or(|wanted| wanted == *side)).collect();
+        let mut selected = Vec::new();
+        for &is_buy_side in &sides {
+            let half = if is_buy_side { &self.bid } else { &self.ask };
+            let mut levels = half.orders_in_band(filter.owner_id, filter.min_price, filter.max_price);
+            if is_buy_side {
+                levels.reverse();
+            }
+            selected.extend(levels.into_iter().flatten().map(|order_id| EngineCancelOrder { order_id, security_id, is_buy_side }));
+        }
+        for &is_buy_side in &sides {
+            let stops = self.triggers.stops_in_band(is_buy_side, filter.owner_id, filter.min_price, filter.max_price);
+            selected.extend(stops.into_iter().map(|order_id| EngineCancelOrder { order_id, security_id, is_buy_side }));
+        }
+        // market orders waiting for an auction have no price, a band never selects them
+        if let Some(auction) = &self.auction && filter.min_price.is_none() && filter.max_price.is_none() {
+            selected.extend(auction.market_orders.iter()
+                .filter(|order| sides.contains(&order.is_buy_side) && filter.owner_id.is_none_or(|owner_id| owner_id == order.owner_id))
+                .map(|order| EngineCancelOrder { order_id : order.engine_order_id, security_id, is_buy_side : order.is_buy_side }));
+        }
+        selected
+    }
+
+    // an order deleted and added back within the same command (a modify that didn't trade)
+    // goes out as a single replace
+    fn push_add(&mut self, order_id : u64, is_buy_side : bool, price : u32, quantity : u32){
+        if let Some(last) = self.order_events.last_mut() && last.order_id == order_id && last.action == OrderAction::Delete {
+            *last = OrderUpdate::new(order_id, is_buy_side, price, quantity, OrderAction::Replace);
+            return;
+        }
+        self.order_events.push(OrderUpdate::new(order_id, is_buy_side, price, quantity, OrderAction::Add));
     }
 
     #[instrument( // used for auto span creation & drop.
@@ -22,12 +93,15 @@ impl OrderBook {
         ),
         err
     )]
-    pub fn create_buy_order(&mut self, order_id : u64, resting_order : OrderNode) -> Result<usize, anyhow::Error>{
+    pub fn create_buy_order(&mut self, order_id : u64, resting_order : OrderNode) -> Result<usize, EngineError>{
         
         let mut order = resting_order;
         let order_quantity = order.current_quantity;
         let price = order.market_limit;
         let order_id = resting_order.order_id;
+        self.bid.touch(price);
+        HalfBook::add_exposure(&mut self.bid.exposure, order.owner_id, price, order_quantity + order.hidden_quantity);
+        self.push_add(order_id, true, price, order_quantity);
 
         match self.bid.price_map.entry(price){ // here price is not moved, bcoz u32 implements Copy
             Entry::Occupied(mut entry) => {
@@ -105,11 +179,14 @@ impl OrderBook {
         ),
         err
     )]
-    pub fn create_sell_order(&mut self, order_id : u64, resting_order : OrderNode) -> Result<usize, anyhow::Error>{
+    pub fn create_sell_order(&mut self, order_id : u64, resting_order : OrderNode) -> Result<usize, EngineError>{
         let mut order = resting_order;
         let order_quantity = order.current_quantity;
         let price = order.market_limit;
         let order_id = resting_order.order_id;
+        self.ask.touch(price);
+        HalfBook::add_exposure(&mut self.ask.exposure, order.owner_id, price, order_quantity + order.hidden_quantity);
+        self.push_add(order_id, false, price, order_quantity);
 
         match self.ask.price_map.entry(price){
             Entry::Occupied(mut entry) => {
@@ -186,35 +263,41 @@ impl OrderBook {
         ),
         err
     )]
-    pub fn cancel_order(&mut self, order_id : u64, order : EngineCancelOrder) -> Result<(), anyhow::Error>{
+    pub fn cancel_order(&mut self, order_id : u64, order : EngineCancelOrder) -> Result<(), EngineError>{
         if order.is_buy_side {
-            let existing_index = self.bid.order_registry.get(&order_id);
+            let existing_index = self.bid.order_registry.get(&order_id).copied();
             if existing_index.is_none(){
-                return Err(anyhow!("index for the order doesn't exist in the local registry"));
+                return Err(EngineError::UnknownOrder(order_id));
             }
+            self.bid.release_exposure(existing_index.unwrap());
                             let (prev, next, old_price, old_quantity) = {
-                                match self.bid.order_pool[*existing_index.unwrap()].as_ref(){
+                                match self.bid.order_pool[existing_index.unwrap()].as_ref(){
                                     Some(node) => {
                                         (node.prev, node.next, node.market_limit, node.current_quantity)
                                     }
                                     None => { 
-                                        return Err(anyhow!("order node doesn't exist at index for cancellation"));
+                                        return Err(EngineError::InvariantViolation("order node doesn't exist at index for cancellation"));
                                     }
                                 }
                             };
+                    self.bid.touch(old_price);
+                    self.order_events.push(OrderUpdate::new(order_id, true, old_price, old_quantity, OrderAction::Delete));
                     if let Some(price_level) = self.bid.price_map.get_mut(&old_price){
 
                         if price_level.head.is_some() && price_level.tail.is_some(){
-                            if *existing_index.unwrap() == price_level.head.unwrap() && *existing_index.unwrap() == price_level.tail.unwrap(){
-                                self.bid.order_pool[*existing_index.unwrap()] = None;
+                            if existing_index.unwrap() == price_level.head.unwrap() && existing_index.unwrap() == price_level.tail.unwrap(){
+                                self.bid.order_pool[existing_index.unwrap()] = None;
                                 price_level.head = None;
                                 price_level.tail = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.bid.free_list.push(*existing_index.unwrap());
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.bid.free_list.push(existing_index.unwrap());
+                                self.bid.order_registry.remove(&order_id);
+                                // last order at this price, the level goes with it
+                                self.bid.price_map.remove(&old_price);
                                 return Ok(());
                             }
-                            else if *existing_index.unwrap() == price_level.tail.unwrap() {
+                            else if existing_index.unwrap() == price_level.tail.unwrap() {
                                if let Some(prev_index) = prev{
                                     if let Some(possible_prev_node) = self.bid.order_pool.get_mut(prev_index){
                                         if let Some(prev_node) = possible_prev_node{
@@ -222,16 +305,17 @@ impl OrderBook {
                                             price_level.tail = Some(prev_index);
                                         }           
                                     }
-                                self.bid.order_pool[*existing_index.unwrap()] = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.bid.free_list.push(*existing_index.unwrap());
+                                self.bid.order_pool[existing_index.unwrap()] = None;
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.bid.free_list.push(existing_index.unwrap());
+                                self.bid.order_registry.remove(&order_id);
                                 return Ok(()); 
                                 } else {
-                                    return Err(anyhow!("no prev node found for deletion of tail node"));
+                                    return Err(EngineError::InvariantViolation("no prev node found for deletion of tail node"));
                                 }
                             }
-                            else if *existing_index.unwrap() == price_level.head.unwrap() {
+                            else if existing_index.unwrap() == price_level.head.unwrap() {
                                 if let Some(next_index) = next{
                                     if let Some(possible_next_node) = self.bid.order_pool.get_mut(next_index){
                                         if let Some(next_node) = possible_next_node{
@@ -239,13 +323,14 @@ impl OrderBook {
                                             price_level.head = Some(next_index);
                                         }
                                     }
-                                    self.bid.order_pool[*existing_index.unwrap()] = None;
-                                    price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                    price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                    self.bid.free_list.push(*existing_index.unwrap());
+                                    self.bid.order_pool[existing_index.unwrap()] = None;
+                                    price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                    price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                    self.bid.free_list.push(existing_index.unwrap());
+                                    self.bid.order_registry.remove(&order_id);
                                     return Ok(());
                                 } else {
-                                    return Err(anyhow!("no next node found for deletion of head node"));
+                                    return Err(EngineError::InvariantViolation("no next node found for deletion of head node"));
                                 }                    
                             }
                             else {
@@ -257,7 +342,7 @@ impl OrderBook {
                                     }
                                 }
                                 else {
-                                    return Err(anyhow!("no prev node found for deletion of middle node"));
+                                    return Err(EngineError::InvariantViolation("no prev node found for deletion of middle node"));
                                 }
                                 if let Some(next_index) = next{
                                     if let Some(possible_next_node) = self.bid.order_pool.get_mut(next_index){
@@ -267,49 +352,56 @@ impl OrderBook {
                                     }
                                 }
                                 else {
-                                    return Err(anyhow!("no next node found for deletion of middle node"));
+                                    return Err(EngineError::InvariantViolation("no next node found for deletion of middle node"));
                                 }
-                                self.bid.order_pool[*existing_index.unwrap()] = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.bid.free_list.push(*existing_index.unwrap());
+                                self.bid.order_pool[existing_index.unwrap()] = None;
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.bid.free_list.push(existing_index.unwrap());
+                                self.bid.order_registry.remove(&order_id);
                                 return Ok(());
                             }
                         } else {
                             self.bid.price_map.remove(&old_price);
-                            return Err(anyhow::anyhow!("head and tail corrupted so deleted"));
+                            return Err(EngineError::InvariantViolation("head and tail corrupted so deleted"));
                         }
                     } else {
-                        return Err(anyhow!("unable to get old price node to perform cancellation"));
+                        return Err(EngineError::InvariantViolation("unable to get old price node to perform cancellation"));
                     }
         } else {
-            let existing_index = self.ask.order_registry.get(&order_id);
+            let existing_index = self.ask.order_registry.get(&order_id).copied();
             if existing_index.is_none(){
-                return Err(anyhow!("index for the order doesn't exist in the local registry"));
+                return Err(EngineError::UnknownOrder(order_id));
             }
+            self.ask.release_exposure(existing_index.unwrap());
                     let (prev, next, old_price, old_quantity) = {
-                                match self.ask.order_pool[*existing_index.unwrap()].as_ref(){
+                                match self.ask.order_pool[existing_index.unwrap()].as_ref(){
                                     Some(node) => {
                                         (node.prev, node.next, node.market_limit, node.current_quantity)
                                     }
                                     None => {
-                                        return Err(anyhow!("order node doesn't exist at index for cancellation"));
+                                        return Err(EngineError::InvariantViolation("order node doesn't exist at index for cancellation"));
                                     }
                                 }
                             };
+                    self.ask.touch(old_price);
+                    self.order_events.push(OrderUpdate::new(order_id, false, old_price, old_quantity, OrderAction::Delete));
                     if let Some(price_level) = self.ask.price_map.get_mut(&old_price){
 
                         if price_level.head.is_some() && price_level.tail.is_some(){
-                            if *existing_index.unwrap() == price_level.head.unwrap() && *existing_index.unwrap() == price_level.tail.unwrap(){
-                                self.ask.order_pool[*existing_index.unwrap()] = None;
+                            if existing_index.unwrap() == price_level.head.unwrap() && existing_index.unwrap() == price_level.tail.unwrap(){
+                                self.ask.order_pool[existing_index.unwrap()] = None;
                                 price_level.head = None;
                                 price_level.tail = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.ask.free_list.push(*existing_index.unwrap());
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.ask.free_list.push(existing_index.unwrap());
+                                self.ask.order_registry.remove(&order_id);
+                                // last order at this price, the level goes with it
+                                self.ask.price_map.remove(&old_price);
                                 return Ok(());
                             }
-                            else if *existing_index.unwrap() == price_level.tail.unwrap() {
+                            else if existing_index.unwrap() == price_level.tail.unwrap() {
                                if let Some(prev_index) = prev{
                                     if let Some(possible_prev_node) = self.ask.order_pool.get_mut(prev_index){
                                         if let Some(prev_node) = possible_prev_node{
@@ -317,16 +409,17 @@ impl OrderBook {
                                             price_level.tail = Some(prev_index);
                                         }           
                                     }
-                                self.ask.order_pool[*existing_index.unwrap()] = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.ask.free_list.push(*existing_index.unwrap());
+                                self.ask.order_pool[existing_index.unwrap()] = None;
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.ask.free_list.push(existing_index.unwrap());
+                                self.ask.order_registry.remove(&order_id);
                                 return Ok(()); 
                                 } else {
-                                    return Err(anyhow!("no prev node found for deletion of tail node"));
+                                    return Err(EngineError::InvariantViolation("no prev node found for deletion of tail node"));
                                 }
                             }
-                            else if *existing_index.unwrap() == price_level.head.unwrap() {
+                            else if existing_index.unwrap() == price_level.head.unwrap() {
                                 if let Some(next_index) = next{
                                     if let Some(possible_next_node) = self.ask.order_pool.get_mut(next_index){
                                         if let Some(next_node) = possible_next_node{
@@ -334,13 +427,14 @@ impl OrderBook {
                                             price_level.head = Some(next_index);
                                         }
                                     }
-                                    self.ask.order_pool[*existing_index.unwrap()] = None;
-                                    price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                    price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                    self.ask.free_list.push(*existing_index.unwrap());
+                                    self.ask.order_pool[existing_index.unwrap()] = None;
+                                    price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                    price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                    self.ask.free_list.push(existing_index.unwrap());
+                                    self.ask.order_registry.remove(&order_id);
                                     return Ok(());
                                 } else {
-                                    return Err(anyhow!("no next node found for deletion of head node"));
+                                    return Err(EngineError::InvariantViolation("no next node found for deletion of head node"));
                                 }                    
                             }
                             else {
@@ -352,7 +446,7 @@ impl OrderBook {
                                     }
                                 }
                                 else {
-                                    return Err(anyhow!("no prev node found for deletion of middle node"));
+                                    return Err(EngineError::InvariantViolation("no prev node found for deletion of middle node"));
                                 }
                                 if let Some(next_index) = next{
                                     if let Some(possible_next_node) = self.ask.order_pool.get_mut(next_index){
@@ -362,20 +456,21 @@ impl OrderBook {
                                     }
                                 }
                                 else {
-                                    return Err(anyhow!("no next node found for deletion of middle node"));
+                                    return Err(EngineError::InvariantViolation("no next node found for deletion of middle node"));
                                 }
-                                self.ask.order_pool[*existing_index.unwrap()] = None;
-                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(anyhow!("error in subtracting total qty in order cancellation"))?;
-                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(anyhow!("error is subtracting order qty in cancellation"))?;
-                                self.ask.free_list.push(*existing_index.unwrap());
+                                self.ask.order_pool[existing_index.unwrap()] = None;
+                                price_level.total_quantity = price_level.total_quantity.checked_sub(old_quantity).ok_or(EngineError::InvariantViolation("error in subtracting total qty in order cancellation"))?;
+                                price_level.order_count = price_level.order_count.checked_sub(1).ok_or(EngineError::InvariantViolation("error is subtracting order qty in cancellation"))?;
+                                self.ask.free_list.push(existing_index.unwrap());
+                                self.ask.order_registry.remove(&order_id);
                                 return Ok(());
                             }
                         } else {
                             self.ask.price_map.remove(&old_price);
-                            return Err(anyhow::anyhow!("head and tail corrupted so deleted"));
+                            return Err(EngineError::InvariantViolation("head and tail corrupted so deleted"));
                         }
                     } else {
-                        return Err(anyhow!("unable to get old price node to perform cancellation"));
+                        return Err(EngineError::InvariantViolation("unable to get old price node to perform cancellation"));
                     }
         }
     }
@@ -388,99 +483,91 @@ impl OrderBook {
         ),
         err
     )]
-    pub fn modify_order(&mut self, order_id : u64, order : EngineModifyOrder) -> Result<Option<ModifyOutcome>, anyhow::Error>{
+    pub fn modify_order(&mut self, order_id : u64, order : EngineModifyOrder) -> Result<Option<ModifyOutcome>, EngineError>{
         if order.is_buy_side{
-            let existing_index = self.bid.order_registry.get(&order_id);
+            let existing_index = self.bid.order_registry.get(&order_id).copied();
             if existing_index.is_none(){
-                return Err(anyhow!("index for the order doesn't exist in the local registry"));
+                return Err(EngineError::UnknownOrder(order_id));
             }
                 let (old_initial_qty, old_current_qty, old_price) = {
-                    match self.bid.order_pool[*existing_index.unwrap()].as_ref(){
+                    match self.bid.order_pool[existing_index.unwrap()].as_ref(){
                         Some(node) => {
-                            (node.initial_quantity, node.current_quantity, node.market_limit)
+                            (node.initial_quantity, node.current_quantity + node.hidden_quantity, node.market_limit)
                         }
                         None => {
-                            return Err(anyhow!("order node not found at index for modification (buy)"));
+                            return Err(EngineError::InvariantViolation("order node not found at index for modification (buy)"));
                         }
                     }
                 };
                 if let Some(new_price) = order.new_price && let Some(new_qty) = order.new_quantity{
                     if new_price != old_price{
-                        if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                            return Ok(Some(ModifyOutcome::Both {new_price, new_initial_qty: new_qty, old_current_qty }));
-                            }
-                        }
+                        self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                        return Ok(Some(ModifyOutcome::Both {new_price, new_initial_qty: new_qty, old_current_qty }));
+                    }
                     return Ok(None);
                 } else if let Some(new_qty) = order.new_quantity  {
                     if new_qty > old_initial_qty{
-                        if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                            return Ok(Some(ModifyOutcome::Requantized {old_price, new_initial_qty: new_qty, old_current_qty }))
-                        }
-                        return Ok(None);
+                        self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                        return Ok(Some(ModifyOutcome::Requantized {old_price, new_initial_qty: new_qty, old_current_qty }))
                     }
                     else {
-                        match self.bid.order_pool[*existing_index.unwrap()].as_mut(){
+                        match self.bid.order_pool[existing_index.unwrap()].as_mut(){
                             Some(order_node) => {
                                 order_node.initial_quantity = new_qty;
                                 return Ok(Some(ModifyOutcome::Inplace));
                             }
                             None => {
-                                return Err(anyhow!("couldn't find order node to modify qty in-place (buy)"));
+                                return Err(EngineError::InvariantViolation("couldn't find order node to modify qty in-place (buy)"));
                             }
                         }
                     }
                 } else {
-                    if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                        return Ok(Some(ModifyOutcome::Repriced {new_price : order.new_price.unwrap(), old_initial_qty, old_current_qty }));
-                    }
-                    return Ok(None);
+                    let new_price = order.new_price.ok_or(EngineError::UnsupportedOrder("modify without a new price or a new quantity"))?;
+                    self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                    return Ok(Some(ModifyOutcome::Repriced {new_price, old_initial_qty, old_current_qty }));
                 }
         } else {
-            let existing_index = self.ask.order_registry.get(&order_id);
+            let existing_index = self.ask.order_registry.get(&order_id).copied();
             if existing_index.is_none(){
-                return Err(anyhow!("index for the order doesn't exist in the local registry"));
+                return Err(EngineError::UnknownOrder(order_id));
             }
                 let (old_initial_qty, old_current_qty, old_price) = {
-                    match self.ask.order_pool[*existing_index.unwrap()].as_ref(){
+                    match self.ask.order_pool[existing_index.unwrap()].as_ref(){
                         Some(node) => {
-                            (node.initial_quantity, node.current_quantity, node.market_limit)
+                            (node.initial_quantity, node.current_quantity + node.hidden_quantity, node.market_limit)
                         }
                         None => {
-                            return Err(anyhow!("order node not found at index for modification (sell)"));
+                            return Err(EngineError::InvariantViolation("order node not found at index for modification (sell)"));
                         }
                     }
                 };
 
                 if let Some(new_price) = order.new_price && let Some(new_qty) = order.new_quantity{
                     if new_price != old_price{
-                        if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                           return Ok(Some(ModifyOutcome::Requantized {old_price, new_initial_qty: new_qty, old_current_qty }))
-                        }
+                        self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id,security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                        return Ok(Some(ModifyOutcome::Both {new_price, new_initial_qty: new_qty, old_current_qty }));
                     }
                     return Ok(None);
                 } else if let Some(new_qty) = order.new_quantity  {
                     if new_qty > old_initial_qty{
-                        if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                            return Ok(Some(ModifyOutcome::Requantized { old_price, new_initial_qty: new_qty, old_current_qty }))
-                        }
-                        return Ok(None);
+                        self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                        return Ok(Some(ModifyOutcome::Requantized { old_price, new_initial_qty: new_qty, old_current_qty }))
                     }
                     else {
-                        match self.ask.order_pool[*existing_index.unwrap()].as_mut(){
+                        match self.ask.order_pool[existing_index.unwrap()].as_mut(){
                             Some(order_node) => {
                                 order_node.initial_quantity = new_qty;
                                 return Ok(Some(ModifyOutcome::Inplace));
                             }
                             None => {
-                                return Err(anyhow!("couldn't find order node to modify qty in-place (sell)"));
+                                return Err(EngineError::InvariantViolation("couldn't find order node to modify qty in-place (sell)"));
                             }
                         }
                     }
                 }else {
-                    if let Ok(_) = self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,}){
-                        return Ok(Some(ModifyOutcome::Repriced { new_price : order.new_price.unwrap(), old_initial_qty, old_current_qty }));
-                    }
-                    return Ok(None);
+                    let new_price = order.new_price.ok_or(EngineError::UnsupportedOrder("modify without a new price or a new quantity"))?;
+                    self.cancel_order(order_id ,EngineCancelOrder { order_id : order.order_id, security_id : order.security_id, is_buy_side: order.is_buy_side,})?;
+                    return Ok(Some(ModifyOutcome::Repriced { new_price, old_initial_qty, old_current_qty }));
                 }
         }
     }
@@ -490,10 +577,11 @@ impl OrderBook {
         skip(self),
         err
     )]
-    pub fn depth(&self, levels_count : Option<u32>) -> Result<BookDepth, anyhow::Error>{
+    pub fn depth(&self, levels_count : Option<u32>) -> Result<BookDepth, EngineError>{
 
-        let ask_iter = self.ask.price_map.iter().rev();
-        let bid_iter = self.bid.price_map.iter();
+        // both sides start at the best price
+        let ask_iter = self.ask.price_map.iter();
+        let bid_iter = self.bid.price_map.iter().rev();
 
         let ask_depth : Vec<_> = match levels_count {
             Some(n) => ask_iter.take(n as usize)
@@ -523,16 +611,442 @@ impl OrderBook {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct HalfBook{
     pub price_map : BTreeMap<u32, PriceLevel>,
     pub order_registry : HashMap<u64, usize>,
     pub order_pool : Vec<Option<OrderNode>>,
     pub free_list : Vec<usize>, // we're storing the free indices from the price level to keep the cache lines hot.
+    pub touched_levels : BTreeMap<u32, Option<(u32, u32)>>, // (quantity, order count) of each level before its first change since the last drain, None if it didn't exist
+    pub exposure : HashMap<u64, Exposure> // owner id -> what its resting orders add up to, owners without any are left out
 }
 
 impl HalfBook {
     pub fn new() -> Self{
-        Self { price_map: BTreeMap::new(), order_registry : HashMap::new(), order_pool: Vec::new(), free_list: Vec::new()}
+        Self { price_map: BTreeMap::new(), order_registry : HashMap::new(), order_pool: Vec::new(), free_list: Vec::new(), touched_levels: BTreeMap::new(), exposure: HashMap::new()}
     }
-}
\ No newline at end of file
+
+    pub fn owner_exposure(&self, owner_id : u64) -> Exposure{
+        self.exposure.get(&owner_id).copied().unwrap_or_default()
+    }
+
+    // assoc fns like `touch_level`, so the sweep can call them on the split-up fields
+    pub fn add_exposure(exposure : &mut HashMap<u64, Exposure>, owner_id : u64, price : u32, quantity : u32){
+        let owner_exposure = exposure.entry(owner_id).or_default();
+        owner_exposure.quantity += quantity as u64;
+        owner_exposure.notional += price as u64 * quantity as u64;
+    }
+
+    pub fn remove_exposure(exposure : &mut HashMap<u64, Exposure>, owner_id : u64, price : u32, quantity : u32){
+        if let Some(owner_exposure) = exposure.get_mut(&owner_id) {
+            owner_exposure.quantity = owner_exposure.quantity.saturating_sub(quantity as u64);
+            owner_exposure.notional = owner_exposure.notional.saturating_sub(price as u64 * quantity as u64);
+            if owner_exposure.quantity == 0 {
+                exposure.remove(&owner_id);
+            }
+        }
+    }
+
+    // the whole remaining quantity of the order at `idx` is leaving the book
+    pub fn release_exposure(&mut self, idx : usize){
+        if let Some(Some(order_node)) = self.order_pool.get(idx) {
+            Self::remove_exposure(&mut self.exposure, order_node.owner_id, order_node.market_limit, order_node.current_quantity + order_node.hidden_quantity);
+        }
+    }
+
+    // has to be called before a level is changed so its previous state can be diffed later
+    pub fn touch(&mut self, price : u32){
+        Self::touch_level(&mut self.touched_levels, &self.price_map, price);
+    }
+
+    // same as `touch`, for callers that already hold the fields apart
+    pub fn touch_level(touched_levels : &mut BTreeMap<u32, Option<(u32, u32)>>, price_map : &BTreeMap<u32, PriceLevel>, price : u32){
+        touched_levels.entry(price).or_insert_with(|| {
+            price_map.get(&price).filter(|level| level.order_count > 0).map(|level| (level.total_quantity, level.order_count))
+        });
+    }
+
+    // (price, action, quantity, order count) for every touched level whose state differs from
+    // before it was touched, in price order. levels changed and restored in between are skipped.
+    pub fn drain_level_changes(&mut self) -> Vec<(u32, LevelAction, u32, u32)>{
+        let mut changes = Vec::new();
+        for (price, before) in std::mem::take(&mut self.touched_levels) {
+            let after = self.price_map.get(&price).filter(|level| level.order_count > 0).map(|level| (level.total_quantity, level.order_count));
+            match (before, after) {
+                (None, Some((quantity, order_count))) => changes.push((price, LevelAction::New, quantity, order_count)),
+                (Some(_), None) => changes.push((price, LevelAction::Delete, 0, 0)),
+                (Some(before), Some((quantity, order_count))) if before != (quantity, order_count) => {
+                    changes.push((price, LevelAction::Change, quantity, order_count))
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    pub fn get_order(&self, order_id : u64) -> Option<&OrderNode>{
+        let idx = self.order_registry.get(&order_id)?;
+        self.order_pool.get(*idx)?.as_ref()
+    }
+
+    // ids of the orders resting between the two prices (inclusive), optionally only those of one
+    // owner. one entry per level, lowest price first, each in queue order.
+    pub fn orders_in_band(&self, owner_id : Option<u64>, min_price : Option<u32>, max_price : Option<u32>) -> Vec<Vec<u64>>{
+        let (low, high) = (min_price.unwrap_or(0), max_price.unwrap_or(u32::MAX));
+        if low > high {
+            return Vec::new();
+        }
+        let mut levels = Vec::new();
+        for (_, price_level) in self.price_map.range(low..=high) {
+            let mut level_ids = Vec::new();
+            let mut cursor = price_level.head;
+            while let Some(order_node) = cursor.and_then(|idx| self.order_pool.get(idx)).and_then(|node| node.as_ref()) {
+                if owner_id.is_none_or(|owner_id| owner_id == order_node.owner_id) {
+                    level_ids.push(order_node.order_id);
+                }
+                cursor = order_node.next;
+            }
+            if !level_ids.is_empty() {
+                levels.push(level_ids);
+            }
+        }
+        levels
+    }
+
+    // first order of `owner_id` in the level's queue
+    pub fn find_owner_order(order_pool : &[Option<OrderNode>], price_level : &PriceLevel, owner_id : u64) -> Option<usize>{
+        let mut cursor = price_level.head;
+        while let Some(idx) = cursor {
+            let order_node = order_pool.get(idx)?.as_ref()?;
+            if order_node.owner_id == owner_id {
+                return Some(idx);
+            }
+            cursor = order_node.next;
+        }
+        None
+    }
+
+    // (pool index, quantity) for every order of the level getting part of `quantity`, in queue
+    // order. with top order priority the head is filled first, the rest is split in proportion
+    // to the shown quantities (rounded down to whole lots). shares under `min_allocation` are
+    // dropped, what rounding and dropping leave over goes to the queue first come first served.
+    pub fn pro_rata_allocation(
+        order_pool : &[Option<OrderNode>],
+        price_level : &PriceLevel,
+        quantity : u32,
+        lot_size : u32,
+        top_order_priority : bool,
+        min_allocation : u32
+    ) -> VecDeque<(usize, u32)>{
+        let mut orders : Vec<(usize, u32, u32)> = Vec::new(); // (pool index, shown quantity, allocated)
+        let mut cursor = price_level.head;
+        while let Some(idx) = cursor {
+            let Some(order_node) = order_pool.get(idx).and_then(|node| node.as_ref()) else {
+                break;
+            };
+            orders.push((idx, order_node.current_quantity, 0));
+            cursor = order_node.next;
+        }
+        let mut remaining = quantity;
+        if top_order_priority && let Some((_, shown, allocated)) = orders.first_mut() {
+            *allocated = remaining.min(*shown);
+            remaining -= *allocated;
+        }
+        let open_total : u64 = orders.iter().map(|(_, shown, allocated)| (shown - allocated) as u64).sum();
+        if remaining > 0 && open_total > 0 {
+            let split = (remaining as u64).min(open_total);
+            for (_, shown, allocated) in &mut orders {
+                let share = (split * (*shown - *allocated) as u64 / open_total) as u32;
+                let share = share - share % lot_size.max(1);
+                if share >= min_allocation {
+                    *allocated += share;
+                    remaining -= share;
+                }
+            }
+            for (_, shown, allocated) in &mut orders {
+                let extra = remaining.min(*shown - *allocated);
+                *allocated += extra;
+                remaining -= extra;
+            }
+        }
+        orders.into_iter().filter(|(_, _, allocated)| *allocated > 0).map(|(idx, _, allocated)| (idx, allocated)).collect()
+    }
+
+    // drops an order from its level (the caller has already taken its quantity off the level
+    // total) and hands its pool slot back to the free list.
+    pub fn unlink(
+        order_pool : &mut [Option<OrderNode>],
+        free_list : &mut Vec<usize>,
+        order_registry : &mut HashMap<u64, usize>,
+        price_level : &mut PriceLevel,
+        idx : usize
+    ){
+        let Some(order_node) = order_pool[idx].take() else {
+            return;
+        };
+        free_list.push(idx);
+        order_registry.remove(&order_node.order_id);
+        price_level.order_count = price_level.order_count.saturating_sub(1);
+        Self::splice_out(order_pool, price_level, order_node.prev, order_node.next);
+        if price_level.head.is_none() {
+            price_level.total_quantity = 0;
+            price_level.order_count = 0;
+        }
+    }
+
+    // unlinks an order from wherever it is in its level and appends it behind the current tail
+    pub fn move_to_tail(order_pool : &mut [Option<OrderNode>], price_level : &mut PriceLevel, idx : usize) -> Result<(), EngineError> {
+        let tail_idx = price_level.tail.ok_or(EngineError::InvariantViolation("price level has a head but no tail"))?;
+        if tail_idx == idx {
+            return Ok(());
+        }
+        let (prev, next) = match order_pool[idx].as_ref() {
+            Some(order_node) => (order_node.prev, order_node.next),
+            None => {
+                return Err(EngineError::InvariantViolation("failed to get node from order pool to move it to the tail"));
+            }
+        };
+        Self::splice_out(order_pool, price_level, prev, next);
+        let tail_idx = price_level.tail.ok_or(EngineError::InvariantViolation("price level has a head but no tail"))?;
+        if let Some(Some(tail_order_node)) = order_pool.get_mut(tail_idx) {
+            tail_order_node.next = Some(idx);
+        }
+        if let Some(order_node) = order_pool[idx].as_mut() {
+            order_node.prev = Some(tail_idx);
+            order_node.next = None;
+        }
+        price_level.tail = Some(idx);
+        Ok(())
+    }
+
+    // links the neighbours of a removed order to each other, moving head/tail when it was at an end
+    pub fn splice_out(order_pool : &mut [Option<OrderNode>], price_level : &mut PriceLevel, prev : Option<usize>, next : Option<usize>){
+        match prev {
+            Some(prev_idx) => if let Some(Some(prev_order_node)) = order_pool.get_mut(prev_idx) {
+                prev_order_node.next = next;
+            },
+            None => price_level.head = next
+        }
+        match next {
+            Some(next_idx) => if let Some(Some(next_order_node)) = order_pool.get_mut(next_idx) {
+                next_order_node.prev = prev;
+            },
+            None => price_level.tail = prev
+        }
+    }
+
+    // walks the order's level from the head, O(orders ahead)
+    pub fn queue_position(&self, order_id : u64) -> Option<QueuePosition>{
+        let order_node = self.get_order(order_id)?;
+        let mut cursor = self.price_map.get(&order_node.market_limit)?.head;
+        let mut position = QueuePosition::default();
+        while let Some(idx) = cursor {
+            let node = self.order_pool.get(idx)?.as_ref()?;
+            if node.order_id == order_id {
+                return Some(position);
+            }
+            position.orders_ahead += 1;
+            position.quantity_ahead += node.current_quantity;
+            cursor = node.next;
+        }
+        None
+    }
+
+    // quantity an incoming order (on the other side) could take from this half without
+    // going past `price_bound`, iceberg reserves included. stops walking the levels as
+    // soon as `wanted` is covered. `self_trade` is the incoming order's owner and the self-trade
+    // prevention mode when it's on: the owner's own orders never count, and unless the mode
+    // cancels them and carries on, reaching one (pro-rata reaches them first at their level)
+    // ends the walk.
+    pub fn crossable_quantity(
+        &self,
+        is_buy_side : bool,
+        price_bound : Option<u32>,
+        wanted : u32,
+        self_trade : Option<(u64, SelfTradePrevention)>,
+        matching_algorithm : MatchingAlgorithm
+    ) -> u32{
+        let levels : Box<dyn Iterator<Item = (&u32, &PriceLevel)>> = if is_buy_side {
+            Box::new(self.price_map.iter())
+        } else {
+            Box::new(self.price_map.iter().rev())
+        };
+        let stops_at_own = self_trade.filter(|(_, mode)| *mode != SelfTradePrevention::CancelOldest).map(|(owner_id, _)| owner_id);
+        let mut available : u32 = 0;
+        for (price, price_level) in levels {
+            if let Some(bound) = price_bound {
+                let crosses = if is_buy_side { bound >= *price } else { bound <= *price };
+                if !crosses {
+                    break;
+                }
+            }
+            if let Some(owner_id) = stops_at_own
+                && matches!(matching_algorithm, MatchingAlgorithm::ProRata { .. })
+                && Self::find_owner_order(&self.order_pool, price_level, owner_id).is_some() {
+                return available;
+            }
+            let mut cursor = price_level.head;
+            while let Some(order_node) = cursor.and_then(|idx| self.order_pool.get(idx)).and_then(|node| node.as_ref()) {
+                cursor = order_node.next;
+                match self_trade {
+                    Some((owner_id, _)) if order_node.owner_id == owner_id => {
+                        if stops_at_own.is_some() {
+                            return available;
+                        }
+                    }
+                    _ => available = available.saturating_add(order_node.current_quantity + order_node.hidden_quantity)
+                }
+                if available >= wanted {
+                    return available;
+                }
+            }
+        }
+        available
+    }
+}
+// stop and stop-limit orders waiting for the last trade price to reach their stop price.
+// orders sharing a stop price are released in arrival order.
+#[derive(Debug, Default)]
+pub struct TriggerBook{
+    pub buy_stops : BTreeMap<u32, VecDeque<EngineNewOrder>>, // triggered when last trade >= stop price
+    pub sell_stops : BTreeMap<u32, VecDeque<EngineNewOrder>> // triggered when last trade <= stop price
+}
+
+impl TriggerBook {
+    pub fn new() -> Self{
+        Self { buy_stops : BTreeMap::new(), sell_stops : BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, stop_price : u32, order : EngineNewOrder){
+        let stops = if order.is_buy_side { &mut self.buy_stops } else { &mut self.sell_stops };
+        stops.entry(stop_price).or_default().push_back(order);
+    }
+
+    pub fn order_ids(&self) -> impl Iterator<Item = u64> + '_{
+        self.buy_stops.values().chain(self.sell_stops.values()).flatten().map(|order| order.engine_order_id)
+    }
+
+    // ids of the stops on one side whose stop price is between the two prices (inclusive),
+    // optionally only those of one owner. lowest stop price first, each in arrival order.
+    pub fn stops_in_band(&self, is_buy_side : bool, owner_id : Option<u64>, min_price : Option<u32>, max_price : Option<u32>) -> Vec<u64>{
+        let (low, high) = (min_price.unwrap_or(0), max_price.unwrap_or(u32::MAX));
+        if low > high {
+            return Vec::new();
+        }
+        let stops = if is_buy_side { &self.buy_stops } else { &self.sell_stops };
+        stops.range(low..=high)
+            .flat_map(|(_, queue)| queue)
+            .filter(|order| owner_id.is_none_or(|owner_id| owner_id == order.owner_id))
+            .map(|order| order.engine_order_id)
+            .collect()
+    }
+
+    pub fn get(&self, order_id : u64, is_buy_side : bool) -> Option<&EngineNewOrder>{
+        let stops = if is_buy_side { &self.buy_stops } else { &self.sell_stops };
+        stops.values().flatten().find(|order| order.engine_order_id == order_id)
+    }
+
+    pub fn remove(&mut self, order_id : u64, is_buy_side : bool) -> Option<EngineNewOrder>{
+        let stops = if is_buy_side { &mut self.buy_stops } else { &mut self.sell_stops };
+        let (stop_price, position) = stops.iter().find_map(|(stop_price, queue)| {
+            queue.iter().position(|order| order.engine_order_id == order_id).map(|position| (*stop_price, position))
+        })?;
+        let queue = stops.get_mut(&stop_price)?;
+        let order = queue.remove(position);
+        if queue.is_empty() {
+            stops.remove(&stop_price);
+        }
+        order
+    }
+
+    // hands out one triggered stop at a time so the caller can re-check after every
+    // execution. buy stops go first (lowest stop price first), then sell stops (highest first).
+    pub fn pop_triggered(&mut self, last_trade_price : u32) -> Option<EngineNewOrder>{
+        if let Some(mut entry) = self.buy_stops.first_entry() && *entry.key() <= last_trade_price {
+            let order = entry.get_mut().pop_front();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            return order;
+        }
+        if let Some(mut entry) = self.sell_stops.last_entry() && *entry.key() >= last_trade_price {
+            let order = entry.get_mut().pop_front();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            return order;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HalfBook, OrderBook};
+    use crate::order_book::types::OrderNode;
+
+    // one bid level at 100, an order per (shown, hidden) quantity with ids from 1 in queue order
+    fn bid_level(orders : &[(u32, u32)]) -> OrderBook{
+        let mut orderbook = OrderBook::new();
+        for (order_id, (shown, hidden)) in (1..).zip(orders) {
+            let order_node = OrderNode {
+                order_id,
+                initial_quantity : shown + hidden,
+                current_quantity : *shown,
+                market_limit : 100,
+                post_only : None,
+                peak_quantity : if *hidden > 0 { *shown } else { 0 },
+                hidden_quantity : *hidden,
+                owner_id : 0,
+                entry_time : 0,
+                next : None,
+                prev : None
+            };
+            orderbook.create_buy_order(order_id, order_node).unwrap();
+        }
+        orderbook
+    }
+
+    fn allocate(orders : &[(u32, u32)], quantity : u32, lot_size : u32, top_order_priority : bool, min_allocation : u32) -> Vec<(u64, u32)>{
+        let orderbook = bid_level(orders);
+        let half = &orderbook.bid;
+        HalfBook::pro_rata_allocation(&half.order_pool, &half.price_map[&100], quantity, lot_size, top_order_priority, min_allocation)
+            .into_iter()
+            .map(|(idx, share)| (half.order_pool[idx].as_ref().unwrap().order_id, share))
+            .collect()
+    }
+
+    #[test]
+    fn pro_rata_splits_in_proportion_to_the_shown_quantity(){
+        assert_eq!(allocate(&[(10, 0), (30, 0), (60, 0)], 50, 1, false, 0), [(1, 5), (2, 15), (3, 30)]);
+        // an iceberg's reserve gets it no bigger share
+        assert_eq!(allocate(&[(10, 90), (10, 0)], 10, 1, false, 0), [(1, 5), (2, 5)]);
+        // more than the level holds fills every order
+        assert_eq!(allocate(&[(10, 0), (30, 0)], 200, 1, false, 0), [(1, 10), (2, 30)]);
+    }
+
+    #[test]
+    fn what_rounding_leaves_over_goes_first_come_first_served(){
+        // 10 over three equal orders is 3 each, the last one goes to the head
+        assert_eq!(allocate(&[(10, 0), (10, 0), (10, 0)], 10, 1, false, 0), [(1, 4), (2, 3), (3, 3)]);
+        // 7.5, 7.5 and 15 rounded down to lots of 5, the 5 left over to the head
+        assert_eq!(allocate(&[(15, 0), (15, 0), (30, 0)], 30, 5, false, 0), [(1, 10), (2, 5), (3, 15)]);
+        // the leftover never takes an order past what it shows
+        assert_eq!(allocate(&[(2, 0), (9, 0), (9, 0)], 10, 1, false, 0), [(1, 2), (2, 4), (3, 4)]);
+    }
+
+    #[test]
+    fn shares_under_the_minimum_allocation_go_to_the_leftover(){
+        assert_eq!(allocate(&[(5, 0), (5, 0), (90, 0)], 20, 1, false, 0), [(1, 1), (2, 1), (3, 18)]);
+        // 1 each is under the minimum of 2, the 2 dropped go to the head of the queue
+        assert_eq!(allocate(&[(5, 0), (5, 0), (90, 0)], 20, 1, false, 2), [(1, 2), (3, 18)]);
+    }
+
+    #[test]
+    fn the_top_order_is_filled_before_the_split(){
+        // 10 to the head, 40 split 30:60 as 13 and 26, the 1 left over to the first order with room
+        assert_eq!(allocate(&[(10, 0), (30, 0), (60, 0)], 50, 1, true, 0), [(1, 10), (2, 14), (3, 26)]);
+        // nothing left to split once the head has taken it all
+        assert_eq!(allocate(&[(10, 0), (30, 0)], 8, 1, true, 0), [(1, 8)]);
+    }
+}
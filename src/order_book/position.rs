@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+// quantity and value (price x quantity) of an owner's resting orders on one side of a book,
+// iceberg reserves included
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Exposure{
+    pub quantity : u64,
+    pub notional : u64
+}
+
+// what an owner has traded in a security
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct TradedQuantity{
+    pub bought_quantity : u64,
+    pub sold_quantity : u64,
+    pub bought_notional : u64,
+    pub sold_notional : u64
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Position{
+    pub owner_id : u64,
+    pub security_id : u32,
+    pub traded : TradedQuantity,
+    pub open_buy : Exposure,
+    pub open_sell : Exposure
+}
+
+impl Position {
+    // long when positive
+    pub fn net_quantity(&self) -> i64{
+        self.traded.bought_quantity as i64 - self.traded.sold_quantity as i64
+    }
+
+    pub fn open(&self, is_buy_side : bool) -> Exposure{
+        if is_buy_side { self.open_buy } else { self.open_sell }
+    }
+}
+
+// traded quantities per (owner id, security id), from every fill since the engine started.
+// open exposure lives with the resting orders in the books.
+#[derive(Debug, Default)]
+pub struct PositionTracker{
+    pub(crate) traded : HashMap<(u64, u32), TradedQuantity>
+}
+
+impl PositionTracker {
+    pub fn new() -> Self{
+        Self { traded : HashMap::new() }
+    }
+
+    pub fn get(&self, owner_id : u64, security_id : u32) -> TradedQuantity{
+        self.traded.get(&(owner_id, security_id)).copied().unwrap_or_default()
+    }
+
+    pub fn record_fill(&mut self, owner_id : u64, security_id : u32, is_buy_side : bool, price : u32, quantity : u32){
+        let traded = self.traded.entry((owner_id, security_id)).or_default();
+        let notional = price as u64 * quantity as u64;
+        if is_buy_side {
+            traded.bought_quantity += quantity as u64;
+            traded.bought_notional += notional;
+        } else {
+            traded.sold_quantity += quantity as u64;
+            traded.sold_notional += notional;
+        }
+    }
+
+    // securities the owner has traded, ascending
+    pub fn securities(&self, owner_id : u64) -> Vec<u32>{
+        let mut security_ids : Vec<u32> = self.traded.keys().filter(|(owner, _)| *owner == owner_id).map(|(_, security_id)| *security_id).collect();
+        security_ids.sort_unstable();
+        security_ids
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use crate::order_book::position::Position;
+
+// what a pre-trade check gets to see of an order: a new order, or the terms a modify would
+// leave it with. market orders have no price.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RiskOrder{
+    pub security_id : u32,
+    pub owner_id : u64,
+    pub is_buy_side : bool,
+    pub price : Option<u32>,
+    pub quantity : u32
+}
+
+// the book the order is about to enter
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct MarketContext{
+    pub best_bid : Option<u32>,
+    pub best_ask : Option<u32>,
+    pub last_trade_price : Option<u32>
+}
+
+impl MarketContext {
+    // what an order on this side would trade against: the opposite best, or the last trade
+    // while that side is empty
+    pub fn reference_price(&self, is_buy_side : bool) -> Option<u32>{
+        let opposite_best = if is_buy_side { self.best_ask } else { self.best_bid };
+        opposite_best.or(self.last_trade_price)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RiskRejection{
+    MaxOrderQuantity { quantity : u32, limit : u32 },
+    MaxNotional { notional : u64, limit : u64 }, // price x quantity, market orders valued at the reference price
+    PriceCollar { price : u32, reference_price : u32, collar_bps : u32 }, // further through the reference than the collar allows
+    MaxOpenQuantity { open_quantity : u64, limit : u64 }, // resting on the order's side, the order included
+    MaxOpenNotional { open_notional : u64, limit : u64 },
+    MaxPosition { position : i64, limit : u64 }, // worst case net position, see `RiskLimits::max_position`
+    Other(String) // for checks plugged in by the caller
+}
+
+impl fmt::Display for RiskRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskRejection::MaxOrderQuantity { quantity, limit } => write!(f, "quantity {} above the limit of {}", quantity, limit),
+            RiskRejection::MaxNotional { notional, limit } => write!(f, "notional {} above the limit of {}", notional, limit),
+            RiskRejection::PriceCollar { price, reference_price, collar_bps } => {
+                write!(f, "price {} more than {} bps through the reference price {}", price, collar_bps, reference_price)
+            }
+            RiskRejection::MaxOpenQuantity { open_quantity, limit } => write!(f, "open quantity {} above the limit of {}", open_quantity, limit),
+            RiskRejection::MaxOpenNotional { open_notional, limit } => write!(f, "open notional {} above the limit of {}", open_notional, limit),
+            RiskRejection::MaxPosition { position, limit } => write!(f, "position could reach {}, above the limit of {}", position, limit),
+            RiskRejection::Other(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+// runs in front of every new order and every modify that changes the price or adds quantity.
+// orders it rejects never reach the book. `position` is the owner's in the order's security,
+// without the order being modified.
+pub trait RiskCheck : Debug + Send {
+    fn check(&self, order : &RiskOrder, market : &MarketContext, position : &Position) -> Result<(), RiskRejection>;
+}
+
+// a limit left as None is not checked
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RiskLimits{
+    pub max_order_quantity : Option<u32>,
+    pub max_notional : Option<u64>,
+    pub price_collar_bps : Option<u32>, // how far (in basis points) a buy may price above / a sell below the reference price
+    pub max_open_quantity : Option<u64>, // per side
+    pub max_open_notional : Option<u64>, // per side
+    pub max_position : Option<u64> // absolute net position if everything resting on the order's side and the order itself filled
+}
+
+impl RiskLimits {
+    pub fn check(&self, order : &RiskOrder, market : &MarketContext, position : &Position) -> Result<(), RiskRejection>{
+        if let Some(limit) = self.max_order_quantity && order.quantity > limit {
+            return Err(RiskRejection::MaxOrderQuantity { quantity : order.quantity, limit });
+        }
+        let reference_price = market.reference_price(order.is_buy_side);
+        if let Some(limit) = self.max_notional && let Some(price) = order.price.or(reference_price) {
+            let notional = price as u64 * order.quantity as u64;
+            if notional > limit {
+                return Err(RiskRejection::MaxNotional { notional, limit });
+            }
+        }
+        if let Some(collar_bps) = self.price_collar_bps && let (Some(price), Some(reference_price)) = (order.price, reference_price) {
+            let band = reference_price as u64 * collar_bps as u64 / 10_000;
+            let outside = if order.is_buy_side {
+                price as u64 > reference_price as u64 + band
+            } else {
+                (price as u64) < (reference_price as u64).saturating_sub(band)
+            };
+            if outside {
+                return Err(RiskRejection::PriceCollar { price, reference_price, collar_bps });
+            }
+        }
+        let open = position.open(order.is_buy_side);
+        if let Some(limit) = self.max_open_quantity {
+            let open_quantity = open.quantity + order.quantity as u64;
+            if open_quantity > limit {
+                return Err(RiskRejection::MaxOpenQuantity { open_quantity, limit });
+            }
+        }
+        if let Some(limit) = self.max_open_notional && let Some(price) = order.price.or(reference_price) {
+            let open_notional = open.notional + price as u64 * order.quantity as u64;
+            if open_notional > limit {
+                return Err(RiskRejection::MaxOpenNotional { open_notional, limit });
+            }
+        }
+        if let Some(limit) = self.max_position {
+            let exposure = (open.quantity + order.quantity as u64) as i64;
+            let worst_case = if order.is_buy_side { position.net_quantity() + exposure } else { position.net_quantity() - exposure };
+            if worst_case.unsigned_abs() > limit {
+                return Err(RiskRejection::MaxPosition { position : worst_case, limit });
+            }
+        }
+        Ok(())
+    }
+}
+
+// the stock risk check: one set of limits per participant (owner id), falling back to
+// `default_limits` for participants without their own
+#[derive(Debug, Default, Clone)]
+pub struct ParticipantLimits{
+    pub default_limits : RiskLimits,
+    pub limits : HashMap<u64, RiskLimits>
+}
+
+impl ParticipantLimits {
+    pub fn new(default_limits : RiskLimits) -> Self{
+        Self { default_limits, limits : HashMap::new() }
+    }
+
+    pub fn set_limits(&mut self, owner_id : u64, limits : RiskLimits){
+        self.limits.insert(owner_id, limits);
+    }
+
+    pub fn limits_for(&self, owner_id : u64) -> &RiskLimits{
+        self.limits.get(&owner_id).unwrap_or(&self.default_limits)
+    }
+}
+
+impl RiskCheck for ParticipantLimits {
+    fn check(&self, order : &RiskOrder, market : &MarketContext, position : &Position) -> Result<(), RiskRejection>{
+        self.limits_for(order.owner_id).check(order, market, position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MarketContext, ParticipantLimits, RiskCheck, RiskLimits, RiskOrder, RiskRejection};
+    use crate::order_book::position::{Exposure, Position, TradedQuantity};
+
+    fn order(is_buy_side : bool, price : Option<u32>, quantity : u32) -> RiskOrder{
+        RiskOrder { security_id : 1, owner_id : 7, is_buy_side, price, quantity }
+    }
+
+    // 99 bid, 101 offered, last traded at 100
+    fn market() -> MarketContext{
+        MarketContext { best_bid : Some(99), best_ask : Some(101), last_trade_price : Some(100) }
+    }
+
+    // nothing traded, nothing resting
+    fn flat() -> Position{
+        Position { owner_id : 7, security_id : 1, ..Default::default() }
+    }
+
+    #[test]
+    fn no_limits_let_everything_through(){
+        let limits = RiskLimits::default();
+        assert_eq!(limits.check(&order(true, Some(u32::MAX), u32::MAX), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(false, None, u32::MAX), &MarketContext::default(), &flat()), Ok(()));
+    }
+
+    #[test]
+    fn max_order_quantity_is_inclusive(){
+        let limits = RiskLimits { max_order_quantity : Some(10), ..Default::default() };
+        assert_eq!(limits.check(&order(true, Some(100), 10), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(false, None, 11), &market(), &flat()), Err(RiskRejection::MaxOrderQuantity { quantity : 11, limit : 10 }));
+    }
+
+    #[test]
+    fn max_notional_values_market_orders_at_the_reference_price(){
+        let limits = RiskLimits { max_notional : Some(1_000), ..Default::default() };
+        assert_eq!(limits.check(&order(true, Some(100), 10), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(true, Some(101), 10), &market(), &flat()), Err(RiskRejection::MaxNotional { notional : 1_010, limit : 1_000 }));
+        // a market buy is worth what it would pay at the best offer
+        assert_eq!(limits.check(&order(true, None, 10), &market(), &flat()), Err(RiskRejection::MaxNotional { notional : 1_010, limit : 1_000 }));
+        assert_eq!(limits.check(&order(false, None, 10), &market(), &flat()), Ok(()));
+        // with no price to go by there is nothing to value it at
+        assert_eq!(limits.check(&order(true, None, 1_000), &MarketContext::default(), &flat()), Ok(()));
+        // price x quantity can't overflow
+        let wide = RiskLimits { max_notional : Some(u64::MAX - 1), ..Default::default() };
+        assert_eq!(wide.check(&order(true, Some(u32::MAX), u32::MAX), &market(), &flat()), Ok(()));
+    }
+
+    #[test]
+    fn the_collar_is_measured_from_the_opposite_best(){
+        // 10% of 101 is 10, a buy may go up to 111
+        let limits = RiskLimits { price_collar_bps : Some(1_000), ..Default::default() };
+        assert_eq!(limits.check(&order(true, Some(111), 1), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(true, Some(112), 1), &market(), &flat()), Err(RiskRejection::PriceCollar { price : 112, reference_price : 101, collar_bps : 1_000 }));
+        // 10% of 99 is 9, a sell may go down to 90
+        assert_eq!(limits.check(&order(false, Some(90), 1), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(false, Some(89), 1), &market(), &flat()), Err(RiskRejection::PriceCollar { price : 89, reference_price : 99, collar_bps : 1_000 }));
+        // only prices through the reference are collared, passive ones never are
+        assert_eq!(limits.check(&order(true, Some(1), 1), &market(), &flat()), Ok(()));
+        assert_eq!(limits.check(&order(false, Some(u32::MAX), 1), &market(), &flat()), Ok(()));
+        // market orders have no price to collar
+        assert_eq!(limits.check(&order(true, None, 1), &market(), &flat()), Ok(()));
+    }
+
+    #[test]
+    fn the_collar_falls_back_to_the_last_trade_while_the_opposite_side_is_empty(){
+        let limits = RiskLimits { price_collar_bps : Some(500), ..Default::default() };
+        let no_offers = MarketContext { best_ask : None, ..market() };
+        // 5% of the last trade at 100
+        assert_eq!(limits.check(&order(true, Some(105), 1), &no_offers, &flat()), Ok(()));
+        assert_eq!(limits.check(&order(true, Some(106), 1), &no_offers, &flat()), Err(RiskRejection::PriceCollar { price : 106, reference_price : 100, collar_bps : 500 }));
+        // the bid side is still there for sells
+        assert_eq!(limits.check(&order(false, Some(94), 1), &no_offers, &flat()), Err(RiskRejection::PriceCollar { price : 94, reference_price : 99, collar_bps : 500 }));
+        // nothing to measure against at all: no collar
+        assert_eq!(limits.check(&order(true, Some(1_000), 1), &MarketContext::default(), &flat()), Ok(()));
+    }
+
+    #[test]
+    fn participants_without_their_own_limits_get_the_default(){
+        let mut limits = ParticipantLimits::new(RiskLimits { max_order_quantity : Some(10), ..Default::default() });
+        limits.set_limits(8, RiskLimits { max_order_quantity : Some(100), ..Default::default() });
+        assert!(limits.check(&order(true, Some(100), 50), &market(), &flat()).is_err());
+        assert_eq!(limits.check(&RiskOrder { owner_id : 8, ..order(true, Some(100), 50) }, &market(), &flat()), Ok(()));
+    }
+
+    #[test]
+    fn rejections_read_as_a_reason(){
+        assert_eq!(RiskRejection::MaxOrderQuantity { quantity : 11, limit : 10 }.to_string(), "quantity 11 above the limit of 10");
+        assert_eq!(RiskRejection::PriceCollar { price : 112, reference_price : 101, collar_bps : 1_000 }.to_string(), "price 112 more than 1000 bps through the reference price 101");
+    }
+
+    // long 20 with 30 bid at 100 and 10 offered at 110
+    fn long() -> Position{
+        Position {
+            traded : TradedQuantity { bought_quantity : 30, sold_quantity : 10, bought_notional : 3_000, sold_notional : 1_000 },
+            open_buy : Exposure { quantity : 30, notional : 3_000 },
+            open_sell : Exposure { quantity : 10, notional : 1_100 },
+            ..flat()
+        }
+    }
+
+    #[test]
+    fn max_open_quantity_counts_the_order_on_top_of_its_side(){
+        let limits = RiskLimits { max_open_quantity : Some(40), ..Default::default() };
+        assert_eq!(limits.check(&order(true, Some(100), 10), &market(), &long()), Ok(()));
+        assert_eq!(limits.check(&order(true, Some(100), 11), &market(), &long()), Err(RiskRejection::MaxOpenQuantity { open_quantity : 41, limit : 40 }));
+        // the other side is counted on its own
+        assert_eq!(limits.check(&order(false, Some(100), 30), &market(), &long()), Ok(()));
+    }
+
+    #[test]
+    fn max_open_notional_values_market_orders_at_the_reference_price(){
+        let limits = RiskLimits { max_open_notional : Some(4_000), ..Default::default() };
+        assert_eq!(limits.check(&order(true, Some(100), 10), &market(), &long()), Ok(()));
+        // 3_000 resting and 10 at the 101 offer
+        assert_eq!(limits.check(&order(true, None, 10), &market(), &long()), Err(RiskRejection::MaxOpenNotional { open_notional : 4_010, limit : 4_000 }));
+        assert_eq!(limits.check(&order(false, None, 10), &market(), &long()), Ok(()));
+    }
+
+    #[test]
+    fn max_position_assumes_the_whole_side_fills(){
+        let limits = RiskLimits { max_position : Some(60), ..Default::default() };
+        // long 20, plus 30 resting, plus the order
+        assert_eq!(limits.check(&order(true, Some(100), 10), &market(), &long()), Ok(()));
+        assert_eq!(limits.check(&order(true, Some(100), 11), &market(), &long()), Err(RiskRejection::MaxPosition { position : 61, limit : 60 }));
+        // selling takes the long down first: 20 - 10 - 70 = -60
+        assert_eq!(limits.check(&order(false, Some(100), 70), &market(), &long()), Ok(()));
+        assert_eq!(limits.check(&order(false, Some(100), 71), &market(), &long()), Err(RiskRejection::MaxPosition { position : -61, limit : 60 }));
+        assert_eq!(RiskRejection::MaxPosition { position : -61, limit : 60 }.to_string(), "position could reach -61, above the limit of 60");
+    }
+}
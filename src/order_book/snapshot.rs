@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use crate::order_book::auction::CallAuction;
+use crate::order_book::error::EngineError;
+use crate::order_book::journal::{Decoder, Encoder};
+use crate::order_book::order_status::{OrderTracker, TrackedOrder};
+use crate::order_book::orderbook::{HalfBook, OrderBook, TriggerBook};
+use crate::order_book::position::{PositionTracker, TradedQuantity};
+use crate::order_book::types::{EngineNewOrder, MatchingAlgorithm, OrderNode, OrderState, PriceLevel, QueuePosition};
+
+// a snapshot file is [magic][version : u32][body], little endian, using the same field
+// encoding as the journal. the order pool is written slot by slot (free slots included) so
+// the restored book has the exact same indices, linked lists and therefore the same FIFO.
+const MAGIC : &[u8; 8] = b"CLOBSNAP";
+const VERSION : u32 = 11;
+
+// writes next to `path` first and renames over it, a crash mid-write keeps the old snapshot
+pub(crate) fn write(path : &Path, body : Encoder) -> Result<(), EngineError>{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path).map_err(|e| EngineError::SnapshotIo(e.kind()))?;
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + body.buf.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&body.buf);
+    file.write_all(&bytes)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| EngineError::SnapshotIo(e.kind()))
+}
+
+// returns the body after checking the header, decode it with `Decoder`
+pub(crate) fn read(path : &Path) -> Result<Vec<u8>, EngineError>{
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| EngineError::SnapshotIo(e.kind()))?;
+    let header = MAGIC.len() + 4;
+    if bytes.len() < header || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(EngineError::CorruptSnapshot("not a snapshot file"));
+    }
+    if u32::from_le_bytes(bytes[MAGIC.len()..header].try_into().unwrap()) != VERSION {
+        return Err(EngineError::CorruptSnapshot("unsupported snapshot version"));
+    }
+    Ok(bytes.split_off(header))
+}
+
+// the decoder reports short reads as journal corruption, re-label them for the snapshot
+pub(crate) fn corrupt(e : EngineError) -> EngineError{
+    match e {
+        EngineError::CorruptJournal(reason) => EngineError::CorruptSnapshot(reason),
+        e => e
+    }
+}
+
+impl Encoder {
+    fn opt_index(&mut self, value : Option<usize>){
+        self.opt_u64(value.map(|idx| idx as u64));
+    }
+
+    fn order_node(&mut self, node : &OrderNode){
+        self.u64(node.order_id);
+        self.u32(node.initial_quantity);
+        self.u32(node.current_quantity);
+        self.u32(node.market_limit);
+        self.post_only(node.post_only);
+        self.u32(node.peak_quantity);
+        self.u32(node.hidden_quantity);
+        self.u64(node.owner_id);
+        self.u64(node.entry_time);
+        self.opt_index(node.next);
+        self.opt_index(node.prev);
+    }
+
+    fn half_book(&mut self, half : &HalfBook){
+        self.u32(half.price_map.len() as u32);
+        for (price, level) in &half.price_map {
+            self.u32(*price);
+            self.opt_index(level.head);
+            self.opt_index(level.tail);
+            self.u32(level.order_count);
+            self.u32(level.total_quantity);
+        }
+        // sorted so the same book always produces the same bytes
+        let mut registry : Vec<(&u64, &usize)> = half.order_registry.iter().collect();
+        registry.sort_unstable();
+        self.u32(registry.len() as u32);
+        for (order_id, idx) in registry {
+            self.u64(*order_id);
+            self.u64(*idx as u64);
+        }
+        self.u32(half.order_pool.len() as u32);
+        for slot in &half.order_pool {
+            match slot {
+                Some(node) => { self.u8(1); self.order_node(node); }
+                None => self.u8(0)
+            }
+        }
+        self.u32(half.free_list.len() as u32);
+        for idx in &half.free_list {
+            self.u64(*idx as u64);
+        }
+    }
+
+    fn stops(&mut self, stops : &BTreeMap<u32, VecDeque<EngineNewOrder>>){
+        self.u32(stops.len() as u32);
+        for (stop_price, queue) in stops {
+            self.u32(*stop_price);
+            self.u32(queue.len() as u32);
+            for order in queue {
+                self.new_order(order);
+            }
+        }
+    }
+
+    pub(crate) fn order_book(&mut self, book : &OrderBook){
+        self.half_book(&book.bid);
+        self.half_book(&book.ask);
+        self.stops(&book.triggers.buy_stops);
+        self.stops(&book.triggers.sell_stops);
+        self.opt_u32(book.last_trade_price);
+        self.trading_state(book.state);
+        self.u32(book.queue_watches.len() as u32);
+        for (order_id, (is_buy_side, position)) in &book.queue_watches {
+            self.u64(*order_id);
+            self.bool(*is_buy_side);
+            self.u32(position.orders_ahead);
+            self.u32(position.quantity_ahead);
+        }
+        match &book.auction {
+            Some(auction) => {
+                self.u8(1);
+                self.auction_kind(auction.kind);
+                self.u32(auction.market_orders.len() as u32);
+                for order in &auction.market_orders {
+                    self.new_order(order);
+                }
+            }
+            None => self.u8(0)
+        }
+        self.u32(book.schedule.len() as u32);
+        for (at, state) in &book.schedule {
+            self.u64(*at);
+            self.trading_state(*state);
+        }
+    }
+
+    pub(crate) fn order_tracker(&mut self, tracker : &OrderTracker){
+        self.u64(tracker.retention as u64);
+        let mut orders : Vec<(&(u32, u64), &TrackedOrder)> = tracker.orders.iter().collect();
+        orders.sort_unstable_by_key(|(key, _)| **key);
+        self.u32(orders.len() as u32);
+        for ((security_id, order_id), tracked) in orders {
+            self.u32(*security_id);
+            self.u64(*order_id);
+            self.u64(tracked.owner_id);
+            self.opt_str(tracked.client_order_id.as_deref());
+            self.opt_u64(tracked.session_id);
+            self.time_in_force(tracked.time_in_force);
+            self.bool(tracked.is_buy_side);
+            self.opt_u32(tracked.price);
+            self.u32(tracked.initial_quantity);
+            self.u32(tracked.filled_quantity);
+            self.u8(match tracked.state {
+                OrderState::Live => 0,
+                OrderState::Filled => 1,
+                OrderState::Cancelled => 2
+            });
+        }
+        self.u32(tracker.terminal.len() as u32);
+        for (security_id, order_id) in &tracker.terminal {
+            self.u32(*security_id);
+            self.u64(*order_id);
+        }
+    }
+
+    pub(crate) fn position_tracker(&mut self, tracker : &PositionTracker){
+        let mut traded : Vec<(&(u64, u32), &TradedQuantity)> = tracker.traded.iter().collect();
+        traded.sort_unstable_by_key(|(key, _)| **key);
+        self.u32(traded.len() as u32);
+        for ((owner_id, security_id), traded) in traded {
+            self.u64(*owner_id);
+            self.u32(*security_id);
+            self.u64(traded.bought_quantity);
+            self.u64(traded.sold_quantity);
+            self.u64(traded.bought_notional);
+            self.u64(traded.sold_notional);
+        }
+    }
+}
+
+impl Decoder<'_> {
+    fn opt_index(&mut self) -> Result<Option<usize>, EngineError>{
+        Ok(self.opt_u64()?.map(|idx| idx as usize))
+    }
+
+    fn order_node(&mut self) -> Result<OrderNode, EngineError>{
+        Ok(OrderNode {
+            order_id : self.u64()?,
+            initial_quantity : self.u32()?,
+            current_quantity : self.u32()?,
+            market_limit : self.u32()?,
+            post_only : self.post_only()?,
+            peak_quantity : self.u32()?,
+            hidden_quantity : self.u32()?,
+            owner_id : self.u64()?,
+            entry_time : self.u64()?,
+            next : self.opt_index()?,
+            prev : self.opt_index()?
+        })
+    }
+
+    fn half_book(&mut self) -> Result<HalfBook, EngineError>{
+        let mut half = HalfBook::new();
+        for _ in 0..self.u32()? {
+            let price = self.u32()?;
+            let level = PriceLevel {
+                head : self.opt_index()?,
+                tail : self.opt_index()?,
+                order_count : self.u32()?,
+                total_quantity : self.u32()?
+            };
+            half.price_map.insert(price, level);
+        }
+        let registry_len = self.u32()?;
+        let mut order_registry = HashMap::with_capacity(registry_len as usize);
+        for _ in 0..registry_len {
+            order_registry.insert(self.u64()?, self.u64()? as usize);
+        }
+        half.order_registry = order_registry;
+        for _ in 0..self.u32()? {
+            let slot = match self.u8()? {
+                0 => None,
+                _ => Some(self.order_node()?)
+            };
+            half.order_pool.push(slot);
+        }
+        for _ in 0..self.u32()? {
+            half.free_list.push(self.u64()? as usize);
+        }
+        Self::check_half_book(&half)?;
+        // exposure follows from the resting orders, it isn't written out
+        for order_node in half.order_pool.iter().flatten() {
+            HalfBook::add_exposure(&mut half.exposure, order_node.owner_id, order_node.market_limit, order_node.current_quantity + order_node.hidden_quantity);
+        }
+        Ok(half)
+    }
+
+    // every index in the snapshot has to land on the right kind of pool slot, otherwise the
+    // first match against the restored book would panic or walk into the wrong order
+    fn check_half_book(half : &HalfBook) -> Result<(), EngineError>{
+        let live = |idx : usize| half.order_pool.get(idx).is_some_and(|slot| slot.is_some());
+        for level in half.price_map.values() {
+            if !level.head.is_none_or(live) || !level.tail.is_none_or(live) {
+                return Err(EngineError::CorruptSnapshot("price level points at an empty pool slot"));
+            }
+        }
+        for (order_id, idx) in &half.order_registry {
+            if !half.order_pool.get(*idx).is_some_and(|slot| slot.is_some_and(|node| node.order_id == *order_id)) {
+                return Err(EngineError::CorruptSnapshot("registry entry does not match the pool"));
+            }
+        }
+        for node in half.order_pool.iter().flatten() {
+            if !node.next.is_none_or(live) || !node.prev.is_none_or(live) {
+                return Err(EngineError::CorruptSnapshot("order links to an empty pool slot"));
+            }
+        }
+        if half.free_list.iter().any(|idx| half.order_pool.get(*idx).is_none_or(|slot| slot.is_some())) {
+            return Err(EngineError::CorruptSnapshot("free list holds a live pool slot"));
+        }
+        Ok(())
+    }
+
+    fn stops(&mut self) -> Result<BTreeMap<u32, VecDeque<EngineNewOrder>>, EngineError>{
+        let mut stops = BTreeMap::new();
+        for _ in 0..self.u32()? {
+            let stop_price = self.u32()?;
+            let queue_len = self.u32()?;
+            let mut queue = VecDeque::with_capacity(queue_len as usize);
+            for _ in 0..queue_len {
+                queue.push_back(self.new_order()?);
+            }
+            stops.insert(stop_price, queue);
+        }
+        Ok(stops)
+    }
+
+    pub(crate) fn order_book(&mut self) -> Result<OrderBook, EngineError>{
+        let bid = self.half_book()?;
+        let ask = self.half_book()?;
+        let triggers = TriggerBook { buy_stops : self.stops()?, sell_stops : self.stops()? };
+        let mut orderbook = OrderBook { ask, bid, triggers, last_trade_price : self.opt_u32()?, state : self.trading_state()?, schedule : Vec::new(), order_events : Vec::new(), published_bbo : (None, None), queue_watches : BTreeMap::new(), matching_algorithm : MatchingAlgorithm::Fifo, lot_size : 1, auction : None };
+        for _ in 0..self.u32()? {
+            let order_id = self.u64()?;
+            let is_buy_side = self.bool()?;
+            let position = QueuePosition { orders_ahead : self.u32()?, quantity_ahead : self.u32()? };
+            orderbook.queue_watches.insert(order_id, (is_buy_side, position));
+        }
+        if self.bool()? {
+            let mut auction = CallAuction::new(self.auction_kind()?);
+            for _ in 0..self.u32()? {
+                auction.market_orders.push(self.new_order()?);
+            }
+            orderbook.auction = Some(auction);
+        }
+        for _ in 0..self.u32()? {
+            orderbook.schedule.push((self.u64()?, self.trading_state()?));
+        }
+        // consumers are expected to start from the restored book, not from an empty one
+        orderbook.published_bbo = (orderbook.best_bid(), orderbook.best_ask());
+        Ok(orderbook)
+    }
+
+    pub(crate) fn order_tracker(&mut self) -> Result<OrderTracker, EngineError>{
+        let mut tracker = OrderTracker::new();
+        tracker.retention = self.u64()? as usize;
+        for _ in 0..self.u32()? {
+            let key = (self.u32()?, self.u64()?);
+            let tracked = TrackedOrder {
+                owner_id : self.u64()?,
+                client_order_id : self.opt_string()?,
+                session_id : self.opt_u64()?,
+                time_in_force : self.time_in_force()?,
+                is_buy_side : self.bool()?,
+                price : self.opt_u32()?,
+                initial_quantity : self.u32()?,
+                filled_quantity : self.u32()?,
+                state : match self.u8()? {
+                    0 => OrderState::Live,
+                    1 => OrderState::Filled,
+                    2 => OrderState::Cancelled,
+                    _ => return Err(EngineError::CorruptSnapshot("unknown order state"))
+                }
+            };
+            // only live orders hold on to their client order id
+            if tracked.state == OrderState::Live && let Some(client_order_id) = tracked.client_order_id.clone() {
+                tracker.client_orders.insert((tracked.owner_id, client_order_id), key);
+            }
+            tracker.orders.insert(key, tracked);
+        }
+        for _ in 0..self.u32()? {
+            tracker.terminal.push_back((self.u32()?, self.u64()?));
+        }
+        Ok(tracker)
+    }
+
+    pub(crate) fn position_tracker(&mut self) -> Result<PositionTracker, EngineError>{
+        let mut tracker = PositionTracker::new();
+        for _ in 0..self.u32()? {
+            let key = (self.u64()?, self.u32()?);
+            let traded = TradedQuantity {
+                bought_quantity : self.u64()?,
+                sold_quantity : self.u64()?,
+                bought_notional : self.u64()?,
+                sold_notional : self.u64()?
+            };
+            tracker.traded.insert(key, traded);
+        }
+        Ok(tracker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Span;
+    use crate::order_book::auction::AuctionKind;
+    use crate::order_book::clock::SimulatedClock;
+    use crate::order_book::matching_engine::MatchingEngine;
+    use crate::order_book::test_support::{assert_same_state, journaled_engine, levels, limit_order, TempFile};
+    use crate::order_book::trading_state::TradingState;
+    use crate::order_book::types::{EngineNewOrder, Instrument, MatchingAlgorithm, OrderType, PostOnly, TimeInForce};
+
+    #[test]
+    fn recover_replays_the_journal_after_the_snapshot(){
+        let (journal, snapshot) = (TempFile::new("recover-journal"), TempFile::new("recover-snapshot"));
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.match_order(limit_order(1, false, 101, 10), &span).unwrap();
+        live.match_order(limit_order(2, true, 101, 4), &span).unwrap();
+        live.match_order(EngineNewOrder { post_only : Some(PostOnly::Slide), ..limit_order(3, true, 99, 10) }, &span).unwrap();
+        live.save_snapshot(&snapshot.0).unwrap();
+        let at_snapshot = (levels(&live, true), levels(&live, false));
+
+        // only slides back to 100 if the restored order kept its post-only mode
+        live.modify(3, 1, Some(101), None, true, &span).unwrap();
+        assert_eq!(levels(&live, true), [(100, 10)]);
+        live.match_order(limit_order(4, true, 101, 2), &span).unwrap();
+        // wrong side, rejected
+        live.cancel(1, 1, &span, true).unwrap_err();
+        live.match_order(limit_order(5, false, 100, 3), &span).unwrap();
+
+        let restored = MatchingEngine::restore_snapshot(&snapshot.0).unwrap();
+        assert_eq!((levels(&restored, true), levels(&restored, false)), at_snapshot);
+        let mut recovered = MatchingEngine::recover(&snapshot.0, &journal.0).unwrap();
+        assert_same_state(&recovered, &live);
+
+        // the recovered engine keeps journaling where the live one stopped
+        recovered.match_order(limit_order(6, false, 100, 5), &span).unwrap();
+        let replayed = MatchingEngine::replay(&journal.0).unwrap();
+        assert_same_state(&replayed, &recovered);
+    }
+
+    #[test]
+    fn sessions_and_day_orders_survive_a_snapshot(){
+        let (journal, snapshot) = (TempFile::new("session-journal"), TempFile::new("session-snapshot"));
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.set_session_keeps_gtc(7, true).unwrap();
+        live.match_order(EngineNewOrder { session_id : Some(7), ..limit_order(1, true, 99, 5) }, &span).unwrap();
+        live.match_order(EngineNewOrder { session_id : Some(7), time_in_force : TimeInForce::Day, ..limit_order(2, true, 98, 5) }, &span).unwrap();
+        live.match_order(EngineNewOrder { time_in_force : TimeInForce::Day, ..limit_order(3, false, 101, 5) }, &span).unwrap();
+        live.save_snapshot(&snapshot.0).unwrap();
+
+        let mut restored = MatchingEngine::restore_snapshot(&snapshot.0).unwrap();
+        let ended : Vec<u64> = restored.end_session(7, &span).unwrap().iter().map(|order| order.order_id).collect();
+        assert_eq!(ended, [2]);
+        let expired : Vec<u64> = restored.expire_day_orders(1, &span).unwrap().iter().map(|order| order.order_id).collect();
+        assert_eq!(expired, [3]);
+        assert_eq!(levels(&restored, true), [(99, 5)]);
+    }
+
+    #[test]
+    fn a_restored_book_keeps_its_matching_algorithm(){
+        let snapshot = TempFile::new("pro-rata-snapshot");
+        let span = Span::none();
+        let mut live = MatchingEngine::new();
+        let matching_algorithm = MatchingAlgorithm::ProRata { top_order_priority : false, min_allocation : 0 };
+        live.register_security(Instrument { matching_algorithm, lot_size : 5, ..Instrument::new(1, "TEST") }).unwrap();
+        live.match_order(limit_order(1, false, 100, 15), &span).unwrap();
+        live.match_order(limit_order(2, false, 100, 15), &span).unwrap();
+        live.save_snapshot(&snapshot.0).unwrap();
+
+        let mut restored = MatchingEngine::restore_snapshot(&snapshot.0).unwrap();
+        // 7.5 each rounded down to lots of 5, the 5 left over to the head
+        let fills : Vec<(u64, u32)> = restored.match_order(limit_order(3, true, 100, 15), &span).unwrap()
+            .fills.iter().map(|fill| (fill.passive_order_id, fill.quantity)).collect();
+        assert_eq!(fills, [(1, 10), (2, 5)]);
+    }
+
+    #[test]
+    fn an_auction_in_progress_survives_a_snapshot(){
+        let (journal, snapshot) = (TempFile::new("auction-journal"), TempFile::new("auction-snapshot"));
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.start_auction(1, AuctionKind::Closing).unwrap();
+        live.match_order(EngineNewOrder { order_type : OrderType::Market(None), price : None, ..limit_order(1, false, 0, 5) }, &span).unwrap();
+        live.match_order(limit_order(2, true, 101, 10), &span).unwrap();
+        live.save_snapshot(&snapshot.0).unwrap();
+
+        let mut restored = MatchingEngine::restore_snapshot(&snapshot.0).unwrap();
+        assert_eq!(restored.indicative_auction_price(1).unwrap(), live.indicative_auction_price(1).unwrap());
+        let trades = |engine : &mut MatchingEngine| -> Vec<(u64, u64, u64, u32, u32)> {
+            engine.uncross(1, &span).unwrap().fills.iter().map(|fill| (fill.trade_id, fill.aggressor_order_id, fill.passive_order_id, fill.price, fill.quantity)).collect()
+        };
+        assert_eq!(trades(&mut restored), trades(&mut live));
+        assert_same_state(&restored, &live);
+    }
+
+    #[test]
+    fn the_trading_state_and_schedule_survive_a_snapshot(){
+        let (journal, snapshot) = (TempFile::new("schedule-journal"), TempFile::new("schedule-snapshot"));
+        let span = Span::none();
+        let mut live = journaled_engine(&journal.0);
+        live.set_trading_state(1, TradingState::Closed, &span).unwrap();
+        live.schedule_transition(1, 2_000, TradingState::PreOpen).unwrap();
+        live.save_snapshot(&snapshot.0).unwrap();
+
+        let mut restored = MatchingEngine::restore_snapshot(&snapshot.0).unwrap();
+        assert_eq!(restored.trading_state(1).unwrap(), TradingState::Closed);
+        assert_eq!(restored.scheduled_transitions(1).unwrap(), [(2_000, TradingState::PreOpen)]);
+        let clock = SimulatedClock::new(2_000);
+        restored.set_clock(Box::new(clock));
+        assert_eq!(restored.run_schedule().unwrap().len(), 1);
+        assert_eq!(restored.trading_state(1).unwrap(), TradingState::PreOpen);
+    }
+}
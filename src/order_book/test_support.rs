@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+use tracing::Span;
+use crate::order_book::matching_engine::MatchingEngine;
+use crate::order_book::types::{EngineNewOrder, Instrument, OrderState, OrderType, PriceLevelDepth, TimeInForce};
+
+// an engine with security 1 registered and nothing else configured
+pub fn engine() -> MatchingEngine{
+    let mut engine = MatchingEngine::new();
+    engine.register_security(Instrument::new(1, "TEST")).unwrap();
+    engine
+}
+
+// an engine journaling to `path` from the start, with security 1 registered
+pub fn journaled_engine(path : &Path) -> MatchingEngine{
+    let mut engine = MatchingEngine::new();
+    engine.attach_journal(path).unwrap();
+    engine.register_security(Instrument::new(1, "TEST")).unwrap();
+    engine
+}
+
+// good till cancel limit order on security 1
+pub fn limit_order(order_id : u64, is_buy_side : bool, price : u32, quantity : u32) -> EngineNewOrder{
+    EngineNewOrder {
+        engine_order_id : order_id,
+        price : Some(price),
+        initial_quantity : quantity,
+        current_quantity : quantity,
+        is_buy_side,
+        security_id : 1,
+        order_type : OrderType::Limit,
+        time_in_force : TimeInForce::GoodTillCancel,
+        post_only : None,
+        owner_id : 0,
+        client_order_id : None,
+        session_id : None
+    }
+}
+
+// (price, quantity) of every level on one side of security 1, in depth order
+pub fn levels(engine : &MatchingEngine, is_buy_side : bool) -> Vec<(u32, u32)>{
+    let depth = engine.depth(1, None, &Span::none()).unwrap();
+    let side : &[PriceLevelDepth] = if is_buy_side { &depth.bid_depth } else { &depth.ask_depth };
+    side.iter().map(|level| (level.price_level, level.quantity)).collect()
+}
+
+// (state, price, filled, still open, queue position)
+pub type Status = (OrderState, Option<u32>, u32, u32, Option<u32>);
+
+// status of an order on security 1
+pub fn status(engine : &MatchingEngine, order_id : u64) -> Option<Status>{
+    let status = engine.order_status(1, order_id).ok()?;
+    Some((status.state, status.price, status.filled_quantity, status.current_quantity, status.queue_position))
+}
+
+// everything a rebuilt engine has to agree on with the live one for security 1, tests keep
+// their order ids under 100
+pub fn assert_same_state(rebuilt : &MatchingEngine, live : &MatchingEngine){
+    for order_id in 1..100 {
+        assert_eq!(status(rebuilt, order_id), status(live, order_id), "status of order {}", order_id);
+    }
+    assert_eq!(rebuilt.last_sequence(), live.last_sequence(), "last sequence");
+    assert_eq!(rebuilt.market_data_sequence(), live.market_data_sequence(), "market data sequence");
+    assert_eq!(levels(rebuilt, true), levels(live, true), "bids");
+    assert_eq!(levels(rebuilt, false), levels(live, false), "asks");
+    for owner_id in 0..10 {
+        assert_eq!(rebuilt.positions(owner_id), live.positions(owner_id), "positions of owner {}", owner_id);
+    }
+}
+
+// a path in the temp dir no other test uses, the file is removed on drop
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(name : &str) -> Self{
+        Self(std::env::temp_dir().join(format!("clob-engine-{}-{}", name, uuid::Uuid::new_v4())))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self){
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -57,6 +57,24 @@ impl Tracing {
                     reason = %reason,
         )
     }
+    pub fn mass_cancel_span(
+        cancelled_count: Empty,
+        reason: Empty,
+    ) -> Span{
+        info_span!("mass_cancel", cancelled_count = cancelled_count,
+                    reason = reason,
+        )
+    }
+    pub fn uncross_span(
+        security_id: u32,
+        matched_quantity: Empty,
+        reason: Empty,
+    ) -> Span{
+        info_span!("uncross", security_id = %security_id,
+                    matched_quantity = matched_quantity,
+                    reason = reason,
+        )
+    }
     pub fn depth_span(
         security_id: Empty,
         status: Empty,
@@ -0,0 +1,120 @@
+use std::fmt;
+use crate::order_book::auction::{AuctionKind, UncrossOutcome};
+use crate::order_book::error::EngineError;
+use crate::order_book::types::EngineCancelOrder;
+
+// the trading phase of one security. cancels are accepted in every state, new orders and
+// modifies only while the security trades or collects orders for an auction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TradingState{
+    PreOpen, // before the open, the book carried over from the last session can only be cancelled from
+    OpeningAuction, // collecting orders for the opening (or a re-opening) uncross
+    #[default]
+    Continuous, // orders match as they arrive, a freshly registered security starts here
+    Halted, // keeps its book and any interrupted auction, only cancels are accepted
+    ClosingAuction, // collecting orders for the closing uncross
+    Closed // after the close, until the next pre-open
+}
+
+impl TradingState {
+    pub fn accepts_orders(&self) -> bool{
+        matches!(self, TradingState::OpeningAuction | TradingState::Continuous | TradingState::ClosingAuction)
+    }
+
+    pub fn auction_kind(&self) -> Option<AuctionKind>{
+        match self {
+            TradingState::OpeningAuction => Some(AuctionKind::Opening),
+            TradingState::ClosingAuction => Some(AuctionKind::Closing),
+            _ => None
+        }
+    }
+
+    // the session runs pre-open -> opening auction -> continuous -> closing auction -> closed.
+    // pre-open can open straight into continuous, continuous can close without an auction or
+    // call a re-opening auction, and every state but closed can be halted. a halt is lifted
+    // into any open state or the close.
+    pub fn can_transition_to(&self, to : TradingState) -> bool{
+        use TradingState::*;
+        match self {
+            PreOpen => matches!(to, OpeningAuction | Continuous | Halted | Closed),
+            OpeningAuction => matches!(to, Continuous | Halted),
+            Continuous => matches!(to, OpeningAuction | ClosingAuction | Halted | Closed),
+            Halted => matches!(to, OpeningAuction | Continuous | ClosingAuction | Closed),
+            ClosingAuction => matches!(to, Closed | Halted),
+            Closed => to == PreOpen
+        }
+    }
+
+    // new orders and modifies, a halt keeps reporting `SecurityHalted`
+    pub fn check_order_entry(&self, security_id : u32) -> Result<(), EngineError>{
+        match self {
+            TradingState::Halted => Err(EngineError::SecurityHalted(security_id)),
+            state if !state.accepts_orders() => Err(EngineError::NotAcceptingOrders { security_id, state : *state }),
+            _ => Ok(())
+        }
+    }
+}
+
+impl fmt::Display for TradingState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TradingState::PreOpen => "pre-open",
+            TradingState::OpeningAuction => "opening auction",
+            TradingState::Continuous => "continuous",
+            TradingState::Halted => "halted",
+            TradingState::ClosingAuction => "closing auction",
+            TradingState::Closed => "closed"
+        })
+    }
+}
+
+// one state change of a security. leaving an auction for anything but a halt uncrosses it.
+#[derive(Debug)]
+pub struct StateTransition{
+    pub security_id : u32,
+    pub from : TradingState,
+    pub to : TradingState,
+    pub uncross : Option<UncrossOutcome>,
+    pub expired_orders : Vec<EngineCancelOrder>, // day orders cancelled by the close, by order id
+    pub sequence : u64, // of the command that made (or, for a scheduled one, found) it due
+    pub timestamp : u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TradingState::{self, *};
+    use crate::order_book::auction::AuctionKind;
+    use crate::order_book::error::EngineError;
+
+    const STATES : [TradingState; 6] = [PreOpen, OpeningAuction, Continuous, Halted, ClosingAuction, Closed];
+
+    #[test]
+    fn the_session_only_moves_along_the_allowed_transitions(){
+        let allowed : [(TradingState, &[TradingState]); 6] = [
+            (PreOpen, &[OpeningAuction, Continuous, Halted, Closed]),
+            (OpeningAuction, &[Continuous, Halted]),
+            (Continuous, &[OpeningAuction, ClosingAuction, Halted, Closed]),
+            (Halted, &[OpeningAuction, Continuous, ClosingAuction, Closed]),
+            (ClosingAuction, &[Closed, Halted]),
+            (Closed, &[PreOpen])
+        ];
+        for (from, to_states) in allowed {
+            for to in STATES {
+                assert_eq!(from.can_transition_to(to), to_states.contains(&to), "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn orders_are_only_entered_while_trading_or_collecting_for_an_auction(){
+        for state in [OpeningAuction, Continuous, ClosingAuction] {
+            assert_eq!(state.check_order_entry(1), Ok(()));
+        }
+        assert_eq!(Halted.check_order_entry(1), Err(EngineError::SecurityHalted(1)));
+        for state in [PreOpen, Closed] {
+            assert_eq!(state.check_order_entry(1), Err(EngineError::NotAcceptingOrders { security_id : 1, state }));
+        }
+        let auction_kinds : Vec<_> = STATES.iter().map(|state| state.auction_kind()).collect();
+        assert_eq!(auction_kinds, [None, Some(AuctionKind::Opening), None, None, Some(AuctionKind::Closing), None]);
+    }
+}
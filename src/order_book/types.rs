@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use crate::order_book::error::EngineError;
+use crate::order_book::trading_state::TradingState;
 
 #[derive(Debug, Copy, Clone)]
 pub struct OrderNode{
@@ -6,12 +7,110 @@ pub struct OrderNode{
     pub initial_quantity : u32,
     pub current_quantity : u32,
     pub market_limit : u32, // essentially the limit or (market limit) price at which the order gets executed
+    pub post_only : Option<PostOnly>, // kept so a modified order re-enters with the same mode
+    pub peak_quantity : u32, // iceberg display size, 0 for regular orders
+    pub hidden_quantity : u32, // iceberg reserve not yet shown in the book
+    pub owner_id : u64, // account the order belongs to, used for self-trade prevention
+    pub entry_time : u64, // engine time the order took its place in the queue
     pub next : Option<usize>,
     pub prev : Option<usize>
 }
 
 
-#[derive(Debug)]
+// how an incoming order's quantity is shared among the resting orders of a price level
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum MatchingAlgorithm{
+    #[default]
+    Fifo, // strict price-time, the head of the level first
+    ProRata {
+        top_order_priority : bool, // the first order in the queue is filled in full before the split
+        min_allocation : u32 // shares below this go to the leftover, which is handed out first come first served
+    }
+}
+
+// static reference data of a tradable security, registered with the engine before any order
+#[derive(Debug, Clone)]
+pub struct Instrument{
+    pub security_id : u32,
+    pub symbol : String,
+    pub tick_size : u32, // every price has to be a multiple of this
+    pub lot_size : u32, // every quantity has to be a multiple of this
+    pub min_quantity : u32,
+    pub max_quantity : u32,
+    pub min_price : u32,
+    pub max_price : u32,
+    pub matching_algorithm : MatchingAlgorithm
+}
+
+impl Instrument {
+    // tick and lot of one with no price or quantity bounds beyond rejecting zero
+    pub fn new(security_id : u32, symbol : &str) -> Self{
+        Self {
+            security_id,
+            symbol : symbol.to_string(),
+            tick_size : 1,
+            lot_size : 1,
+            min_quantity : 1,
+            max_quantity : u32::MAX,
+            min_price : 1,
+            max_price : u32::MAX,
+            matching_algorithm : MatchingAlgorithm::Fifo
+        }
+    }
+
+    pub fn validate_reference_data(&self) -> Result<(), EngineError>{
+        if self.tick_size == 0 {
+            return Err(EngineError::InvalidReferenceData("tick size has to be greater than zero"));
+        }
+        if self.lot_size == 0 {
+            return Err(EngineError::InvalidReferenceData("lot size has to be greater than zero"));
+        }
+        if self.min_quantity == 0 || self.min_quantity > self.max_quantity {
+            return Err(EngineError::InvalidReferenceData("quantity bounds have to satisfy 0 < min <= max"));
+        }
+        if self.min_price > self.max_price {
+            return Err(EngineError::InvalidReferenceData("price bounds have to satisfy min <= max"));
+        }
+        Ok(())
+    }
+
+    pub fn validate_price(&self, price : u32) -> Result<(), EngineError>{
+        if !price.is_multiple_of(self.tick_size) {
+            return Err(EngineError::PriceNotOnTick { price, tick_size : self.tick_size });
+        }
+        if price < self.min_price || price > self.max_price {
+            return Err(EngineError::PriceOutOfRange { price, min_price : self.min_price, max_price : self.max_price });
+        }
+        Ok(())
+    }
+
+    pub fn validate_quantity(&self, quantity : u32) -> Result<(), EngineError>{
+        if !quantity.is_multiple_of(self.lot_size) {
+            return Err(EngineError::QuantityNotOnLot { quantity, lot_size : self.lot_size });
+        }
+        if quantity < self.min_quantity || quantity > self.max_quantity {
+            return Err(EngineError::QuantityOutOfRange { quantity, min_quantity : self.min_quantity, max_quantity : self.max_quantity });
+        }
+        Ok(())
+    }
+
+    // checks every price and quantity an incoming order carries
+    pub fn validate_order(&self, order : &EngineNewOrder) -> Result<(), EngineError>{
+        if let Some(price) = order.price {
+            self.validate_price(price)?;
+        }
+        match order.order_type {
+            OrderType::Market(Some(price)) | OrderType::Stop(price) | OrderType::StopLimit(price) => self.validate_price(price)?,
+            OrderType::Iceberg(peak_quantity) if !peak_quantity.is_multiple_of(self.lot_size) => {
+                return Err(EngineError::QuantityNotOnLot { quantity : peak_quantity, lot_size : self.lot_size });
+            }
+            _ => {}
+        }
+        self.validate_quantity(order.current_quantity)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct EngineNewOrder{
     pub engine_order_id : u64,
     pub price : Option<u32>, // price recieved over here are already in whole number
@@ -19,23 +118,73 @@ pub struct EngineNewOrder{
     pub current_quantity : u32,
     pub is_buy_side : bool,
     pub security_id : u32,
-    pub order_type : OrderType
+    pub order_type : OrderType,
+    pub time_in_force : TimeInForce,
+    pub post_only : Option<PostOnly>, // only valid for good till cancel limit orders
+    pub owner_id : u64,
+    pub client_order_id : Option<String>, // ClOrdID, unique among the owner's live orders
+    pub session_id : Option<u64> // orders entered through a session can be cancelled with it, see `end_session`
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OrderType{
     Market(Option<u32>), // No cieling/floor price. leftover quantity is canceled
-    Limit
+    Limit,
+    Stop(u32), // stop price. becomes Market(None) once the last trade reaches it
+    StopLimit(u32), // stop price. becomes Limit (at `price`) once the last trade reaches it
+    Iceberg(u32) // peak quantity. a limit order showing at most this much at a time
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimeInForce{
+    GoodTillCancel, // whatever doesn't match rests in the book (limit orders only)
+    ImmediateOrCancel, // match what crosses at the limit, cancel the rest
+    FillOrKill, // match the whole quantity right away or do nothing at all
+    Day // rests like good till cancel, but expires at the close of its security, with `expire_day_orders` or with the session it was entered through
+}
+
+impl TimeInForce {
+    // whether the leftover of a limit order stays in the book
+    pub fn rests(&self) -> bool{
+        matches!(self, TimeInForce::GoodTillCancel | TimeInForce::Day)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PostOnly{
+    Reject, // refuse the order if it would take liquidity
+    Slide // reprice one tick behind the opposite best so it rests as a maker
+}
+
+// what happens when an incoming order would trade against a resting order of the same owner
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SelfTradePrevention{
+    CancelNewest, // cancel the rest of the incoming order, resting order untouched
+    CancelOldest, // cancel the resting order and keep matching the incoming one
+    CancelBoth, // cancel the resting order and the rest of the incoming order
+    DecrementAndCancel // reduce both by the smaller quantity, the smaller one ends up cancelled
+}
+
+#[derive(Debug, Clone)]
 pub struct EngineCancelOrder{
     pub order_id : u64,
     pub security_id : u32,
     pub is_buy_side : bool
 }
 
-#[derive(Debug)]
+// selects the orders a mass cancel takes out, a field left as None matches everything.
+// the price band is inclusive on both ends and is checked against a stop's stop price,
+// market orders waiting for an auction only match a filter without one.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct MassCancelFilter{
+    pub security_id : Option<u32>,
+    pub is_buy_side : Option<bool>,
+    pub owner_id : Option<u64>,
+    pub min_price : Option<u32>,
+    pub max_price : Option<u32>
+}
+
+#[derive(Debug, Clone)]
 pub struct EngineModifyOrder{ //THINK ABOUT CANCEL AND NOT CANCEL SCENARIO
     pub order_id : u64,
     pub security_id : u32,
@@ -44,29 +193,6 @@ pub struct EngineModifyOrder{ //THINK ABOUT CANCEL AND NOT CANCEL SCENARIO
     pub new_quantity : Option<u32>,
 }
 
-#[derive(Debug)]
-pub struct OrderRegistry{
-    _asset_view : HashMap<u64, usize>
-}
-
-impl OrderRegistry {
-    pub fn new() -> Self{
-        Self { _asset_view: HashMap::new() }
-    }
-    pub fn insert(&mut self, order_id : u64, idx : usize) -> Option<usize>{
-        self._asset_view.insert(order_id, idx)
-    }
-    pub fn order_exist(&self, order_id : u64) -> bool{
-        self._asset_view.contains_key(&order_id)
-    }
-    pub fn get_idx(&self, order_id : u64) -> &usize{
-        self._asset_view.get(&order_id).unwrap()
-    }
-    pub fn delete_key(&mut self, order_id : u64) -> Option<usize>{
-        self._asset_view.remove(&order_id)
-    }
-}
-
 #[derive(Debug)]
 pub struct PriceLevel{
     pub head : Option<usize>,
@@ -75,11 +201,129 @@ pub struct PriceLevel{
     pub total_quantity : u32
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct MatchOutcome {
     pub order_index: Option<u32>,
     pub orders_touched: u32,
     pub levels_consumed: u32,
+    pub fills: Vec<Fill>, // one entry per resting order hit, in execution order
+    pub cancelled_quantity: u32, // quantity dropped instead of resting (market/IOC remainder, killed FOK)
+    pub released_stops: Vec<u64>, // stop orders triggered by this match, their fills are part of `fills`
+    pub cancelled_stops: Vec<u64>, // released stops that failed to execute and were dropped, also in `released_stops`
+    pub self_trade_cancels: Vec<u64>, // resting orders removed by self-trade prevention
+    pub sequence: u64, // engine sequence number of the command that produced this outcome
+    pub timestamp: u64, // engine time of that command, shared by all of its events
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Fill{
+    pub sequence : u64, // engine sequence number of this event
+    pub timestamp : u64,
+    pub trade_id : u64,
+    pub security_id : u32,
+    pub aggressor_order_id : u64,
+    pub passive_order_id : u64,
+    pub price : u32, // always the resting (passive) order's price
+    pub quantity : u32,
+    pub aggressor_is_buy_side : bool
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LevelAction{
+    New,
+    Change,
+    Delete // quantity and order count are 0
+}
+
+// absolute state of one price level after a change, not a delta
+#[derive(Debug, Copy, Clone)]
+pub struct LevelUpdate{
+    pub sequence : u64, // market data sequence, contiguous across the feed so a gap means a missed update
+    pub timestamp : u64,
+    pub security_id : u32,
+    pub is_buy_side : bool,
+    pub price : u32,
+    pub quantity : u32, // visible quantity, iceberg reserves are not shown
+    pub order_count : u32,
+    pub action : LevelAction
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OrderAction{
+    Add, // entered the book at the back of `price`, showing `quantity`
+    Executed { trade_id : u64 }, // `quantity` traded, the order stays until a Delete or Replace
+    Cancel, // `quantity` cancelled, the rest keeps its place
+    Delete, // left the book, `quantity` is what it was still showing
+    Replace // left its place and re-entered at the back of `price`, showing `quantity`
+}
+
+// one change to a resting order. the book fills in the order fields, sequence, timestamp and
+// security id are stamped by the engine when it publishes the update.
+#[derive(Debug, Copy, Clone)]
+pub struct OrderUpdate{
+    pub sequence : u64, // shares the market data sequence with `LevelUpdate`
+    pub timestamp : u64,
+    pub security_id : u32,
+    pub order_id : u64,
+    pub is_buy_side : bool,
+    pub price : u32,
+    pub quantity : u32,
+    pub action : OrderAction
+}
+
+impl OrderUpdate {
+    pub fn new(order_id : u64, is_buy_side : bool, price : u32, quantity : u32, action : OrderAction) -> Self{
+        Self { sequence : 0, timestamp : 0, security_id : 0, order_id, is_buy_side, price, quantity, action }
+    }
+}
+
+// best bid and offer after a command that changed either of them, price or quantity
+#[derive(Debug, Copy, Clone)]
+pub struct BboUpdate{
+    pub sequence : u64, // shares the market data sequence with the other updates
+    pub timestamp : u64,
+    pub security_id : u32,
+    pub best_bid : Option<PriceLevelDepth>, // None when that side of the book is empty
+    pub best_ask : Option<PriceLevelDepth>
+}
+
+// what stands between a resting order and the front of its price level
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct QueuePosition{
+    pub orders_ahead : u32,
+    pub quantity_ahead : u32 // visible quantity only, reserves ahead refill behind us
+}
+
+// new queue position of a watched order after a command moved it
+#[derive(Debug, Copy, Clone)]
+pub struct QueuePositionUpdate{
+    pub sequence : u64,
+    pub timestamp : u64,
+    pub security_id : u32,
+    pub order_id : u64,
+    pub is_buy_side : bool,
+    pub position : QueuePosition
+}
+
+// a security entered a new trading state, after the book updates of the uncross it caused
+#[derive(Debug, Copy, Clone)]
+pub struct TradingStateUpdate{
+    pub sequence : u64,
+    pub timestamp : u64,
+    pub security_id : u32,
+    pub state : TradingState
+}
+
+// everything the engine publishes for market data consumers, in publication order. the
+// order updates of a command come first, then the level updates summarising it, the bbo
+// and the queue positions of watched orders last.
+#[derive(Debug, Clone)]
+pub enum MarketDataEvent{
+    Level(LevelUpdate),
+    Order(OrderUpdate),
+    Bbo(BboUpdate),
+    QueuePosition(QueuePositionUpdate),
+    TradingState(TradingStateUpdate)
 }
 
 #[derive(Debug)]
@@ -102,19 +346,35 @@ pub enum ModifyOutcome{
     }
 }
 
-#[derive(Debug)]
-pub enum CancelOutcome {
-    Success,
-    Failed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OrderState{
+    Live, // resting in the book or waiting in the trigger book
+    Filled,
+    Cancelled // by the owner, self-trade prevention, delisting or an unfilled IOC/FOK/market remainder
 }
 
+#[derive(Debug, Clone)]
+pub struct OrderStatus{
+    pub security_id : u32,
+    pub order_id : u64,
+    pub client_order_id : Option<String>,
+    pub is_buy_side : bool,
+    pub price : Option<u32>, // None for market and untriggered stop orders
+    pub initial_quantity : u32,
+    pub current_quantity : u32, // still open, iceberg reserve included. 0 once the order is done
+    pub filled_quantity : u32,
+    pub queue_position : Option<u32>, // 1 at the head of its price level, None when not resting
+    pub state : OrderState
+}
+
+// both sides are listed from the best price outwards
 #[derive(Debug)]
 pub struct BookDepth{
     pub bid_depth : Vec<PriceLevelDepth>,
     pub ask_depth : Vec<PriceLevelDepth>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct PriceLevelDepth{
     pub price_level : u32,
     pub quantity : u32